@@ -88,6 +88,45 @@ def build_sphere(pos: Vec3, radius: float, ttype: str, albedo: float, color: Col
     )
 
 
+def build_plane(point: Vec3, normal: Vec3, ttype: str, albedo: float, color: Color):
+    return """
+            {{
+                "type": "plane",
+                "point": {{
+                    "x": {x},
+                    "y": {y},
+                    "z": {z}
+                }},
+                "normal": {{
+                    "x": {nx},
+                    "y": {ny},
+                    "z": {nz}
+                }},
+                "material": {{
+                    "type": "{ttype}",
+                    "albedo": {albedo},
+                    "color": {{
+                        "r": {color_r},
+                        "g": {color_g},
+                        "b": {color_b}
+                    }}
+                }}
+            }}
+            """.format(
+        x=point.x,
+        y=point.y,
+        z=point.z,
+        nx=normal.x,
+        ny=normal.y,
+        nz=normal.z,
+        ttype=ttype,
+        albedo=albedo,
+        color_r=color.r,
+        color_g=color.g,
+        color_b=color.b
+    )
+
+
 def build_light(pos: Vec3, radius: float):
     return """
             {{
@@ -137,8 +176,8 @@ for i in range(0, gridsize):
                                  0.2 + random.randint(1, 100) / 400.0, "metal", 0.8, col))
 
 builder.add(build_light(Vec3(100, 100, 10), 40.0))
-builder.add(build_sphere(Vec3(0.0, -99.0, 0.0),
-                         100.0, "lambertian", 0.6, Color(0.4, 0.8, 0.1)))
+builder.add(build_plane(Vec3(0.0, 1.0, 0.0), Vec3(0.0, 1.0, 0.0),
+                        "lambertian", 0.6, Color(0.4, 0.8, 0.1)))
 
 with open("scene.json", "w") as file:
     file.write("[" + builder.render() + "]")
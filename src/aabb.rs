@@ -0,0 +1,75 @@
+use crate::ray::Ray;
+
+use glam::Vec3;
+
+// axis aligned bounding box, the building block of the BVH
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    // inverted box which doesn't contain anything, growing it by anything gives that thing
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::splat(f32::INFINITY),
+            max: Vec3::splat(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn grow(&self, p: Vec3) -> Aabb {
+        Aabb {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let e = self.extent();
+        if e.x < 0.0 || e.y < 0.0 || e.z < 0.0 {
+            return 0.0;
+        }
+
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    /**
+     * Slab test: the ray is inside the box where it's inside all three pairs of planes at once,
+     * so we intersect the [near, far] intervals of each axis. Returns the distance at which the
+     * ray enters the box (0 if it starts inside) provided that happens before `t_max`.
+     * `inv_dir` is 1/dir precomputed by the caller as the same ray is tested against many boxes
+     */
+    pub fn hit(&self, r: &Ray, inv_dir: Vec3, t_max: f32) -> Option<f32> {
+        let t0 = (self.min - r.origin) * inv_dir;
+        let t1 = (self.max - r.origin) * inv_dir;
+
+        let t_near = t0.min(t1).max_element().max(0.0);
+        let t_far = t0.max(t1).min_element().min(t_max);
+
+        if t_near <= t_far {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+}
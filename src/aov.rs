@@ -0,0 +1,403 @@
+use crate::material::Color;
+use crate::scene::Radiance;
+use crate::tonemap;
+use crate::tonemap::ToneMapping;
+
+use glam::Vec3;
+use std::path::Path;
+
+/*
+    Arbitrary output variables, extra images rendered next to the beauty one for compositing and
+    denoising. The geometric ones describe the first surface the camera rays hit and are averaged
+    over the samples that hit something, ids can't be averaged so they're taken from the first
+    sample that hit. Direct light is the light that bounced off a single surface on its way to the
+    camera (or came straight from a light), indirect is the rest, together they add up to beauty.
+    EXR output keeps them all in one file as layers, the other formats get a file per AOV.
+*/
+
+#[derive(clap::ArgEnum, PartialEq, Eq, Debug, Copy, Clone)]
+#[clap(rename_all = "snake_case")]
+pub enum Aov {
+    // distance from the camera along the ray, 0 where nothing was hit
+    Depth,
+    // world space, unit length
+    Normal,
+    // the surface color the light gets multiplied with
+    Albedo,
+    // counted from 1 in the order of the scene file, 0 where nothing was hit
+    ObjectId,
+    // objects with identical materials share the id
+    MaterialId,
+    // world space
+    Position,
+    Direct,
+    Indirect,
+    // how many samples the pixel got, only interesting with adaptive sampling
+    Samples,
+}
+
+impl Aov {
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Position => "position",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Samples => "samples",
+        }
+    }
+
+    // channel names within the EXR layer
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::Samples => &["count"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Albedo | Aov::Direct | Aov::Indirect => &["R", "G", "B"],
+        }
+    }
+
+    // values of the channels, one channel AOVs only use the first
+    pub fn values(self, p: &AovPixel) -> [f32; 3] {
+        let color = |c: Color| [c.r, c.g, c.b];
+
+        match self {
+            Aov::Depth => [p.depth, 0.0, 0.0],
+            Aov::ObjectId => [p.object_id as f32, 0.0, 0.0],
+            Aov::MaterialId => [p.material_id as f32, 0.0, 0.0],
+            Aov::Samples => [p.samples as f32, 0.0, 0.0],
+            Aov::Normal => p.normal.to_array(),
+            Aov::Position => p.position.to_array(),
+            Aov::Albedo => color(p.albedo),
+            Aov::Direct => color(p.direct),
+            Aov::Indirect => color(p.indirect),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AovPixel {
+    pub depth: f32,
+    pub normal: Vec3,
+    pub albedo: Color,
+    pub object_id: u32,
+    pub material_id: u32,
+    pub position: Vec3,
+    pub direct: Color,
+    pub indirect: Color,
+    pub samples: u32,
+}
+
+// what the camera ray of a sample hit first
+pub struct FirstHit {
+    pub depth: f32,
+    pub normal: Vec3,
+    pub position: Vec3,
+    pub albedo: Color,
+    pub object_id: u32,
+    pub material_id: u32,
+}
+
+// sums up the samples of a pixel, the film keeps one per pixel so passes can add to it
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AovAccumulator {
+    pub samples: u32,
+    // samples that hit something, geometry is averaged over these
+    pub hits: u32,
+    pub sum: AovPixel,
+}
+
+impl AovAccumulator {
+    pub fn add(&mut self, hit: Option<FirstHit>, radiance: Radiance) {
+        self.samples += 1;
+        self.sum.direct = self.sum.direct + radiance.direct;
+        self.sum.indirect = self.sum.indirect + radiance.indirect;
+
+        if let Some(hit) = hit {
+            if self.hits == 0 {
+                self.sum.object_id = hit.object_id;
+                self.sum.material_id = hit.material_id;
+            }
+
+            self.hits += 1;
+            self.sum.depth += hit.depth;
+            self.sum.normal += hit.normal;
+            self.sum.position += hit.position;
+            self.sum.albedo = self.sum.albedo + hit.albedo;
+        }
+    }
+
+    // as if all of the samples of other had been added to this one
+    pub fn merge(&mut self, other: &AovAccumulator) {
+        if self.hits == 0 {
+            self.sum.object_id = other.sum.object_id;
+            self.sum.material_id = other.sum.material_id;
+        }
+
+        self.samples += other.samples;
+        self.hits += other.hits;
+        self.sum.depth += other.sum.depth;
+        self.sum.normal += other.sum.normal;
+        self.sum.position += other.sum.position;
+        self.sum.albedo = self.sum.albedo + other.sum.albedo;
+        self.sum.direct = self.sum.direct + other.sum.direct;
+        self.sum.indirect = self.sum.indirect + other.sum.indirect;
+    }
+
+    pub fn finish(&self) -> AovPixel {
+        let samples = self.samples.max(1) as f32;
+        let hits = self.hits.max(1) as f32;
+
+        AovPixel {
+            depth: self.sum.depth / hits,
+            normal: self.sum.normal.normalize_or_zero(),
+            // averaged over all the samples so that edges blend with the background like beauty
+            albedo: self.sum.albedo / samples,
+            object_id: self.sum.object_id,
+            material_id: self.sum.material_id,
+            position: self.sum.position / hits,
+            direct: self.sum.direct / samples,
+            indirect: self.sum.indirect / samples,
+            // the renderer knows better, it counts the samples even without AOVs
+            samples: 0,
+        }
+    }
+}
+
+// formats holding a single image get a file per AOV next to the output, a.png -> a.depth.png
+pub fn sidecar_path(path: &str, aov: Aov) -> String {
+    let p = Path::new(path);
+    let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match p.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}.{}.{}", stem, aov.name(), extension),
+        None => format!("{}.{}", stem, aov.name()),
+    };
+
+    p.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// raw values three per pixel, one channel AOVs are repeated over all three
+pub fn rgb(aov: Aov, pixels: &[AovPixel]) -> Vec<f32> {
+    let single = aov.channels().len() == 1;
+
+    pixels
+        .iter()
+        .flat_map(|p| {
+            let v = aov.values(p);
+            if single {
+                [v[0]; 3]
+            } else {
+                v
+            }
+        })
+        .collect()
+}
+
+/**
+ * 8 bit RGBA for looking at, not for further processing:
+ * - depth and position are scaled by the range found in the image, near is dark
+ * - normals are mapped from [-1;1] to [0;1] the way normal maps are
+ * - ids get a color each so that neighbouring ids are easy to tell apart
+ * - sample counts are a heatmap from blue for the fewest to red for the most
+ * - lighting is tone mapped the same as the beauty image
+ */
+pub fn rgba8(aov: Aov, pixels: &[AovPixel], tone_mapping: &ToneMapping) -> Vec<u8> {
+    let quantize = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let encode = |c: Color| {
+        [
+            quantize(tonemap::srgb_oetf(c.r.clamp(0.0, 1.0))),
+            quantize(tonemap::srgb_oetf(c.g.clamp(0.0, 1.0))),
+            quantize(tonemap::srgb_oetf(c.b.clamp(0.0, 1.0))),
+        ]
+    };
+
+    let depth_max = pixels.iter().map(|p| p.depth).fold(0.0, f32::max);
+    let position_min = pixels
+        .iter()
+        .map(|p| p.position)
+        .fold(Vec3::splat(f32::MAX), Vec3::min);
+    let samples_min = pixels.iter().map(|p| p.samples).min().unwrap_or(0);
+    let samples_max = pixels.iter().map(|p| p.samples).max().unwrap_or(0);
+    let position_max = pixels
+        .iter()
+        .map(|p| p.position)
+        .fold(Vec3::splat(f32::MIN), Vec3::max);
+
+    pixels
+        .iter()
+        .flat_map(|p| {
+            let [r, g, b] = match aov {
+                Aov::Depth => [quantize(p.depth / depth_max.max(f32::EPSILON)); 3],
+                Aov::Normal => {
+                    let n = p.normal * 0.5 + Vec3::splat(0.5);
+                    [quantize(n.x), quantize(n.y), quantize(n.z)]
+                }
+                Aov::Position => {
+                    let range = (position_max - position_min).max(Vec3::splat(f32::EPSILON));
+                    let n = (p.position - position_min) / range;
+                    [quantize(n.x), quantize(n.y), quantize(n.z)]
+                }
+                Aov::ObjectId => id_color(p.object_id),
+                Aov::MaterialId => id_color(p.material_id),
+                Aov::Samples => {
+                    let range = (samples_max - samples_min).max(1) as f32;
+                    let t = (p.samples - samples_min) as f32 / range;
+                    [
+                        quantize(2.0 * t - 1.0),
+                        quantize(1.0 - (2.0 * t - 1.0).abs()),
+                        quantize(1.0 - 2.0 * t),
+                    ]
+                }
+                Aov::Albedo => encode(p.albedo),
+                Aov::Direct => encode(tone_mapping.apply(p.direct)),
+                Aov::Indirect => encode(tone_mapping.apply(p.indirect)),
+            };
+
+            [r, g, b, 255]
+        })
+        .collect()
+}
+
+// 0 is nothing and stays black, the rest are scattered over the colors by a hash
+fn id_color(id: u32) -> [u8; 3] {
+    if id == 0 {
+        return [0, 0, 0];
+    }
+
+    let h = id
+        .wrapping_mul(0x9E3779B1)
+        .rotate_left(13)
+        .wrapping_mul(0x85EBCA77);
+    // keep them away from black so they don't look like the background
+    let channel = |shift: u32| 64 + ((h >> shift) & 0xFF) as u8 / 4 * 3;
+
+    [channel(0), channel(8), channel(16)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(depth: f32, normal: Vec3, object_id: u32) -> FirstHit {
+        FirstHit {
+            depth,
+            normal,
+            position: Vec3::new(0.0, 0.0, -depth),
+            albedo: Color::new(0.5, 0.5, 0.5),
+            object_id,
+            material_id: 1,
+        }
+    }
+
+    fn radiance(direct: f32, indirect: f32) -> Radiance {
+        Radiance {
+            direct: Color::new(direct, direct, direct),
+            indirect: Color::new(indirect, indirect, indirect),
+        }
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let mut acc = AovAccumulator::default();
+        acc.add(Some(hit(2.0, Vec3::Z, 3)), radiance(1.0, 0.5));
+        acc.add(Some(hit(4.0, Vec3::X, 7)), radiance(1.0, 0.5));
+        acc.add(None, radiance(0.0, 0.0));
+        acc.add(None, radiance(0.0, 0.0));
+
+        let p = acc.finish();
+
+        // geometry is averaged over the hits only
+        assert_eq!(p.depth, 3.0);
+        assert_eq!(p.position, Vec3::new(0.0, 0.0, -3.0));
+        assert!((p.normal - Vec3::new(1.0, 0.0, 1.0).normalize()).length() < 1e-6);
+        assert_eq!(p.object_id, 3);
+
+        // while colors over every sample
+        assert_eq!(p.albedo.r, 0.25);
+        assert_eq!(p.direct.r, 0.5);
+        assert_eq!(p.indirect.r, 0.25);
+
+        // no hits at all is the background
+        let mut acc = AovAccumulator::default();
+        acc.add(None, radiance(0.0, 0.0));
+        let p = acc.finish();
+        assert_eq!(p.depth, 0.0);
+        assert_eq!(p.normal, Vec3::ZERO);
+        assert_eq!(p.object_id, 0);
+    }
+
+    #[test]
+    fn test_merge() {
+        // a pass that missed everything followed by one that didn't
+        let mut first = AovAccumulator::default();
+        first.add(None, radiance(0.0, 0.0));
+        let mut second = AovAccumulator::default();
+        second.add(Some(hit(2.0, Vec3::Z, 3)), radiance(1.0, 0.5));
+        second.add(Some(hit(4.0, Vec3::X, 7)), radiance(1.0, 0.5));
+
+        let mut all = AovAccumulator::default();
+        all.add(None, radiance(0.0, 0.0));
+        all.add(Some(hit(2.0, Vec3::Z, 3)), radiance(1.0, 0.5));
+        all.add(Some(hit(4.0, Vec3::X, 7)), radiance(1.0, 0.5));
+
+        first.merge(&second);
+        let (merged, all) = (first.finish(), all.finish());
+
+        assert_eq!(merged.depth, all.depth);
+        assert_eq!(merged.normal, all.normal);
+        assert_eq!(merged.albedo, all.albedo);
+        assert_eq!(merged.direct, all.direct);
+        assert_eq!(merged.object_id, 3);
+    }
+
+    #[test]
+    fn test_sidecar_path() {
+        assert_eq!(sidecar_path("out.png", Aov::Depth), "out.depth.png");
+        assert_eq!(
+            sidecar_path("renders/a.b.pfm", Aov::ObjectId),
+            "renders/a.b.object_id.pfm"
+        );
+    }
+
+    #[test]
+    fn test_channels() {
+        let pixel = AovPixel {
+            depth: 5.0,
+            normal: Vec3::new(0.0, -1.0, 0.0),
+            ..AovPixel::default()
+        };
+
+        assert_eq!(rgb(Aov::Depth, &[pixel]), vec![5.0, 5.0, 5.0]);
+        assert_eq!(rgb(Aov::Normal, &[pixel]), vec![0.0, -1.0, 0.0]);
+
+        let preview = rgba8(Aov::Normal, &[pixel], &ToneMapping::default());
+        assert_eq!(preview, vec![128, 0, 128, 255]);
+
+        // the farthest point is white
+        let far = AovPixel {
+            depth: 10.0,
+            ..pixel
+        };
+        let preview = rgba8(Aov::Depth, &[pixel, far], &ToneMapping::default());
+        assert_eq!(preview[4..8], [255, 255, 255, 255]);
+
+        let few = AovPixel {
+            samples: 4,
+            ..pixel
+        };
+        let many = AovPixel {
+            samples: 64,
+            ..pixel
+        };
+        let preview = rgba8(Aov::Samples, &[few, many], &ToneMapping::default());
+        assert_eq!(preview, vec![0, 0, 255, 255, 255, 0, 0, 255]);
+
+        assert_eq!(id_color(0), [0, 0, 0]);
+        assert_ne!(id_color(1), id_color(2));
+    }
+}
@@ -1,14 +1,21 @@
 use crate::scene::Scene;
 
 use crate::camera;
+use crate::filter::Filter;
+use crate::filter::Tile;
 use crate::renderer;
+use crate::sampler::SamplerKind;
 use crate::scene;
+use serde::Deserialize;
+use serde::Serialize;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Mutex;
 
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub struct BBox {
@@ -20,14 +27,45 @@ pub struct BBox {
 
 pub enum PartialRenderMessage {
     PixelData(PartialRenderMessagePixels),
-    Progress(f64),
 }
 pub struct PartialRenderMessagePixels {
-    pub pixel_data: Arc<Vec<scene::Pixel>>,
+    // the block that was rendered
     pub bbox: BBox,
+    pub pixel_data: Arc<Vec<scene::Pixel>>,
+    pub splats: Tile,
+}
+
+// what a pass of render_block builds on
+#[derive(Clone, Default)]
+pub struct Pass {
+    // samples every pixel should have once the pass is done, adaptive sampling may stop earlier
+    pub goal: u32,
+    // the film before the pass, row by row. Empty before the first pass
+    pub film: Arc<Vec<scene::Totals>>,
+}
+
+#[cfg(test)]
+impl Pass {
+    // everything in one go, how a render without progressive passes goes
+    pub fn single(settings: &Settings) -> Pass {
+        Pass {
+            goal: settings.max_samples,
+            film: Arc::default(),
+        }
+    }
+}
+
+// how the color of a camera ray is computed
+#[derive(clap::ArgEnum, Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    // direct light with shadow rays plus mirror and glass bounces, fast but unlit areas are black
+    Whitted,
+    // unidirectional path tracing with global illumination, see pathtracer.rs
+    Path,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub struct Settings {
     // samples per pixel
     pub samples: u32,
@@ -41,6 +79,35 @@ pub struct Settings {
     // soft shadows are produced by throwing rays into the light source and averaging how many hit it
     // the more rays - the better quality of a shadow
     pub shadow_samples: u32,
+
+    pub integrator: Integrator,
+
+    // adaptive sampling: after every `samples` samples a pixel is checked and sampling stops once
+    // its relative error is below the threshold, or max_samples is reached. Without a threshold
+    // every pixel gets `samples`
+    pub adaptive_threshold: Option<f32>,
+    pub max_samples: u32,
+
+    // fill in the AOVs of every pixel, costs an extra ray per sample. The denoiser needs them too
+    pub aovs: bool,
+
+    // progressive rendering: the whole frame is rendered over and over, every pass adding this
+    // many samples to each pixel until there are max_samples. Without it there's a single pass
+    pub pass_samples: Option<u32>,
+
+    // keep adding passes until the time is up, max_samples is only a cap then. A pass isn't
+    // started when the last one says it wouldn't finish in time
+    pub time_limit: Option<Duration>,
+
+    // the random numbers of every sample follow from it, see rng.rs. The same seed gives the
+    // same image
+    pub seed: u64,
+
+    // where the numbers the samples are made of come from, see sampler.rs
+    pub sampler: SamplerKind,
+
+    // how the samples are weighted into the pixels around them, see filter.rs
+    pub filter: Filter,
 }
 
 impl Settings {
@@ -50,6 +117,29 @@ impl Settings {
             threads,
             bboxes,
             shadow_samples,
+            integrator: Integrator::Whitted,
+            adaptive_threshold: None,
+            max_samples: samples,
+            aovs: false,
+            pass_samples: None,
+            time_limit: None,
+            seed: 0,
+            sampler: SamplerKind::Random,
+            filter: Filter::default(),
+        }
+    }
+
+    // how many passes it takes to get every pixel to max_samples
+    pub fn passes(&self) -> u32 {
+        let step = self.pass_samples.unwrap_or(self.max_samples).max(1);
+        self.max_samples.div_ceil(step).max(1)
+    }
+
+    // samples a pixel should have after the pass, counted from 1
+    pub fn pass_goal(&self, pass: u32) -> u32 {
+        match self.pass_samples {
+            Some(step) => step.max(1).saturating_mul(pass).min(self.max_samples),
+            None => self.max_samples,
         }
     }
 }
@@ -60,43 +150,273 @@ impl Default for Settings {
             threads: 1,
             bboxes: 1,
             shadow_samples: 1,
+            integrator: Integrator::Whitted,
+            adaptive_threshold: None,
+            max_samples: 1,
+            aovs: false,
+            pass_samples: None,
+            time_limit: None,
+            seed: 0,
+            sampler: SamplerKind::Random,
+            filter: Filter::default(),
         }
     }
 }
 
+/**
+ * Renders the scene into the renderer pass by pass, see Settings::pass_samples. After every pass
+ * after_pass gets the renderer along with the number of the pass, counted from 1, and whether it's
+ * the last one. Passes the film already got to, when resuming from a checkpoint, are skipped
+ */
 pub fn render(
     renderer: Arc<Mutex<renderer::Renderer>>,
     camera: camera::Camera,
     scene: Arc<Scene>,
-    _width: u32,
-    _height: u32,
     settings: Settings,
+    mut after_pass: impl FnMut(&mut renderer::Renderer, u32, bool),
 ) {
-    let (tx, rx): (Sender<PartialRenderMessage>, Receiver<PartialRenderMessage>) = mpsc::channel();
-
-    let h = thread::spawn(move || loop {
-        match rx.try_recv() {
-            Ok(data) => match data {
-                PartialRenderMessage::Progress(progress) => {
-                    if progress >= 1.0 {
-                        break;
-                    }
-                }
-                PartialRenderMessage::PixelData(data) => {
-                    let mut locked_renderer = renderer.lock().unwrap();
+    let passes = settings.passes();
+    let start = Instant::now();
+
+    for pass in 1..=passes {
+        let goal = settings.pass_goal(pass);
+        if goal <= renderer.lock().unwrap().goal() {
+            continue;
+        }
+
+        if settings.time_limit.is_some() {
+            println!("Pass {}", pass);
+        } else if passes > 1 {
+            println!("Pass {}/{}", pass, passes);
+        }
+        let pass_start = Instant::now();
+
+        let film = Arc::new(renderer.lock().unwrap().totals());
+        let pass_data = Pass { goal, film };
+
+        let (tx, rx): (Sender<PartialRenderMessage>, Receiver<PartialRenderMessage>) =
+            mpsc::channel();
+
+        // runs until draw and everything it started have dropped their senders, so no block
+        // can get lost
+        let h = thread::spawn(move || {
+            rx.iter()
+                .map(|data| {
+                    let PartialRenderMessage::PixelData(data) = data;
+                    data
+                })
+                .collect::<Vec<_>>()
+        });
+
+        scene::draw(scene.clone(), camera, settings, pass_data, tx);
 
-                    for pixel in &*data.pixel_data {
-                        locked_renderer.putpixel(pixel.x as u32, pixel.y as u32, pixel.color);
-                    }
+        // added in the same order whichever thread finished first, the splats of neighbouring
+        // blocks overlap and floats don't add up the same in a different order
+        let mut blocks = h.join().unwrap();
+        blocks.sort_by_key(|data| (data.bbox.y, data.bbox.x));
+        {
+            let mut locked_renderer = renderer.lock().unwrap();
+            for data in &blocks {
+                for pixel in &*data.pixel_data {
+                    locked_renderer.add(pixel);
                 }
-            },
-            Err(_e) => {
-                thread::yield_now();
+                locked_renderer.add_splats(&data.splats);
+            }
+        }
+
+        // the next pass is expected to take as long as this one
+        let out_of_time = match settings.time_limit {
+            Some(limit) => start.elapsed() + pass_start.elapsed() > limit,
+            None => false,
+        };
+        let last = pass == passes || out_of_time;
+
+        let mut locked_renderer = renderer.lock().unwrap();
+        locked_renderer.set_goal(goal);
+        after_pass(&mut locked_renderer, pass, last);
+
+        if last {
+            break;
+        }
+    }
+}
+
+/**
+ * A duration like 90s, 1.5m, 2h or 500ms, a plain number is in seconds. For --time-limit
+ */
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let seconds = match unit.trim() {
+        "" | "s" => 1.0,
+        "ms" => 0.001,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        unit => return Err(format!("unknown unit {}, use ms, s, m or h", unit)),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{} is not a duration, try 90s", s))?;
+
+    Ok(Duration::from_secs_f64(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterKind;
+    use crate::loader;
+
+    #[test]
+    fn test_passes() {
+        let mut settings = Settings::new(4, 1, 1, 1);
+        assert_eq!(settings.passes(), 1);
+        assert_eq!(settings.pass_goal(1), 4);
+
+        // the last pass only tops up to max_samples
+        settings.max_samples = 10;
+        settings.pass_samples = Some(4);
+        assert_eq!(settings.passes(), 3);
+        let goals: Vec<u32> = (1..=3).map(|pass| settings.pass_goal(pass)).collect();
+        assert_eq!(goals, [4, 8, 10]);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("90 parsecs").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn test_time_limit_makes_passes_open_ended() {
+        let mut settings = Settings::new(4, 1, 1, 1);
+        settings.pass_samples = Some(4);
+        settings.max_samples = u32::MAX;
+        settings.time_limit = Some(Duration::from_secs(1));
+
+        assert!(settings.passes() > 1_000_000);
+        assert_eq!(settings.pass_goal(3), 12);
+        assert_eq!(settings.pass_goal(settings.passes()), u32::MAX);
+    }
+
+    #[test]
+    fn test_time_limit_stops_after_the_pass_that_used_it_up() {
+        let size = 8;
+        let (scene, camera) = scene_1(size);
+
+        let mut settings = Settings::new(2, 2, 2, 1);
+        settings.pass_samples = Some(2);
+        settings.max_samples = u32::MAX;
+        settings.time_limit = Some(Duration::ZERO);
+
+        let renderer = Arc::new(Mutex::new(renderer::Renderer::new(size, size)));
+        let mut passes = Vec::new();
+        render(
+            renderer.clone(),
+            camera,
+            scene,
+            settings,
+            |r: &mut renderer::Renderer, pass, last| passes.push((pass, last, r.goal())),
+        );
+
+        assert_eq!(passes, [(1, true, 2)]);
+        assert!(renderer
+            .lock()
+            .unwrap()
+            .totals()
+            .iter()
+            .all(|t| t.samples == 2));
+    }
+
+    fn scene_1(size: u32) -> (Arc<Scene>, camera::Camera) {
+        let loaded = loader::load("scenes/scene_1.json").unwrap();
+        let mut scene = Scene::new(size as u64, size as u64);
+        for (geometry, mat, kind) in loaded.objects {
+            match kind {
+                loader::Kind::Object => scene.add_object(geometry, mat),
+                loader::Kind::Light { intensity } => scene.add_light(geometry, mat, intensity),
             }
         }
-    });
+        scene.finalize();
+
+        (Arc::new(scene), loaded.camera)
+    }
+
+    #[test]
+    fn test_seed_decides_the_image() {
+        let size = 12;
+        let (scene, camera) = scene_1(size);
 
-    scene::draw(scene, camera, settings, tx);
+        let film = |threads: usize, bboxes: usize, seed: u64| {
+            let mut settings = Settings::new(3, threads, bboxes, 2);
+            settings.integrator = Integrator::Path;
+            settings.seed = seed;
 
-    h.join().unwrap();
+            let renderer = Arc::new(Mutex::new(renderer::Renderer::new(size, size)));
+            render(
+                renderer.clone(),
+                camera,
+                scene.clone(),
+                settings,
+                |_, _, _| {},
+            );
+            let totals = renderer.lock().unwrap().totals();
+            totals
+        };
+
+        // to the bit, however the tiles are split and whichever thread gets them
+        let reference = film(1, 1, 7);
+        assert_eq!(film(3, 4, 7), reference);
+        assert_eq!(film(2, 3, 7), reference);
+        assert_ne!(film(1, 1, 8), reference);
+    }
+
+    #[test]
+    fn test_filter_reaches_across_blocks() {
+        let size = 12;
+        let (scene, camera) = scene_1(size);
+
+        let film = |threads: usize, bboxes: usize, filter: Filter| {
+            let mut settings = Settings::new(2, threads, bboxes, 2);
+            settings.seed = 3;
+            settings.filter = filter;
+
+            let renderer = Arc::new(Mutex::new(renderer::Renderer::new(size, size)));
+            render(
+                renderer.clone(),
+                camera,
+                scene.clone(),
+                settings,
+                |_, _, _| {},
+            );
+            let r = renderer.lock().unwrap();
+            (r.colors(), r.totals())
+        };
+
+        let mitchell = Filter::new(FilterKind::Mitchell, None);
+        let (whole, _) = film(1, 1, mitchell);
+        let (blocks, _) = film(4, 9, mitchell);
+        // the same to the bit whichever thread finishes first
+        assert_eq!(film(1, 9, mitchell).0, blocks);
+        // and as if there were no blocks, up to the order the floats were added in
+        for (a, b) in whole.iter().zip(&blocks) {
+            assert!((a.r - b.r).abs() < 1.0e-5 && (a.b - b.b).abs() < 1.0e-5);
+        }
+
+        // a box of half a pixel leaves the samples in the pixels they were taken in
+        let (boxed, totals) = film(4, 9, Filter::default());
+        assert!(boxed.iter().zip(&totals).all(|(c, t)| *c == t.mean()));
+        assert_ne!(boxed, whole);
+    }
 }
@@ -0,0 +1,276 @@
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+
+use glam::Vec3;
+
+// how many buckets the centroids are sorted into when looking for the best split
+const BINS: usize = 12;
+
+// nodes with this many primitives or less are never split further
+const MIN_LEAF_SIZE: usize = 2;
+
+// leaves this big are split even when SAH says it's not worth it, keeps the worst case in check
+const MAX_LEAF_SIZE: usize = 8;
+
+// cost of stepping into a node relative to the cost of testing a single primitive
+const TRAVERSAL_COST: f32 = 0.5;
+
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    bbox: Aabb,
+    // for leaves it's the first primitive in `indices`, for inner nodes it's the index of the
+    // second child. The first child is always stored right after its parent
+    offset: usize,
+    // number of primitives in a leaf, 0 for inner nodes
+    count: usize,
+}
+
+/**
+ * Bounding volume hierarchy over anything that has a bounding box. The tree is built once using
+ * the surface area heuristic and flattened into an array in depth first order, the primitives
+ * themselves are not stored here, only their indices, so it can be used for scene objects as
+ * well as for triangles of a single mesh
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn build(boxes: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(boxes.len() * 2),
+            indices: (0..boxes.len()).collect(),
+        };
+
+        if !boxes.is_empty() {
+            let centroids: Vec<Vec3> = boxes.iter().map(|b| b.centroid()).collect();
+            bvh.build_node(boxes, &centroids, 0, boxes.len());
+        }
+
+        bvh
+    }
+
+    fn build_node(
+        &mut self,
+        boxes: &[Aabb],
+        centroids: &[Vec3],
+        start: usize,
+        end: usize,
+    ) -> usize {
+        let bbox = self.indices[start..end]
+            .iter()
+            .fold(Aabb::empty(), |acc, &i| acc.union(&boxes[i]));
+
+        let node_idx = self.nodes.len();
+        self.nodes.push(Node {
+            bbox,
+            offset: start,
+            count: end - start,
+        });
+
+        let count = end - start;
+        if count <= MIN_LEAF_SIZE {
+            return node_idx;
+        }
+
+        let centroid_bounds = self.indices[start..end]
+            .iter()
+            .fold(Aabb::empty(), |acc, &i| acc.grow(centroids[i]));
+
+        let mid = match self.find_split(boxes, centroids, &bbox, &centroid_bounds, start, end) {
+            Some(mid) => mid,
+            None => return node_idx,
+        };
+
+        self.build_node(boxes, centroids, start, mid);
+        let second = self.build_node(boxes, centroids, mid, end);
+
+        self.nodes[node_idx].offset = second;
+        self.nodes[node_idx].count = 0;
+
+        node_idx
+    }
+
+    /**
+     * Sorts the centroids of each axis into buckets and evaluates splitting between every pair
+     * of them, the cost of a split is how likely a ray is to hit each half (surface area relative
+     * to the parent) times how many primitives it would have to test there.
+     * Partitions the primitives and returns where the second half starts, or None if the node is
+     * better off as a leaf
+     */
+    fn find_split(
+        &mut self,
+        boxes: &[Aabb],
+        centroids: &[Vec3],
+        bbox: &Aabb,
+        centroid_bounds: &Aabb,
+        start: usize,
+        end: usize,
+    ) -> Option<usize> {
+        let count = end - start;
+        let extent = centroid_bounds.extent();
+
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        for axis in 0..3 {
+            if extent[axis] <= 0.0 {
+                continue;
+            }
+
+            let bin_of = |c: Vec3| {
+                let b =
+                    ((c[axis] - centroid_bounds.min[axis]) / extent[axis] * BINS as f32) as usize;
+                b.min(BINS - 1)
+            };
+
+            let mut bin_boxes = [Aabb::empty(); BINS];
+            let mut bin_counts = [0usize; BINS];
+
+            for &i in &self.indices[start..end] {
+                let b = bin_of(centroids[i]);
+                bin_boxes[b] = bin_boxes[b].union(&boxes[i]);
+                bin_counts[b] += 1;
+            }
+
+            // sweep from the right so that we know the right half for every split position
+            let mut right_area = [0.0; BINS];
+            let mut right_count = [0usize; BINS];
+            let mut acc_box = Aabb::empty();
+            let mut acc_count = 0;
+            for b in (1..BINS).rev() {
+                acc_box = acc_box.union(&bin_boxes[b]);
+                acc_count += bin_counts[b];
+                right_area[b] = acc_box.surface_area();
+                right_count[b] = acc_count;
+            }
+
+            let mut acc_box = Aabb::empty();
+            let mut acc_count = 0;
+            for split in 1..BINS {
+                acc_box = acc_box.union(&bin_boxes[split - 1]);
+                acc_count += bin_counts[split - 1];
+
+                if acc_count == 0 || right_count[split] == 0 {
+                    continue;
+                }
+
+                let cost = acc_box.surface_area() * acc_count as f32
+                    + right_area[split] * right_count[split] as f32;
+
+                if best.is_none_or(|(_, _, c)| cost < c) {
+                    best = Some((axis, split, cost));
+                }
+            }
+        }
+
+        let parent_area = bbox.surface_area();
+
+        match best {
+            Some((axis, split, cost)) => {
+                let split_cost = if parent_area > 0.0 {
+                    TRAVERSAL_COST + cost / parent_area
+                } else {
+                    TRAVERSAL_COST + count as f32
+                };
+
+                if split_cost >= count as f32 && count <= MAX_LEAF_SIZE {
+                    return None;
+                }
+
+                let min = centroid_bounds.min[axis];
+                let size = extent[axis];
+                let mut mid = start;
+                for i in start..end {
+                    let c = centroids[self.indices[i]];
+                    let b = (((c[axis] - min) / size * BINS as f32) as usize).min(BINS - 1);
+                    if b < split {
+                        self.indices.swap(i, mid);
+                        mid += 1;
+                    }
+                }
+
+                Some(mid)
+            }
+            // all the centroids are in the same spot, nothing to gain from splitting unless
+            // there are too many of them, in which case just cut the list in half
+            None => {
+                if count <= MAX_LEAF_SIZE {
+                    None
+                } else {
+                    Some(start + count / 2)
+                }
+            }
+        }
+    }
+
+    /**
+     * Finds the closest primitive along the ray. `hit` is called with the primitive index and
+     * the distance to the closest hit found so far, it returns the distance to the primitive if
+     * the ray hits it. Children are visited nearest first and anything further than the current
+     * closest hit is skipped.
+     * Returns the index of the closest primitive and the distance to it
+     */
+    pub fn traverse<F>(&self, r: &Ray, mut hit: F) -> Option<(usize, f32)>
+    where
+        F: FnMut(usize, f32) -> Option<f32>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_dir = r.dir.recip();
+        let mut closest: Option<(usize, f32)> = None;
+        let mut t_max = f32::INFINITY;
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        if self.nodes[0].bbox.hit(r, inv_dir, t_max).is_some() {
+            stack.push(0);
+        }
+
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+
+            // the closest hit might have moved since this node was pushed
+            if node.bbox.hit(r, inv_dir, t_max).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &prim in &self.indices[node.offset..node.offset + node.count] {
+                    if let Some(t) = hit(prim, t_max) {
+                        if t < t_max {
+                            t_max = t;
+                            closest = Some((prim, t));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let first = node_idx + 1;
+            let second = node.offset;
+            let t_first = self.nodes[first].bbox.hit(r, inv_dir, t_max);
+            let t_second = self.nodes[second].bbox.hit(r, inv_dir, t_max);
+
+            // push the further one first so that the nearer one is popped next
+            match (t_first, t_second) {
+                (Some(a), Some(b)) => {
+                    if a <= b {
+                        stack.push(second);
+                        stack.push(first);
+                    } else {
+                        stack.push(first);
+                        stack.push(second);
+                    }
+                }
+                (Some(_), None) => stack.push(first),
+                (None, Some(_)) => stack.push(second),
+                (None, None) => {}
+            }
+        }
+
+        closest
+    }
+}
@@ -1,14 +1,502 @@
+use crate::ray::Ray;
+use crate::sampling;
+
+use glam::Vec2;
 use glam::Vec3;
+use std::f32::consts::PI;
+
+// vectors shorter than this can't tell us a direction
+const DEGENERATE_EPSILON: f32 = 1e-6;
+
+// f-stops are converted to a lens size as if the image was taken on a full frame (36x24mm) sensor
+// with scene units being meters
+const SENSOR_WIDTH_MM: f32 = 36.0;
+const SENSOR_HEIGHT_MM: f32 = 24.0;
+
+// which side of the image the field of view spans, the other one follows from the aspect ratio
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FovAxis {
+    Horizontal,
+    Vertical,
+}
+
+// how directions in front of the camera are laid out on the image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    // pinhole, straight lines stay straight
+    Perspective,
+    // parallel rays, the image covers the given size in scene units along the fov axis
+    Orthographic { size: f32 },
+    // equidistant fisheye, the distance from the center of the image is proportional to the angle
+    // from the view direction. The fov (up to 360) spans the image circle along the fov axis
+    Fisheye,
+    // longitude goes along the width and latitude along the height, covers the whole sphere
+    Equirectangular,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Aperture {
+    // everything is in focus
+    Pinhole,
+    // focal length divided by the aperture diameter, the focal length follows from the fov
+    FStop(f32),
+    // radius of the lens in scene units
+    Radius(f32),
+}
 
 #[derive(Copy, Clone)]
 pub struct Camera {
     pub pos: Vec3,
     pub lookat: Vec3,
+    // which way is up in the picture, doesn't need to be perpendicular to the view direction
+    pub vup: Vec3,
+    // in degrees
     pub fov: f32,
+    pub fov_axis: FovAxis,
+    pub projection: Projection,
+    // only used by the perspective projection
+    pub aperture: Aperture,
+    // distance to the plane that's in focus, the distance to lookat when not given
+    pub focus_distance: Option<f32>,
+    // number of aperture blades, bokeh takes their shape. Below 3 the aperture is round
+    pub blades: u32,
+    // in degrees
+    pub blade_rotation: f32,
 }
 
 impl Camera {
     pub fn new(pos: Vec3, lookat: Vec3, fov: f32) -> Camera {
-        Camera { pos, lookat, fov }
+        Camera {
+            pos,
+            lookat,
+            vup: Vec3::Y,
+            fov,
+            fov_axis: FovAxis::Vertical,
+            projection: Projection::Perspective,
+            aperture: Aperture::Pinhole,
+            focus_distance: None,
+            blades: 0,
+            blade_rotation: 0.0,
+        }
+    }
+
+    pub fn with_vup(mut self, vup: Vec3) -> Camera {
+        self.vup = vup;
+        self
+    }
+
+    pub fn with_fov_axis(mut self, fov_axis: FovAxis) -> Camera {
+        self.fov_axis = fov_axis;
+        self
+    }
+
+    pub fn with_projection(mut self, projection: Projection) -> Camera {
+        self.projection = projection;
+        self
+    }
+
+    pub fn with_aperture(mut self, aperture: Aperture) -> Camera {
+        self.aperture = aperture;
+        self
+    }
+
+    pub fn with_focus_distance(mut self, focus_distance: f32) -> Camera {
+        self.focus_distance = Some(focus_distance);
+        self
+    }
+
+    pub fn with_blades(mut self, blades: u32, blade_rotation: f32) -> Camera {
+        self.blades = blades;
+        self.blade_rotation = blade_rotation;
+        self
+    }
+
+    /**
+     * Radius of the lens in scene units. For f-stops the focal length is the one that gives our
+     * field of view on a full frame sensor, e.g. a 50mm lens at f/2 is 25mm wide, 0.0125 in radius
+     */
+    pub fn lens_radius(&self) -> f32 {
+        match self.aperture {
+            Aperture::Pinhole => 0.0,
+            Aperture::Radius(radius) => radius,
+            Aperture::FStop(f_stop) => {
+                let sensor_mm = match self.fov_axis {
+                    FovAxis::Horizontal => SENSOR_WIDTH_MM,
+                    FovAxis::Vertical => SENSOR_HEIGHT_MM,
+                };
+                let focal_length_mm = sensor_mm / (2.0 * (self.fov.to_radians() / 2.0).tan());
+
+                focal_length_mm / f_stop / 2.0 / 1000.0
+            }
+        }
+    }
+
+    pub fn get_focus_distance(&self) -> f32 {
+        self.focus_distance
+            .unwrap_or_else(|| (self.lookat - self.pos).length())
+    }
+
+    // point on the lens with unit radius, shaped by the blades
+    fn lens_point(&self, u: Vec2) -> Vec2 {
+        if self.blades >= 3 {
+            sampling::regular_polygon(u, self.blades, self.blade_rotation.to_radians())
+        } else {
+            sampling::concentric_disk(u)
+        }
+    }
+
+    /**
+     * Right, up and backwards unit vectors of the camera. When vup is parallel to the view direction
+     * (looking straight up or down with the default vup) it doesn't tell which way is up, any
+     * perpendicular direction is as good as another then
+     */
+    pub fn basis(&self) -> (Vec3, Vec3, Vec3) {
+        let w = (self.pos - self.lookat).normalize();
+
+        let right = self.vup.cross(w);
+        let u = if right.length() > DEGENERATE_EPSILON {
+            right.normalize()
+        } else {
+            sampling::orthonormal_basis(w).0
+        };
+
+        (u, w.cross(u), w)
+    }
+
+    /**
+     * Width and height of the image plane at distance 1 in front of the camera, the field of view
+     * is kept along its axis and the other side is stretched by the aspect ratio (width / height).
+     * For the orthographic projection it's the size of the image in scene units and for the rest
+     * the fov in radians
+     */
+    pub fn viewport(&self, aspect: f32) -> (f32, f32) {
+        let size = match self.projection {
+            Projection::Perspective => 2.0 * (self.fov.to_radians() / 2.0).tan(),
+            Projection::Orthographic { size } => size,
+            Projection::Fisheye | Projection::Equirectangular => self.fov.to_radians(),
+        };
+
+        match self.fov_axis {
+            FovAxis::Vertical => (size * aspect, size),
+            FovAxis::Horizontal => (size, size / aspect),
+        }
+    }
+
+    /**
+     * Primary ray through a point of the image, s goes from the left edge to the right one and t
+     * from the top to the bottom, both in [0;1]. Nothing is seen through points outside of the
+     * fisheye image circle
+     */
+    pub fn get_ray(&self, s: f32, t: f32, aspect: f32, lens_sample: Vec2) -> Option<Ray> {
+        let (u, v, w) = self.basis();
+        let (width, height) = self.viewport(aspect);
+
+        // offset from the center of the image, right and up
+        let x = (s - 0.5) * width;
+        let y = (0.5 - t) * height;
+
+        match self.projection {
+            Projection::Perspective => Some(self.perspective_ray(u * x + v * y - w, lens_sample)),
+            Projection::Orthographic { .. } => Some(Ray::new(self.pos + u * x + v * y, -w)),
+            Projection::Fisheye => {
+                // the offset is the angle from the view direction
+                let theta = (x * x + y * y).sqrt();
+                if theta > self.fov.to_radians() / 2.0 {
+                    return None;
+                }
+
+                let (sin, cos) = theta.sin_cos();
+                let (x, y) = if theta > 0.0 {
+                    (x / theta, y / theta)
+                } else {
+                    (0.0, 0.0)
+                };
+
+                Some(Ray::new(self.pos, (u * x + v * y) * sin - w * cos))
+            }
+            Projection::Equirectangular => {
+                let longitude = (s - 0.5) * 2.0 * PI;
+                let latitude = (0.5 - t) * PI;
+                let (sin_lon, cos_lon) = longitude.sin_cos();
+                let (sin_lat, cos_lat) = latitude.sin_cos();
+
+                let dir = u * cos_lat * sin_lon + v * sin_lat - w * cos_lat * cos_lon;
+                Some(Ray::new(self.pos, dir))
+            }
+        }
+    }
+
+    /**
+     * With an aperture the ray starts from a point on the lens picked by lens_sample (in [0;1]^2)
+     * and goes through the point where the pinhole ray meets the focus plane. Every ray through
+     * that point is bent to meet there so things on the focus plane are sharp while the rest is
+     * blurred the more the further they are from it
+     */
+    fn perspective_ray(&self, dir: Vec3, lens_sample: Vec2) -> Ray {
+        let lens_radius = self.lens_radius();
+        if lens_radius <= 0.0 {
+            return Ray::new(self.pos, dir);
+        }
+
+        let (u, v, _) = self.basis();
+
+        // dir is 1 long along the view direction so this lands on the focus plane
+        let focus_point = self.pos + dir * self.get_focus_distance();
+        let lens = self.lens_point(lens_sample) * lens_radius;
+        let origin = self.pos + u * lens.x + v * lens.y;
+
+        Ray::new(origin, focus_point - origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Hitable;
+    use crate::sphere::Sphere;
+
+    // which pixels of the image the sphere covers, as (count, width, height) of the covered area
+    fn footprint(camera: &Camera, sphere: &Sphere, width: u32, height: u32) -> (u32, u32, u32) {
+        let aspect = width as f32 / height as f32;
+        let mut count = 0;
+        let (mut min_x, mut max_x, mut min_y, mut max_y) = (width, 0, height, 0);
+
+        for y in 0..height {
+            for x in 0..width {
+                let s = (x as f32 + 0.5) / width as f32;
+                let t = (y as f32 + 0.5) / height as f32;
+
+                if sphere
+                    .hit(&camera.get_ray(s, t, aspect, Vec2::ZERO).unwrap())
+                    .is_some()
+                {
+                    count += 1;
+                    min_x = min_x.min(x);
+                    max_x = max_x.max(x);
+                    min_y = min_y.min(y);
+                    max_y = max_y.max(y);
+                }
+            }
+        }
+
+        (count, max_x - min_x + 1, max_y - min_y + 1)
+    }
+
+    #[test]
+    fn test_sphere_projects_as_circle() {
+        let sphere = Sphere::new(Vec3::ZERO, 1.0);
+
+        for fov_axis in [FovAxis::Vertical, FovAxis::Horizontal] {
+            let camera =
+                Camera::new(Vec3::new(0.0, 0.0, 10.0), Vec3::ZERO, 30.0).with_fov_axis(fov_axis);
+            let (count, w, h) = footprint(&camera, &sphere, 1920, 1080);
+
+            assert!(w > 100, "{:?}: the sphere is too small to tell", fov_axis);
+            assert!(w.abs_diff(h) <= 1, "{:?}: {}x{}", fov_axis, w, h);
+
+            let diameter = (w + h) as f32 / 2.0;
+            let circle = std::f32::consts::PI * diameter * diameter / 4.0;
+            assert!((count as f32 - circle).abs() < circle * 0.01);
+        }
+    }
+
+    #[test]
+    fn test_fov_axis() {
+        let camera = Camera::new(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0), 90.0);
+        let aspect = 16.0 / 9.0;
+
+        // the edges of the image are 45 degrees away from the center along the fov axis
+        let r = camera.get_ray(0.5, 0.0, aspect, Vec2::ZERO).unwrap();
+        assert!((r.dir.y - r.dir.z.abs()).abs() < 1e-5);
+
+        let camera = camera.with_fov_axis(FovAxis::Horizontal);
+        let r = camera.get_ray(1.0, 0.5, aspect, Vec2::ZERO).unwrap();
+        assert!((r.dir.x - r.dir.z.abs()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_image_orientation() {
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::ZERO, 60.0);
+
+        let r = camera.get_ray(1.0, 0.0, 1.0, Vec2::ZERO).unwrap();
+        assert!(r.dir.x > 0.0 && r.dir.y > 0.0, "top right goes {}", r.dir);
+
+        // tilting vup rolls the picture
+        let camera = camera.with_vup(Vec3::X);
+        let r = camera.get_ray(0.5, 0.0, 1.0, Vec2::ZERO).unwrap();
+        assert!(r.dir.x > 0.0 && r.dir.y.abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_right_is_right() {
+        // the camera from behind, to its right is where it lands in the image
+        let (width, height) = (64, 48);
+        let cameras = [
+            (Vec3::new(0.0, 0.0, 10.0), Vec3::ZERO, Vec3::X),
+            (Vec3::new(-10.0, 0.0, 0.0), Vec3::ZERO, Vec3::Z),
+            (Vec3::new(0.0, 0.0, -10.0), Vec3::ZERO, -Vec3::X),
+        ];
+
+        for (pos, lookat, right) in cameras {
+            let camera = Camera::new(pos, lookat, 60.0);
+            let sphere = Sphere::new(right * 2.0, 1.0);
+
+            let mut covered = Vec::new();
+            for y in 0..height {
+                for x in 0..width {
+                    let s = (x as f32 + 0.5) / width as f32;
+                    let t = (y as f32 + 0.5) / height as f32;
+                    let r = camera
+                        .get_ray(s, t, width as f32 / height as f32, Vec2::ZERO)
+                        .unwrap();
+                    if sphere.hit(&r).is_some() {
+                        covered.push(x);
+                    }
+                }
+            }
+
+            assert!(!covered.is_empty());
+            assert!(covered.iter().all(|&x| x >= width / 2), "from {}", pos);
+        }
+    }
+
+    #[test]
+    fn test_looking_straight_down() {
+        let camera = Camera::new(Vec3::new(0.0, 10.0, 0.0), Vec3::ZERO, 60.0);
+        let (u, v, w) = camera.basis();
+
+        for d in [u, v, w] {
+            assert!(d.is_finite() && (d.length() - 1.0).abs() < 1e-5);
+        }
+
+        let r = camera.get_ray(0.5, 0.5, 1.0, Vec2::ZERO).unwrap();
+        assert!((r.dir - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_f_stop_to_lens_radius() {
+        // 50mm lens on a full frame sensor
+        let fov = 2.0 * (12.0_f32 / 50.0).atan().to_degrees();
+        let camera = Camera::new(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0), fov)
+            .with_aperture(Aperture::FStop(2.0));
+
+        assert!((camera.lens_radius() - 0.0125).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_focus_plane_is_sharp() {
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::ZERO, 60.0)
+            .with_aperture(Aperture::Radius(0.5))
+            .with_blades(6, 15.0);
+
+        // the focus plane goes through lookat by default
+        let pinhole = camera
+            .with_aperture(Aperture::Pinhole)
+            .get_ray(0.3, 0.8, 1.5, Vec2::ZERO)
+            .unwrap();
+        let sharp = pinhole.at(5.0 / pinhole.dir.z.abs());
+
+        let mut spread: f32 = 0.0;
+        for i in 0..16 {
+            let lens_sample = Vec2::new(i as f32 / 16.0, (i * 7 % 16) as f32 / 16.0);
+            let r = camera.get_ray(0.3, 0.8, 1.5, lens_sample).unwrap();
+
+            // every ray goes through the same point on the focus plane
+            let on_focus_plane = r.at((r.origin.z) / r.dir.z.abs());
+            assert!((on_focus_plane - sharp).length() < 1e-4);
+
+            // but not through the same point anywhere else
+            let behind = r.at((r.origin.z + 5.0) / r.dir.z.abs());
+            spread = spread.max((behind - pinhole.at(10.0 / pinhole.dir.z.abs())).length());
+        }
+
+        assert!(spread > 0.1);
+    }
+
+    #[test]
+    fn test_bokeh_shape() {
+        let hexagon = |u: Vec2| sampling::regular_polygon(u, 6, 0.0);
+        // distance from the center to the middle of an edge of a hexagon inscribed in the unit circle
+        let apothem = (std::f32::consts::PI / 6.0).cos();
+
+        let mut max_distance: f32 = 0.0;
+        for i in 0..64 {
+            for j in 0..64 {
+                let p = hexagon(Vec2::new(i as f32 / 64.0, j as f32 / 64.0));
+                assert!(p.length() <= 1.0 + 1e-5);
+
+                // nothing beyond the flat edge between the first two corners
+                let edge_normal =
+                    Vec2::new(30.0_f32.to_radians().cos(), 30.0_f32.to_radians().sin());
+                assert!(p.dot(edge_normal) <= apothem + 1e-5);
+                max_distance = max_distance.max(p.length());
+            }
+        }
+
+        // while reaching close to the corners
+        assert!(max_distance > 0.95);
+    }
+
+    #[test]
+    fn test_orthographic() {
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::ZERO, 60.0)
+            .with_projection(Projection::Orthographic { size: 4.0 });
+
+        // every ray goes the same way, the image is 4 high and 8 wide
+        let corner = camera.get_ray(1.0, 0.0, 2.0, Vec2::ZERO).unwrap();
+        assert!((corner.origin - Vec3::new(4.0, 2.0, 5.0)).length() < 1e-5);
+        assert!((corner.dir - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-5);
+
+        // so things don't get smaller with distance
+        let sphere = Sphere::new(Vec3::ZERO, 1.0);
+        let far = camera.with_projection(Projection::Orthographic { size: 4.0 });
+        let far = Camera {
+            pos: Vec3::new(0.0, 0.0, 50.0),
+            ..far
+        };
+        assert_eq!(
+            footprint(&camera, &sphere, 64, 64),
+            footprint(&far, &sphere, 64, 64)
+        );
+    }
+
+    #[test]
+    fn test_fisheye() {
+        let camera = Camera::new(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0), 180.0)
+            .with_projection(Projection::Fisheye);
+
+        let center = camera.get_ray(0.5, 0.5, 1.0, Vec2::ZERO).unwrap();
+        assert!((center.dir - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-5);
+
+        // the edge of the image circle is 90 degrees to the side, halfway there is 45
+        let edge = camera.get_ray(1.0, 0.5, 1.0, Vec2::ZERO).unwrap();
+        assert!((edge.dir - Vec3::X).length() < 1e-5);
+        let halfway = camera.get_ray(0.75, 0.5, 1.0, Vec2::ZERO).unwrap();
+        assert!((halfway.dir.x - halfway.dir.z.abs()).abs() < 1e-5);
+
+        // nothing in the corners
+        assert!(camera.get_ray(0.0, 0.0, 1.0, Vec2::ZERO).is_none());
+
+        // all the way around the edge of the image looks backwards
+        let camera = Camera {
+            fov: 360.0,
+            ..camera
+        };
+        let edge = camera.get_ray(0.5, 0.0, 1.0, Vec2::ZERO).unwrap();
+        assert!((edge.dir - Vec3::Z).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_equirectangular() {
+        let camera = Camera::new(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0), 60.0)
+            .with_projection(Projection::Equirectangular);
+        let dir = |s, t| camera.get_ray(s, t, 2.0, Vec2::ZERO).unwrap().dir;
+
+        assert!((dir(0.5, 0.5) - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-5);
+        assert!((dir(0.75, 0.5) - Vec3::X).length() < 1e-5);
+        assert!((dir(0.25, 0.5) + Vec3::X).length() < 1e-5);
+        assert!((dir(0.0, 0.5) - Vec3::Z).length() < 1e-5);
+        assert!((dir(0.3, 0.0) - Vec3::Y).length() < 1e-5);
+        assert!((dir(0.3, 1.0) + Vec3::Y).length() < 1e-5);
     }
 }
@@ -0,0 +1,473 @@
+use crate::aov::AovAccumulator;
+use crate::app::Settings;
+use crate::filter::Splat;
+use crate::material::Color;
+use crate::scene::Totals;
+
+use glam::Vec3;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+/*
+    Checkpoints let a long render be stopped and picked up again. The file is a line of JSON with
+    the settings and what the film was rendered from, followed by the film itself as raw little
+    endian values row by row from the top: the sums, squares and sample count of every pixel, the
+    filtered sums and their weights and, when the render had AOVs, their accumulators. The
+    random numbers of a sample follow from the seed in the settings and the index of the sample
+    (see rng.rs) so the seed and the sample counts are all the random state there is, a resumed
+    render draws the same numbers the original one would have.
+    A checkpoint is only good for the scene it was rendered from, the scene file is hashed along
+    with the OBJ and MTL files it loads and resuming refuses any other.
+*/
+
+const MAGIC: &str = "rustracer checkpoint";
+const VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Header {
+    pub magic: String,
+    pub version: u32,
+    // of the scene file and the files it loads, see scene_hash
+    pub scene_hash: u64,
+    pub width: u32,
+    pub height: u32,
+    pub settings: Settings,
+    // samples per pixel of the last finished pass
+    pub goal: u32,
+}
+
+pub struct Checkpoint {
+    pub header: Header,
+    pub film: Vec<Totals>,
+    pub splats: Vec<Splat>,
+    // empty when the render had no AOVs
+    pub aov_film: Vec<AovAccumulator>,
+}
+
+impl Header {
+    pub fn new(scene_hash: u64, width: u32, height: u32, settings: Settings, goal: u32) -> Header {
+        Header {
+            magic: MAGIC.to_string(),
+            version: VERSION,
+            scene_hash,
+            width,
+            height,
+            settings,
+            goal,
+        }
+    }
+
+    /**
+     * Whether a render with these settings can carry on from the checkpoint. Anything that
+     * changes what a sample looks like has to match, how many samples there are may change
+     */
+    pub fn check(
+        &self,
+        scene_hash: u64,
+        width: u32,
+        height: u32,
+        settings: &Settings,
+    ) -> Result<(), String> {
+        let saved = &self.settings;
+
+        if self.scene_hash != scene_hash {
+            return Err("the scene has changed since the checkpoint was written".to_string());
+        }
+        if (self.width, self.height) != (width, height) {
+            return Err(format!(
+                "the checkpoint is {}x{}, not {}x{}",
+                self.width, self.height, width, height
+            ));
+        }
+        if saved.integrator != settings.integrator {
+            return Err(format!(
+                "the checkpoint was rendered with the {:?} integrator",
+                saved.integrator
+            ));
+        }
+        if saved.shadow_samples != settings.shadow_samples {
+            return Err(format!(
+                "the checkpoint was rendered with {} shadow samples",
+                saved.shadow_samples
+            ));
+        }
+        if saved.sampler != settings.sampler {
+            return Err(format!(
+                "the checkpoint was rendered with the {:?} sampler",
+                saved.sampler
+            ));
+        }
+        if saved.filter != settings.filter {
+            return Err(format!(
+                "the checkpoint was rendered with the {:?} filter of radius {}",
+                saved.filter.kind, saved.filter.radius
+            ));
+        }
+        if settings.aovs && !saved.aovs {
+            return Err("the checkpoint has no AOVs, they can't be added later".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * FNV-1a over the contents of every file in order, stable across builds and platforms unlike
+ * the hashers of the standard library. The length of each file goes in after it so that bytes
+ * moving from one file to the next still change the hash
+ */
+pub fn scene_hash<P: AsRef<Path>>(files: &[P]) -> io::Result<u64> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in files {
+        let contents = fs::read(file)?;
+        let length = (contents.len() as u64).to_le_bytes();
+
+        for &byte in contents.iter().chain(length.iter()) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    Ok(hash)
+}
+
+/**
+ * Goes through a temporary file next to the checkpoint, a render killed while writing leaves the
+ * previous checkpoint intact
+ */
+pub fn write(path: &str, checkpoint: &Checkpoint) -> io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    {
+        let mut w = BufWriter::new(File::create(&temporary)?);
+        w.write_all(&encode(checkpoint)?)?;
+        w.flush()?;
+    }
+
+    fs::rename(&temporary, path)
+}
+
+pub fn read(path: &str) -> io::Result<Checkpoint> {
+    decode(&fs::read(path)?)
+}
+
+fn encode(checkpoint: &Checkpoint) -> io::Result<Vec<u8>> {
+    let mut out = serde_json::to_vec(&checkpoint.header)?;
+    out.push(b'\n');
+
+    let color = |out: &mut Vec<u8>, c: Color| {
+        for v in [c.r, c.g, c.b] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    };
+    let vector = |out: &mut Vec<u8>, v: Vec3| color(out, v.into());
+
+    for t in &checkpoint.film {
+        color(&mut out, t.sum);
+        color(&mut out, t.squares);
+        out.extend_from_slice(&t.samples.to_le_bytes());
+    }
+
+    for s in &checkpoint.splats {
+        color(&mut out, s.sum);
+        out.extend_from_slice(&s.weight.to_le_bytes());
+    }
+
+    for a in &checkpoint.aov_film {
+        out.extend_from_slice(&a.samples.to_le_bytes());
+        out.extend_from_slice(&a.hits.to_le_bytes());
+        out.extend_from_slice(&a.sum.depth.to_le_bytes());
+        vector(&mut out, a.sum.normal);
+        color(&mut out, a.sum.albedo);
+        out.extend_from_slice(&a.sum.object_id.to_le_bytes());
+        out.extend_from_slice(&a.sum.material_id.to_le_bytes());
+        vector(&mut out, a.sum.position);
+        color(&mut out, a.sum.direct);
+        color(&mut out, a.sum.indirect);
+    }
+
+    Ok(out)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// reads the values encode wrote one after the other
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn word(&mut self) -> io::Result<[u8; 4]> {
+        if self.bytes.len() < 4 {
+            return Err(invalid("the checkpoint is cut short"));
+        }
+        let (word, rest) = self.bytes.split_at(4);
+        self.bytes = rest;
+
+        Ok([word[0], word[1], word[2], word[3]])
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.word()?))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.word()?))
+    }
+
+    fn color(&mut self) -> io::Result<Color> {
+        Ok(Color::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    fn vector(&mut self) -> io::Result<Vec3> {
+        Ok(self.color()?.into())
+    }
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Checkpoint> {
+    let end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| invalid("not a checkpoint"))?;
+    let header: Header =
+        serde_json::from_slice(&bytes[..end]).map_err(|_| invalid("not a checkpoint"))?;
+    if header.magic != MAGIC {
+        return Err(invalid("not a checkpoint"));
+    }
+    if header.version != VERSION {
+        return Err(invalid(&format!(
+            "checkpoint version {} can't be read, only {}",
+            header.version, VERSION
+        )));
+    }
+
+    let mut r = Reader {
+        bytes: &bytes[end + 1..],
+    };
+    let pixels = (header.width * header.height) as usize;
+
+    let mut film = Vec::with_capacity(pixels);
+    for _ in 0..pixels {
+        film.push(Totals {
+            sum: r.color()?,
+            squares: r.color()?,
+            samples: r.u32()?,
+        });
+    }
+
+    let mut splats = Vec::with_capacity(pixels);
+    for _ in 0..pixels {
+        splats.push(Splat {
+            sum: r.color()?,
+            weight: r.f32()?,
+        });
+    }
+
+    let mut aov_film = Vec::new();
+    if header.settings.aovs {
+        aov_film.reserve(pixels);
+        for _ in 0..pixels {
+            let mut a = AovAccumulator {
+                samples: r.u32()?,
+                hits: r.u32()?,
+                ..AovAccumulator::default()
+            };
+            a.sum.depth = r.f32()?;
+            a.sum.normal = r.vector()?;
+            a.sum.albedo = r.color()?;
+            a.sum.object_id = r.u32()?;
+            a.sum.material_id = r.u32()?;
+            a.sum.position = r.vector()?;
+            a.sum.direct = r.color()?;
+            a.sum.indirect = r.color()?;
+            aov_film.push(a);
+        }
+    }
+
+    if !r.bytes.is_empty() {
+        return Err(invalid("the checkpoint has trailing data"));
+    }
+
+    Ok(Checkpoint {
+        header,
+        film,
+        splats,
+        aov_film,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+    use crate::filter::FilterKind;
+    use crate::loader;
+    use crate::sampler::SamplerKind;
+
+    fn checkpoint(aovs: bool) -> Checkpoint {
+        let mut settings = Settings::new(4, 1, 1, 2);
+        settings.aovs = aovs;
+
+        let film = (0..6)
+            .map(|i| Totals {
+                sum: Color::new(i as f32, 0.5, 1.0e6),
+                squares: Color::new(0.25, i as f32 * 2.0, 0.0),
+                samples: i,
+            })
+            .collect();
+        let splats = (0..6)
+            .map(|i| Splat {
+                sum: Color::new(0.5, i as f32, 2.0),
+                weight: i as f32 * 0.75,
+            })
+            .collect();
+
+        let aov_film = if aovs {
+            (0..6)
+                .map(|i| {
+                    let mut a = AovAccumulator {
+                        samples: i,
+                        hits: i / 2,
+                        ..AovAccumulator::default()
+                    };
+                    a.sum.depth = 1.5;
+                    a.sum.normal = Vec3::new(0.0, i as f32, -1.0);
+                    a.sum.object_id = i + 1;
+                    a.sum.indirect = Color::new(0.1, 0.2, 0.3);
+                    a
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Checkpoint {
+            header: Header::new(42, 3, 2, settings, 4),
+            film,
+            splats,
+            aov_film,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for aovs in [false, true] {
+            let original = checkpoint(aovs);
+            let decoded = decode(&encode(&original).unwrap()).unwrap();
+
+            assert_eq!(decoded.header, original.header);
+            assert_eq!(decoded.film, original.film);
+            assert_eq!(decoded.splats, original.splats);
+            assert_eq!(decoded.aov_film.len(), original.aov_film.len());
+            for (a, b) in decoded.aov_film.iter().zip(&original.aov_film) {
+                assert_eq!((a.samples, a.hits), (b.samples, b.hits));
+                assert_eq!(a.sum.normal, b.sum.normal);
+                assert_eq!(a.sum.object_id, b.sum.object_id);
+                assert_eq!(a.sum.indirect, b.sum.indirect);
+            }
+        }
+    }
+
+    #[test]
+    fn test_damaged_files_are_refused() {
+        let bytes = encode(&checkpoint(true)).unwrap();
+
+        assert!(decode(&bytes[..bytes.len() - 3]).is_err());
+        assert!(decode(b"{\"width\": 3}\n").is_err());
+        assert!(decode(b"P6\n3 2\n255\n").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let header = checkpoint(false).header;
+        let settings = header.settings;
+        assert!(header.check(42, 3, 2, &settings).is_ok());
+
+        // more samples are the point of resuming
+        let more = Settings {
+            samples: 64,
+            max_samples: 64,
+            ..settings
+        };
+        assert!(header.check(42, 3, 2, &more).is_ok());
+
+        assert!(header.check(43, 3, 2, &settings).is_err());
+        assert!(header.check(42, 2, 3, &settings).is_err());
+        let aovs = Settings {
+            aovs: true,
+            ..settings
+        };
+        assert!(header.check(42, 3, 2, &aovs).is_err());
+        let shadows = Settings {
+            shadow_samples: 8,
+            ..settings
+        };
+        assert!(header.check(42, 3, 2, &shadows).is_err());
+        let sampler = Settings {
+            sampler: SamplerKind::Sobol,
+            ..settings
+        };
+        assert!(header.check(42, 3, 2, &sampler).is_err());
+        let filter = Settings {
+            filter: Filter::new(FilterKind::Mitchell, None),
+            ..settings
+        };
+        assert!(header.check(42, 3, 2, &filter).is_err());
+    }
+
+    #[test]
+    fn test_scene_hash() {
+        let a = scene_hash(&["scenes/scene_1.json"]).unwrap();
+        assert_eq!(a, scene_hash(&["scenes/scene_1.json"]).unwrap());
+        assert_ne!(a, scene_hash(&["scenes/scene_2.json"]).unwrap());
+        assert!(scene_hash(&["scenes/missing.json"]).is_err());
+
+        // the same bytes split differently between the files are a different scene
+        let both = scene_hash(&["scenes/scene_1.json", "scenes/scene_2.json"]).unwrap();
+        assert_ne!(both, a);
+        assert_ne!(
+            both,
+            scene_hash(&["scenes/scene_2.json", "scenes/scene_1.json"]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_changed_mesh_refuses_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("rustracer-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(dir.join("models")).unwrap();
+        fs::copy("scenes/scene_mesh.json", dir.join("scene.json")).unwrap();
+        for file in ["models/cube.obj", "models/cube.mtl"] {
+            fs::copy(Path::new("scenes").join(file), dir.join(file)).unwrap();
+        }
+
+        let scene = dir.join("scene.json");
+        let hash = || {
+            let loaded = loader::load(scene.to_str().unwrap()).unwrap();
+            scene_hash(&loaded.files).unwrap()
+        };
+        let header = Header::new(hash(), 3, 2, Settings::new(4, 1, 1, 2), 4);
+        let settings = header.settings;
+        assert!(header.check(hash(), 3, 2, &settings).is_ok());
+
+        // a bigger cube and a different red, neither is in the scene file itself
+        let changed = |file: &str, from: &str, to: &str| {
+            let path = dir.join(file);
+            let original = fs::read_to_string(&path).unwrap();
+            fs::write(&path, original.replace(from, to)).unwrap();
+            let result = header.check(hash(), 3, 2, &settings);
+            fs::write(&path, original).unwrap();
+            result
+        };
+        assert!(changed("models/cube.obj", "0.5", "0.75").is_err());
+        assert!(changed("models/cube.mtl", "0.8", "0.9").is_err());
+        assert!(header.check(hash(), 3, 2, &settings).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::scene::CollisionData;
+use crate::scene::Face;
+use crate::scene::Hitable;
+
+use glam::Vec3;
+use std::sync::Arc;
+
+/*
+    Constructive solid geometry, two solids combined into one. Along a ray each solid is a set of
+    intervals where the ray is inside of it, which we get from where it crosses the surface
+    going in and out. Going through the crossings of both in order and keeping track of whether
+    the ray is in either of them tells where it's in the combination, the crossings where that
+    changes are the surface of the result.
+    Both have to be closed for that, or a half space like a plane, open shapes like quads and
+    tubes have no inside to speak of.
+*/
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Union,
+    Intersection,
+    // a with b cut out of it
+    Difference,
+}
+
+impl Operation {
+    fn inside(self, a: bool, b: bool) -> bool {
+        match self {
+            Operation::Union => a || b,
+            Operation::Intersection => a && b,
+            Operation::Difference => a && !b,
+        }
+    }
+}
+
+pub struct Csg {
+    op: Operation,
+    a: Arc<dyn Hitable>,
+    b: Arc<dyn Hitable>,
+    bbox: Aabb,
+}
+
+impl Csg {
+    pub fn new(op: Operation, a: Arc<dyn Hitable>, b: Arc<dyn Hitable>) -> Csg {
+        let (box_a, box_b) = (a.bounding_box(), b.bounding_box());
+        let bbox = match op {
+            Operation::Union => box_a.union(&box_b),
+            Operation::Intersection => {
+                Aabb::new(box_a.min.max(box_b.min), box_a.max.min(box_b.max))
+            }
+            Operation::Difference => box_a,
+        };
+
+        Csg { op, a, b, bbox }
+    }
+}
+
+// the ray starts inside the solid when the first time it crosses the surface is on its way out
+fn starts_inside(crossings: &[(f32, CollisionData)]) -> bool {
+    matches!(crossings.first(), Some((_, hit)) if matches!(hit.face, Face::Back))
+}
+
+impl Hitable for Csg {
+    // on a, whatever of it is left
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        self.a.pos() + self.a.get_random_point(u) - self.pos()
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.bbox.centroid()
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bbox
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        self.crossings(r).into_iter().next().map(|(_, hit)| hit)
+    }
+
+    fn crossings(&self, r: &Ray) -> Vec<(f32, CollisionData)> {
+        let a = self.a.crossings(r);
+        let b = self.b.crossings(r);
+
+        let mut inside = [starts_inside(&a), starts_inside(&b)];
+        let mut was_inside = self.op.inside(inside[0], inside[1]);
+
+        let mut a = a.into_iter().peekable();
+        let mut b = b.into_iter().peekable();
+        let mut crossings = Vec::new();
+
+        loop {
+            let from_b = match (a.peek(), b.peek()) {
+                (Some((ta, _)), Some((tb, _))) => tb < ta,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (None, None) => break,
+            };
+            let (t, hit) = match from_b {
+                true => b.next(),
+                false => a.next(),
+            }
+            .unwrap();
+
+            inside[from_b as usize] = matches!(hit.face, Face::Front);
+            let now_inside = self.op.inside(inside[0], inside[1]);
+            if now_inside == was_inside {
+                continue;
+            }
+            was_inside = now_inside;
+
+            // where b is cut out its surface faces the other way
+            let outward = if from_b && self.op == Operation::Difference {
+                -hit.outward()
+            } else {
+                hit.outward()
+            };
+            crossings.push((t, CollisionData::new(r, t, outward, hit.uv)));
+        }
+
+        crossings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cuboid::Cuboid;
+    use crate::sphere::Sphere;
+
+    fn sphere(x: f32, radius: f32) -> Arc<dyn Hitable> {
+        Arc::new(Sphere::new(Vec3::new(x, 0.0, 0.0), radius))
+    }
+
+    // where the ray goes in and out along x, and which way
+    fn crossings(shape: &dyn Hitable, from: f32) -> Vec<(f32, bool)> {
+        shape
+            .crossings(&Ray::new(Vec3::new(from, 0.0, 0.0), -Vec3::X))
+            .iter()
+            .map(|(_, hit)| (hit.point.x, matches!(hit.face, Face::Front)))
+            .collect()
+    }
+
+    fn assert_crossings(actual: &[(f32, bool)], expected: &[(f32, bool)]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a.0 - e.0).abs() < 1.0e-4 && a.1 == e.1, "{:?}", actual);
+        }
+    }
+
+    #[test]
+    fn test_operations() {
+        // spheres from -1 to 1 and from 0 to 2
+        let (a, b) = (sphere(0.0, 1.0), sphere(1.0, 1.0));
+
+        let union = Csg::new(Operation::Union, a.clone(), b.clone());
+        assert_crossings(&crossings(&union, 5.0), &[(2.0, true), (-1.0, false)]);
+
+        let lens = Csg::new(Operation::Intersection, a.clone(), b.clone());
+        assert_crossings(&crossings(&lens, 5.0), &[(1.0, true), (0.0, false)]);
+
+        let bitten = Csg::new(Operation::Difference, a.clone(), b.clone());
+        assert_crossings(&crossings(&bitten, 5.0), &[(0.0, true), (-1.0, false)]);
+        let hit = bitten
+            .hit(&Ray::new(Vec3::new(5.0, 0.0, 0.0), -Vec3::X))
+            .unwrap();
+        // the inside of b that's left facing the ray
+        assert!(hit.normal.distance(Vec3::X) < 1.0e-5);
+
+        let bbox = lens.bounding_box();
+        assert!(bbox.min.distance(Vec3::new(0.0, -1.0, -1.0)) < 1.0e-5);
+        assert!(bbox.max.distance(Vec3::new(1.0, 1.0, 1.0)) < 1.0e-5);
+        assert_eq!(bitten.bounding_box(), a.bounding_box());
+    }
+
+    #[test]
+    fn test_drilled_block() {
+        let block: Arc<dyn Hitable> = Arc::new(Cuboid::new(Vec3::splat(-1.0), Vec3::splat(1.0)));
+        let drilled = Csg::new(Operation::Difference, block, sphere(0.0, 0.5));
+
+        // through the hole and out the other side
+        assert_crossings(
+            &crossings(&drilled, 5.0),
+            &[(1.0, true), (0.5, false), (-0.5, true), (-1.0, false)],
+        );
+
+        // from the hole the first thing is the wall of it, seen from outside the solid
+        let hit = drilled.hit(&Ray::new(Vec3::ZERO, -Vec3::X)).unwrap();
+        assert!(hit.point.distance(Vec3::new(-0.5, 0.0, 0.0)) < 1.0e-5);
+        assert!(matches!(hit.face, Face::Front));
+        assert!(hit.normal.distance(Vec3::X) < 1.0e-5);
+
+        // from inside the block the ray only leaves through the hole
+        assert_crossings(
+            &crossings(&drilled, 0.75),
+            &[(0.5, false), (-0.5, true), (-1.0, false)],
+        );
+
+        // and filling the hole with a ball makes it solid again
+        let filled = Csg::new(Operation::Union, Arc::new(drilled), sphere(0.0, 0.75));
+        assert_crossings(&crossings(&filled, 5.0), &[(1.0, true), (-1.0, false)]);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::aabb::Aabb;
+use crate::frame::Frame;
+use crate::ray::Ray;
+use crate::scene::CollisionData;
+use crate::scene::Hitable;
+
+use glam::Mat3;
+use glam::Vec2;
+use glam::Vec3;
+
+const THRESHOLD: f32 = 0.001;
+
+/**
+ * Box, axis aligned unless rotated around its center. It's intersected in its own frame where
+ * it's axis aligned and centered on the origin, with the same slab test the BVH uses for its
+ * boxes. The UVs of every side go from 0 to 1 across it
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Cuboid {
+    frame: Frame,
+    // half the size along each axis of the frame
+    half: Vec3,
+}
+
+impl Cuboid {
+    pub fn new(min: Vec3, max: Vec3) -> Cuboid {
+        Cuboid {
+            frame: Frame::new((min + max) * 0.5, Mat3::IDENTITY),
+            half: (max - min).abs() * 0.5,
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: Mat3) -> Cuboid {
+        self.frame.rotation = rotation;
+        self
+    }
+}
+
+impl Hitable for Cuboid {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        self.frame
+            .world_direction((u * 2.0 - Vec3::ONE) * self.half)
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.frame.origin
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.frame.bounding_box(-self.half, self.half)
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let local = self.frame.local_ray(r);
+        let inv_dir = local.dir.recip();
+        let t0 = (-self.half - local.origin) * inv_dir;
+        let t1 = (self.half - local.origin) * inv_dir;
+
+        let t_near = t0.min(t1).max_element();
+        let t_far = t0.max(t1).min_element();
+        if t_near > t_far || t_far <= THRESHOLD {
+            return None;
+        }
+
+        // from inside the box it's where the ray leaves
+        let t = if t_near > THRESHOLD { t_near } else { t_far };
+
+        // the side hit is the one the point is the furthest out towards
+        let p = local.at(t);
+        let relative = p / self.half;
+        let axis = if relative.x.abs() >= relative.y.abs() && relative.x.abs() >= relative.z.abs() {
+            0
+        } else if relative.y.abs() >= relative.z.abs() {
+            1
+        } else {
+            2
+        };
+
+        let mut outward = Vec3::ZERO;
+        outward[axis] = relative[axis].signum();
+        let across = (relative * 0.5 + Vec3::splat(0.5)).clamp(Vec3::ZERO, Vec3::ONE);
+        let uv = Vec2::new(across[(axis + 1) % 3], across[(axis + 2) % 3]);
+
+        Some(CollisionData::new(
+            r,
+            t,
+            self.frame.world_direction(outward),
+            uv,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Face;
+
+    #[test]
+    fn test_sides() {
+        let cuboid = Cuboid::new(Vec3::new(-1.0, -2.0, -3.0), Vec3::new(1.0, 2.0, 3.0));
+
+        let cases = [
+            (
+                Vec3::new(5.0, 0.5, 0.5),
+                -Vec3::X,
+                Vec3::new(1.0, 0.5, 0.5),
+                Vec3::X,
+            ),
+            (
+                Vec3::new(0.5, -5.0, 0.5),
+                Vec3::Y,
+                Vec3::new(0.5, -2.0, 0.5),
+                -Vec3::Y,
+            ),
+            (
+                Vec3::new(0.5, 0.5, 9.0),
+                -Vec3::Z,
+                Vec3::new(0.5, 0.5, 3.0),
+                Vec3::Z,
+            ),
+        ];
+        for (origin, dir, point, normal) in cases {
+            let hit = cuboid.hit(&Ray::new(origin, dir)).unwrap();
+            assert!(hit.point.distance(point) < 1.0e-5, "{:?}", hit.point);
+            assert_eq!(hit.normal, normal);
+            assert!(matches!(hit.face, Face::Front));
+            assert!(hit.uv.cmpge(Vec2::ZERO).all() && hit.uv.cmple(Vec2::ONE).all());
+        }
+
+        // from inside it's the far side, seen from the back
+        let hit = cuboid.hit(&Ray::new(Vec3::ZERO, Vec3::Y)).unwrap();
+        assert!(hit.point.distance(Vec3::new(0.0, 2.0, 0.0)) < 1.0e-5);
+        assert!(matches!(hit.face, Face::Back));
+        assert_eq!(hit.normal, -Vec3::Y);
+
+        assert!(cuboid
+            .hit(&Ray::new(Vec3::new(5.0, 5.0, 0.0), Vec3::X))
+            .is_none());
+    }
+
+    #[test]
+    fn test_rotated() {
+        // a unit cube turned 45 degrees around y has a corner where the side used to be
+        let cuboid = Cuboid::new(Vec3::splat(-0.5), Vec3::splat(0.5))
+            .with_rotation(Mat3::from_rotation_y(std::f32::consts::FRAC_PI_4));
+
+        let hit = cuboid
+            .hit(&Ray::new(Vec3::new(5.0, 0.0, 0.1), -Vec3::X))
+            .unwrap();
+        let corner = 0.5 * std::f32::consts::SQRT_2;
+        assert!(
+            (hit.point.x - (corner - 0.1)).abs() < 1.0e-4,
+            "{:?}",
+            hit.point
+        );
+        assert!((hit.normal.x - hit.normal.z.abs()).abs() < 1.0e-5);
+
+        let bbox = cuboid.bounding_box();
+        assert!((bbox.max.x - corner).abs() < 1.0e-5);
+        assert!((bbox.max.y - 0.5).abs() < 1.0e-5);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::aabb::Aabb;
+use crate::frame::Frame;
+use crate::ray::Ray;
+use crate::sampling;
+use crate::scene::CollisionData;
+use crate::scene::Hitable;
+
+use glam::Vec2;
+use glam::Vec3;
+use std::f32::consts::PI;
+
+/*
+    Cylinders and cones, both standing on their base with the axis going up to the top. They are
+    intersected in the frame where the base is at the origin and the axis is y: the side is a
+    quadric that gives a quadratic in the distance along the ray, its roots outside the height
+    are dropped, and the caps are disks at either end. The nearest of all that is the hit.
+    u goes around the axis and v up the side, on the caps the UVs are the disk seen from above
+    squeezed into [0;1].
+*/
+
+const THRESHOLD: f32 = 0.001;
+
+// coefficients this small mean the ray runs along the side
+const EPSILON: f32 = 1e-8;
+
+// a hit in the frame of the shape, the normal points out of it
+struct LocalHit {
+    t: f32,
+    outward: Vec3,
+    uv: Vec2,
+}
+
+// roots of a t^2 + b t + c = 0, the smaller one first
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    if a.abs() < EPSILON {
+        if b.abs() < EPSILON {
+            return None;
+        }
+        let t = -c / b;
+        return Some((t, t));
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    // avoids subtracting two almost equal numbers
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (t0, t1) = (q / a, if q != 0.0 { c / q } else { q / a });
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+fn around(p: Vec3) -> f32 {
+    (p.z.atan2(p.x) + PI) / (2.0 * PI)
+}
+
+// the cap at height y with the radius, facing up or down
+fn cap(local: &Ray, y: f32, radius: f32, up: bool) -> Option<LocalHit> {
+    if local.dir.y.abs() < EPSILON {
+        return None;
+    }
+
+    let t = (y - local.origin.y) / local.dir.y;
+    let p = local.at(t);
+    if p.x * p.x + p.z * p.z > radius * radius {
+        return None;
+    }
+
+    Some(LocalHit {
+        t,
+        outward: if up { Vec3::Y } else { -Vec3::Y },
+        uv: Vec2::new(p.x, p.z) / (2.0 * radius) + Vec2::splat(0.5),
+    })
+}
+
+// the side between the roots of the quadric that are within the height
+fn side(
+    local: &Ray,
+    roots: Option<(f32, f32)>,
+    height: f32,
+    normal: impl Fn(Vec3) -> Vec3,
+) -> [Option<LocalHit>; 2] {
+    let at = |t: f32| {
+        let p = local.at(t);
+        if !(0.0..=height).contains(&p.y) {
+            return None;
+        }
+
+        Some(LocalHit {
+            t,
+            outward: normal(p),
+            uv: Vec2::new(around(p), p.y / height),
+        })
+    };
+
+    match roots {
+        Some((t0, t1)) => [at(t0), at(t1)],
+        None => [None, None],
+    }
+}
+
+fn nearest(
+    r: &Ray,
+    frame: &Frame,
+    hits: impl IntoIterator<Item = Option<LocalHit>>,
+) -> Option<CollisionData> {
+    let hit = hits
+        .into_iter()
+        .flatten()
+        .filter(|h| h.t > THRESHOLD)
+        .min_by(|a, b| a.t.total_cmp(&b.t))?;
+
+    Some(CollisionData::new(
+        r,
+        hit.t,
+        frame.world_direction(hit.outward).normalize(),
+        hit.uv,
+    ))
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Cylinder {
+    frame: Frame,
+    radius: f32,
+    height: f32,
+    // an open tube without them
+    capped: bool,
+}
+
+impl Cylinder {
+    pub fn new(base: Vec3, top: Vec3, radius: f32, capped: bool) -> Cylinder {
+        Cylinder {
+            frame: Frame::around(base, (top - base).normalize()),
+            radius,
+            height: (top - base).length(),
+            capped,
+        }
+    }
+}
+
+impl Hitable for Cylinder {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        let p = sampling::concentric_disk(u.truncate()) * self.radius;
+        let y = (u.z - 0.5) * self.height;
+        self.frame.world_direction(Vec3::new(p.x, y, p.y))
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.frame
+            .world_point(Vec3::new(0.0, self.height * 0.5, 0.0))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = self.radius;
+        self.frame
+            .bounding_box(Vec3::new(-r, 0.0, -r), Vec3::new(r, self.height, r))
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let local = self.frame.local_ray(r);
+        let (o, d) = (local.origin, local.dir);
+
+        // x^2 + z^2 = radius^2
+        let roots = solve_quadratic(
+            d.x * d.x + d.z * d.z,
+            2.0 * (o.x * d.x + o.z * d.z),
+            o.x * o.x + o.z * o.z - self.radius * self.radius,
+        );
+        let [a, b] = side(&local, roots, self.height, |p| {
+            Vec3::new(p.x, 0.0, p.z) / self.radius
+        });
+
+        let caps = if self.capped {
+            [
+                cap(&local, 0.0, self.radius, false),
+                cap(&local, self.height, self.radius, true),
+            ]
+        } else {
+            [None, None]
+        };
+
+        nearest(r, &self.frame, [a, b].into_iter().chain(caps))
+    }
+}
+
+// the base is round with the radius, the tip is at the top
+#[derive(Copy, Clone, Debug)]
+pub struct Cone {
+    frame: Frame,
+    radius: f32,
+    height: f32,
+    // whether the base is closed
+    capped: bool,
+}
+
+impl Cone {
+    pub fn new(base: Vec3, top: Vec3, radius: f32, capped: bool) -> Cone {
+        Cone {
+            frame: Frame::around(base, (top - base).normalize()),
+            radius,
+            height: (top - base).length(),
+            capped,
+        }
+    }
+}
+
+impl Hitable for Cone {
+    // there's more of the cone near the base, s is how far from the tip towards it
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        let s = u.z.cbrt();
+        let p = sampling::concentric_disk(u.truncate()) * self.radius * s;
+        let y = self.height * (1.0 - s) - self.height * 0.5;
+        self.frame.world_direction(Vec3::new(p.x, y, p.y))
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.frame
+            .world_point(Vec3::new(0.0, self.height * 0.5, 0.0))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = self.radius;
+        self.frame
+            .bounding_box(Vec3::new(-r, 0.0, -r), Vec3::new(r, self.height, r))
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let local = self.frame.local_ray(r);
+        let (o, d) = (local.origin, local.dir);
+
+        // x^2 + z^2 = k^2 (height - y)^2 where k is how much the radius shrinks per unit up
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let below_tip = self.height - o.y;
+        let roots = solve_quadratic(
+            d.x * d.x + d.z * d.z - k2 * d.y * d.y,
+            2.0 * (o.x * d.x + o.z * d.z + k2 * below_tip * d.y),
+            o.x * o.x + o.z * o.z - k2 * below_tip * below_tip,
+        );
+        let [a, b] = side(&local, roots, self.height, |p| {
+            let n = Vec3::new(p.x, k2 * (self.height - p.y), p.z);
+            // right at the tip there's no telling, up is as good as anything
+            if n.length_squared() < EPSILON {
+                Vec3::Y
+            } else {
+                n.normalize()
+            }
+        });
+
+        let base = if self.capped {
+            cap(&local, 0.0, self.radius, false)
+        } else {
+            None
+        };
+
+        nearest(r, &self.frame, [a, b, base])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Face;
+
+    #[test]
+    fn test_cylinder() {
+        let cylinder = Cylinder::new(Vec3::ZERO, Vec3::new(0.0, 2.0, 0.0), 1.0, true);
+
+        let side = cylinder
+            .hit(&Ray::new(Vec3::new(5.0, 1.5, 0.0), -Vec3::X))
+            .unwrap();
+        assert!(side.point.distance(Vec3::new(1.0, 1.5, 0.0)) < 1.0e-5);
+        assert!(side.normal.distance(Vec3::X) < 1.0e-5);
+        assert!(matches!(side.face, Face::Front));
+        assert!((side.uv.y - 0.75).abs() < 1.0e-5);
+
+        let top = cylinder
+            .hit(&Ray::new(Vec3::new(0.5, 5.0, 0.0), -Vec3::Y))
+            .unwrap();
+        assert!(top.point.distance(Vec3::new(0.5, 2.0, 0.0)) < 1.0e-5);
+        assert!(top.normal.distance(Vec3::Y) < 1.0e-5);
+
+        // above the top the side doesn't count
+        assert!(cylinder
+            .hit(&Ray::new(Vec3::new(5.0, 2.5, 0.0), -Vec3::X))
+            .is_none());
+
+        // from inside
+        let inside = cylinder
+            .hit(&Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::Z))
+            .unwrap();
+        assert!(matches!(inside.face, Face::Back));
+        assert!(inside.normal.distance(-Vec3::Z) < 1.0e-5);
+
+        // without caps the ray goes down the tube and hits the inside of the wall
+        let open = Cylinder::new(Vec3::ZERO, Vec3::new(0.0, 2.0, 0.0), 1.0, false);
+        let hit = open
+            .hit(&Ray::new(
+                Vec3::new(0.0, 5.0, 0.0),
+                Vec3::new(0.2, -1.0, 0.0),
+            ))
+            .unwrap();
+        assert!(matches!(hit.face, Face::Back));
+        assert!((hit.point.x - 1.0).abs() < 1.0e-4);
+    }
+
+    #[test]
+    fn test_tilted_cylinder() {
+        // lying along x
+        let cylinder = Cylinder::new(Vec3::ZERO, Vec3::new(4.0, 0.0, 0.0), 0.5, true);
+        let bbox = cylinder.bounding_box();
+        assert!(bbox.min.distance(Vec3::new(0.0, -0.5, -0.5)) < 1.0e-5);
+        assert!(bbox.max.distance(Vec3::new(4.0, 0.5, 0.5)) < 1.0e-5);
+
+        let hit = cylinder
+            .hit(&Ray::new(Vec3::new(1.0, 3.0, 0.0), -Vec3::Y))
+            .unwrap();
+        assert!(hit.point.distance(Vec3::new(1.0, 0.5, 0.0)) < 1.0e-5);
+        assert!(hit.normal.distance(Vec3::Y) < 1.0e-5);
+
+        let end = cylinder
+            .hit(&Ray::new(Vec3::new(9.0, 0.1, 0.1), -Vec3::X))
+            .unwrap();
+        assert!(end.normal.distance(Vec3::X) < 1.0e-5);
+    }
+
+    #[test]
+    fn test_cone() {
+        // 45 degree sides, the tip at y = 1
+        let cone = Cone::new(Vec3::ZERO, Vec3::Y, 1.0, true);
+
+        let side = cone
+            .hit(&Ray::new(Vec3::new(5.0, 0.5, 0.0), -Vec3::X))
+            .unwrap();
+        assert!(side.point.distance(Vec3::new(0.5, 0.5, 0.0)) < 1.0e-5);
+        let expected = Vec3::new(1.0, 1.0, 0.0).normalize();
+        assert!(side.normal.distance(expected) < 1.0e-5);
+        assert!(matches!(side.face, Face::Front));
+
+        let base = cone
+            .hit(&Ray::new(Vec3::new(0.5, -3.0, 0.0), Vec3::Y))
+            .unwrap();
+        assert!(base.point.distance(Vec3::new(0.5, 0.0, 0.0)) < 1.0e-5);
+        assert!(base.normal.distance(-Vec3::Y) < 1.0e-5);
+
+        // the other nappe of the double cone above the tip isn't part of it
+        assert!(cone
+            .hit(&Ray::new(Vec3::new(5.0, 1.5, 0.0), -Vec3::X))
+            .is_none());
+
+        let down = cone
+            .hit(&Ray::new(Vec3::new(0.0, 3.0, 0.0), -Vec3::Y))
+            .unwrap();
+        assert!(down.point.distance(Vec3::Y) < 1.0e-4);
+    }
+}
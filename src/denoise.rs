@@ -0,0 +1,337 @@
+use crate::aov::AovPixel;
+use crate::material::Color;
+
+use glam::Vec3;
+
+/*
+    Denoiser for low sample renders, a joint non-local means filter (Rousselle et al. 2012, with
+    feature buffers in the spirit of later work). Every pixel becomes a weighted average of the
+    pixels in a window around it, a neighbour gets a high weight when:
+    - the patches around the two pixels look alike, taking into account how noisy they are. Two
+      pixels differing by about as much as their variance says they would are considered equal
+    - the first hit surfaces have similar normals, which keeps geometric edges sharp
+    - they have similar albedo, which keeps edges between materials sharp
+    Noisy areas end up averaged over the whole window while converged ones barely change.
+*/
+
+// window is (2 * RADIUS + 1)^2 pixels
+const RADIUS: i32 = 7;
+
+// patches compared are (2 * PATCH_RADIUS + 1)^2 pixels
+const PATCH_RADIUS: i32 = 3;
+
+// how different two patches may be relative to their variance, higher blurs more
+const STRENGTH: f32 = 0.45;
+
+// keeps the color distance finite where the variance is 0
+const EPSILON: f32 = 1e-4;
+
+// squared distances of the guides at which the weight drops to 1/e
+const NORMAL_SIGMA2: f32 = 0.1;
+const ALBEDO_SIGMA2: f32 = 0.01;
+
+struct Image<'a, T> {
+    width: i32,
+    height: i32,
+    data: &'a [T],
+}
+
+impl<'a, T: Copy> Image<'a, T> {
+    // coordinates outside are clamped to the edge
+    fn at(&self, x: i32, y: i32) -> T {
+        let x = x.clamp(0, self.width - 1);
+        let y = y.clamp(0, self.height - 1);
+        self.data[(y * self.width + x) as usize]
+    }
+}
+
+/**
+ * Filters the beauty image with the variance of every pixel and the albedo and normal of the
+ * AOVs as guides. All of them are row by row from the top.
+ * Rather than comparing whole patches for every pair of pixels, the window is walked one offset at
+ * a time: the per pixel distances to the pixel at that offset are computed for the whole image and
+ * box filtered, which gives the patch distances for all the pixels at once
+ */
+pub fn denoise(
+    width: u32,
+    height: u32,
+    color: &[Color],
+    variance: &[f32],
+    guides: &[AovPixel],
+) -> Vec<Color> {
+    let (width, height) = (width as i32, height as i32);
+
+    // variance estimated from a few samples is noisy itself, a small blur steadies it
+    let variance = box_filter(width, height, variance, 1);
+
+    let color = Image {
+        width,
+        height,
+        data: color,
+    };
+    let variance = Image {
+        width,
+        height,
+        data: &variance,
+    };
+    let guides = Image {
+        width,
+        height,
+        data: guides,
+    };
+
+    let pixels = (width * height) as usize;
+    let mut sum = vec![Vec3::ZERO; pixels];
+    let mut total_weight = vec![0.0; pixels];
+    let mut distances = Vec::with_capacity(pixels);
+
+    for dy in -RADIUS..=RADIUS {
+        for dx in -RADIUS..=RADIUS {
+            distances.clear();
+            for y in 0..height {
+                for x in 0..width {
+                    let (qx, qy) = (x + dx, y + dy);
+                    distances.push(if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                        f32::NAN
+                    } else {
+                        color_distance(&color, &variance, (x, y), (qx, qy))
+                    });
+                }
+            }
+            let patch_distances = box_filter(width, height, &distances, PATCH_RADIUS);
+
+            for y in 0..height {
+                for x in 0..width {
+                    let (qx, qy) = (x + dx, y + dy);
+                    if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                        continue;
+                    }
+
+                    let i = (y * width + x) as usize;
+                    // the pixel itself is always in its own window so this is never NaN
+                    let distance = patch_distances[i].max(0.0)
+                        + feature_distance(&guides.at(x, y), &guides.at(qx, qy));
+                    let weight = (-distance).exp();
+
+                    sum[i] += Vec3::from(color.at(qx, qy)) * weight;
+                    total_weight[i] += weight;
+                }
+            }
+        }
+    }
+
+    // the pixel itself always has weight 1 so the total can't be 0
+    sum.iter()
+        .zip(total_weight)
+        .map(|(sum, weight)| (*sum / weight).into())
+        .collect()
+}
+
+/**
+ * Squared difference of the two pixels minus what the noise alone would explain, relative to
+ * their variance. Averaged over a patch and clamped at 0 it's the patch distance, patches that
+ * differ less than expected are just as alike as identical ones
+ */
+fn color_distance(
+    color: &Image<Color>,
+    variance: &Image<f32>,
+    p: (i32, i32),
+    q: (i32, i32),
+) -> f32 {
+    let var_p = variance.at(p.0, p.1);
+    let var_q = variance.at(q.0, q.1);
+    let diff = Vec3::from(color.at(p.0, p.1)) - Vec3::from(color.at(q.0, q.1));
+
+    let d = (diff * diff - Vec3::splat(var_p + var_p.min(var_q)))
+        / (EPSILON + STRENGTH * STRENGTH * (var_p + var_q));
+    (d.x + d.y + d.z) / 3.0
+}
+
+fn feature_distance(a: &AovPixel, b: &AovPixel) -> f32 {
+    let normal = (a.normal - b.normal).length_squared();
+    let albedo = (Vec3::from(a.albedo) - Vec3::from(b.albedo)).length_squared();
+
+    normal / NORMAL_SIGMA2 + albedo / ALBEDO_SIGMA2
+}
+
+/**
+ * Average over the (2 * radius + 1)^2 square, done as a horizontal and then a vertical pass. Only
+ * what's inside the image and not NaN counts, that way patches at the edges aren't dominated by
+ * the edge pixels
+ */
+fn box_filter(width: i32, height: i32, values: &[f32], radius: i32) -> Vec<f32> {
+    let pass = |values: &[f32], step: (i32, i32)| -> Vec<f32> {
+        let mut result = Vec::with_capacity(values.len());
+
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0.0;
+                let mut count = 0;
+
+                for i in -radius..=radius {
+                    let (sx, sy) = (x + i * step.0, y + i * step.1);
+                    if sx < 0 || sy < 0 || sx >= width || sy >= height {
+                        continue;
+                    }
+
+                    let v = values[(sy * width + sx) as usize];
+                    if !v.is_nan() {
+                        sum += v;
+                        count += 1;
+                    }
+                }
+
+                result.push(if count > 0 {
+                    sum / count as f32
+                } else {
+                    f32::NAN
+                });
+            }
+        }
+
+        result
+    };
+
+    pass(&pass(values, (1, 0)), (0, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app;
+    use crate::app::BBox;
+    use crate::loader;
+    use crate::scene;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+
+    struct Render {
+        color: Vec<Color>,
+        variance: Vec<f32>,
+        guides: Vec<AovPixel>,
+    }
+
+    fn render(path: &str, size: u32, samples: u32, seed: u64) -> Render {
+        let loaded = loader::load(path).unwrap();
+        let mut scene = scene::Scene::new(size as u64, size as u64);
+        for (geometry, mat, kind) in loaded.objects {
+            match kind {
+                loader::Kind::Object => scene.add_object(geometry, mat),
+                loader::Kind::Light { intensity } => scene.add_light(geometry, mat, intensity),
+            }
+        }
+        scene.finalize();
+
+        let mut settings = app::Settings::new(samples, 1, 1, 1);
+        settings.integrator = app::Integrator::Path;
+        settings.aovs = true;
+        settings.seed = seed;
+
+        let bbox = BBox {
+            x: 0,
+            y: 0,
+            w: size as i32,
+            h: size as i32,
+        };
+        let (tx, _rx) = mpsc::channel();
+        let pass = app::Pass::single(&settings);
+        let mut pixels =
+            scene::render_block(Arc::new(scene), loaded.camera, settings, pass, bbox, tx).pixels;
+        pixels.sort_by_key(|p| (p.y, p.x));
+
+        Render {
+            color: pixels.iter().map(|p| p.totals.mean()).collect(),
+            variance: pixels.iter().map(|p| p.totals.variance()).collect(),
+            guides: pixels.iter().map(|p| p.aov.finish()).collect(),
+        }
+    }
+
+    // values are clipped at 1 the way they'd end up in a PNG, the odd firefly shouldn't dominate
+    fn mse(a: &[Color], b: &[Color]) -> f32 {
+        let total: f32 = a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| {
+                let d = Vec3::from(*a).min(Vec3::ONE) - Vec3::from(*b).min(Vec3::ONE);
+                d.length_squared() / 3.0
+            })
+            .sum();
+
+        total / a.len() as f32
+    }
+
+    #[test]
+    fn test_denoising_gets_closer_to_the_reference() {
+        let size = 64;
+        // different seeds, the reference shouldn't share its first samples with the noisy one
+        let reference = render("scenes/scene_1.json", size, 128, 1);
+        let noisy = render("scenes/scene_1.json", size, 8, 2);
+
+        let denoised = denoise(size, size, &noisy.color, &noisy.variance, &noisy.guides);
+
+        let before = mse(&noisy.color, &reference.color);
+        let after = mse(&denoised, &reference.color);
+        assert!(
+            after < before * 0.8,
+            "mse {} before, {} after",
+            before,
+            after
+        );
+    }
+
+    // scrambles the coordinates into something that looks random
+    fn hash(x: u32, y: u32) -> u32 {
+        let h = x.wrapping_mul(73856093) ^ y.wrapping_mul(19349663);
+        h.wrapping_mul(0x9E3779B1) >> 16
+    }
+
+    #[test]
+    fn test_flat_noise_is_smoothed_and_edges_kept() {
+        // left half is one material, right half another, both with the same noise
+        let (width, height) = (16, 16);
+        let mut color = Vec::new();
+        let mut guides = Vec::new();
+
+        for y in 0..height {
+            for x in 0..width {
+                let left = x < width / 2;
+                let noise = if hash(x, y) & 1 == 0 { 0.1 } else { -0.1 };
+                let base = if left { 0.2 } else { 0.8 };
+                color.push(Color::new(base + noise, base + noise, base + noise));
+
+                let albedo = if left { 0.2 } else { 0.8 };
+                guides.push(AovPixel {
+                    normal: Vec3::Z,
+                    albedo: Color::new(albedo, albedo, albedo),
+                    ..AovPixel::default()
+                });
+            }
+        }
+        let variance = vec![0.01; (width * height) as usize];
+
+        let denoised = denoise(width, height, &color, &variance, &guides);
+
+        // nothing bleeds over the edge
+        for y in 0..height {
+            for x in 0..width {
+                let v = denoised[(y * width + x) as usize].r;
+                if x < width / 2 {
+                    assert!(v < 0.4, "{} at {},{}", v, x, y);
+                } else {
+                    assert!(v > 0.6, "{} at {},{}", v, x, y);
+                }
+            }
+        }
+
+        // while the noise is mostly gone
+        let spread = |image: &[Color]| {
+            let left: Vec<f32> = (0..height)
+                .flat_map(|y| (0..width / 2).map(move |x| (y * width + x) as usize))
+                .map(|i| image[i].r)
+                .collect();
+            let mean = left.iter().sum::<f32>() / left.len() as f32;
+            left.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / left.len() as f32
+        };
+        assert!(spread(&denoised) < spread(&color) * 0.2);
+    }
+}
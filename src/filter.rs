@@ -0,0 +1,297 @@
+use crate::material::Color;
+
+use glam::Vec2;
+use serde::Deserialize;
+use serde::Serialize;
+use std::f32::consts::PI;
+
+/*
+    Reconstruction filters turn the samples into pixels. A sample lands somewhere on the film and
+    is splatted onto every pixel whose center is within the radius of the filter, weighted by the
+    filter at the distance between them. A pixel ends up as the weighted sum of the samples around
+    it divided by the sum of their weights, so the filters don't need to be normalized.
+    - box: every sample counts the same, with the default radius of half a pixel a sample only
+      counts for the pixel it was taken in
+    - tent: falls off linearly, a little softer
+    - gaussian: smooth and soft, no ringing
+    - mitchell: Mitchell-Netravali with B = C = 1/3, sharper than the Gaussian with a slight
+      negative lobe
+    - lanczos: windowed sinc, the sharpest but rings around hard edges
+    The filters are separable, the weight is the product of the filter along x and along y.
+    Samples reach across the edges of the tiles, render_block splats into a tile that's larger
+    than its block by the radius and the tiles are added up when the pass is done.
+*/
+
+#[derive(clap::ArgEnum, Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+#[clap(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+impl FilterKind {
+    // in pixels, what --filter-radius falls back to
+    pub fn default_radius(&self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub struct Filter {
+    pub kind: FilterKind,
+    // in pixels, from the center of the filter to where it drops to 0
+    pub radius: f32,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::new(FilterKind::Box, None)
+    }
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, radius: Option<f32>) -> Filter {
+        Filter {
+            kind,
+            radius: radius.unwrap_or_else(|| kind.default_radius()),
+        }
+    }
+
+    // weight of a sample d pixels away from the center of a pixel
+    pub fn evaluate(&self, d: Vec2) -> f32 {
+        self.evaluate_1d(d.x) * self.evaluate_1d(d.y)
+    }
+
+    // half open like the pixels are, a box of radius 0.5 covers the pixel and nothing else
+    fn evaluate_1d(&self, d: f32) -> f32 {
+        let r = self.radius;
+        if d < -r || d >= r {
+            return 0.0;
+        }
+        let d = d.abs();
+
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => 1.0 - d / r,
+            FilterKind::Gaussian => {
+                // three standard deviations fit in the radius, shifted down to end at 0
+                let sigma = r / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(d) - gaussian(r)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * d / r),
+            FilterKind::Lanczos => sinc(d) * sinc(d / r),
+        }
+    }
+}
+
+// the cubic over [0;2] with B = C = 1/3, times 6
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+
+    if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b)
+    } else {
+        (-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1.0e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+// samples of a pixel weighted by the filter, the film keeps one per pixel
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Splat {
+    pub sum: Color,
+    pub weight: f32,
+}
+
+impl Splat {
+    pub fn merge(&mut self, other: &Splat) {
+        self.sum = self.sum + other.sum;
+        self.weight += other.weight;
+    }
+
+    // None until a sample with some weight landed on the pixel
+    pub fn color(&self) -> Option<Color> {
+        if self.weight.abs() < 1.0e-6 {
+            return None;
+        }
+        Some(self.sum / self.weight)
+    }
+}
+
+/**
+ * Splats of a block of pixels and of the pixels around it the filter reaches, only the part
+ * within the image
+ */
+#[derive(Clone, Debug)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    // row by row
+    pub splats: Vec<Splat>,
+}
+
+impl Tile {
+    // the pixels from x, y on that samples taken in them reach, clipped to the image
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        filter: &Filter,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        image_width: u32,
+        image_height: u32,
+    ) -> Tile {
+        // a sample is at most a pixel away from the center of its pixel in every direction
+        let margin = (filter.radius + 0.5).ceil() as u32;
+        let x0 = x.saturating_sub(margin);
+        let y0 = y.saturating_sub(margin);
+        let x1 = (x + width + margin).min(image_width);
+        let y1 = (y + height + margin).min(image_height);
+
+        Tile {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+            splats: vec![Splat::default(); ((x1 - x0) * (y1 - y0)) as usize],
+        }
+    }
+
+    // a sample at the position on the film, in pixels from the top left corner of the image
+    pub fn add(&mut self, filter: &Filter, position: Vec2, color: Color) {
+        // pixels with a center within the radius
+        let first =
+            |p: f32, start: u32| ((p - 0.5 - filter.radius).ceil().max(start as f32)) as u32;
+        let last = |p: f32, end: u32| (p - 0.5 + filter.radius).floor().min(end as f32 - 1.0);
+
+        let (x0, y0) = (first(position.x, self.x), first(position.y, self.y));
+        let (x1, y1) = (
+            last(position.x, self.x + self.width),
+            last(position.y, self.y + self.height),
+        );
+        if x1 < x0 as f32 || y1 < y0 as f32 {
+            return;
+        }
+
+        for py in y0..=y1 as u32 {
+            for px in x0..=x1 as u32 {
+                let center = Vec2::new(px as f32 + 0.5, py as f32 + 0.5);
+                let weight = filter.evaluate(position - center);
+                if weight == 0.0 {
+                    continue;
+                }
+
+                let splat = &mut self.splats[((py - self.y) * self.width + px - self.x) as usize];
+                splat.sum = splat.sum + color * weight;
+                splat.weight += weight;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [FilterKind; 5] = [
+        FilterKind::Box,
+        FilterKind::Tent,
+        FilterKind::Gaussian,
+        FilterKind::Mitchell,
+        FilterKind::Lanczos,
+    ];
+
+    #[test]
+    fn test_filters() {
+        for kind in KINDS {
+            let filter = Filter::new(kind, None);
+            let r = filter.radius;
+
+            assert!(filter.evaluate(Vec2::ZERO) > 0.0, "{:?}", kind);
+            assert_eq!(filter.evaluate(Vec2::new(r, 0.0)), 0.0, "{:?}", kind);
+            assert_eq!(filter.evaluate(Vec2::new(0.0, -r - 0.1)), 0.0, "{:?}", kind);
+            // symmetric and no higher than in the middle
+            for d in [0.1, 0.4, 0.9, 1.7] {
+                let v = filter.evaluate(Vec2::new(d, 0.0));
+                assert_eq!(v, filter.evaluate(Vec2::new(-d, 0.0)));
+                assert_eq!(v, filter.evaluate(Vec2::new(0.0, d)));
+                assert!(v <= filter.evaluate(Vec2::ZERO));
+            }
+        }
+
+        // the sharpening ones go below 0 on the way out
+        let mitchell = Filter::new(FilterKind::Mitchell, None);
+        assert!(mitchell.evaluate(Vec2::new(1.5, 0.0)) < 0.0);
+        let lanczos = Filter::new(FilterKind::Lanczos, None);
+        assert!(lanczos.evaluate(Vec2::new(1.5, 0.0)) < 0.0);
+        assert!(lanczos.evaluate(Vec2::new(1.0, 0.0)).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn test_box_keeps_samples_in_their_pixel() {
+        let filter = Filter::default();
+        let mut tile = Tile::new(&filter, 2, 2, 2, 2, 8, 8);
+        assert_eq!((tile.x, tile.y, tile.width, tile.height), (1, 1, 4, 4));
+
+        for position in [
+            Vec2::new(2.0, 2.0),
+            Vec2::new(2.99, 2.5),
+            Vec2::new(2.5, 2.01),
+        ] {
+            tile.add(&filter, position, Color::new(1.0, 2.0, 3.0));
+        }
+
+        // pixel 2, 2 is the second one of the second row of the tile
+        let pixel = 5;
+        for (i, splat) in tile.splats.iter().enumerate() {
+            if i == pixel {
+                assert_eq!(splat.weight, 3.0);
+                assert_eq!(splat.color(), Some(Color::new(1.0, 2.0, 3.0)));
+            } else {
+                assert_eq!(*splat, Splat::default());
+            }
+        }
+    }
+
+    #[test]
+    fn test_samples_reach_the_neighbours() {
+        let filter = Filter::new(FilterKind::Tent, Some(1.5));
+        let mut tile = Tile::new(&filter, 0, 0, 2, 2, 3, 3);
+        assert_eq!((tile.width, tile.height), (3, 3));
+
+        tile.add(&filter, Vec2::new(1.5, 1.5), Color::new(1.0, 1.0, 1.0));
+        let weights: Vec<f32> = tile.splats.iter().map(|s| s.weight).collect();
+
+        // the pixel the sample is in gets the most, its neighbours less, the corners least
+        let (side, corner) = (1.0 - 1.0 / 1.5, (1.0 - 1.0 / 1.5) * (1.0 - 1.0 / 1.5));
+        let expected = [corner, side, corner, side, 1.0, side, corner, side, corner];
+        for (w, e) in weights.iter().zip(expected) {
+            assert!((w - e).abs() < 1.0e-6, "{:?}", weights);
+        }
+    }
+}
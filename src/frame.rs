@@ -0,0 +1,85 @@
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::sampling;
+
+use glam::Mat3;
+use glam::Vec3;
+
+/**
+ * Orthonormal frame with an origin of its own. Shapes that are simple in some frame, a cylinder
+ * standing on the origin along y, are intersected there: the ray is brought into the frame and
+ * the hit taken back out. Nothing is scaled so distances along the ray stay the same
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub origin: Vec3,
+    // columns are the axes of the frame in world space
+    pub rotation: Mat3,
+}
+
+impl Frame {
+    pub fn new(origin: Vec3, rotation: Mat3) -> Frame {
+        Frame { origin, rotation }
+    }
+
+    // y goes along the axis, which has to be of unit length, x and z are whatever's perpendicular
+    pub fn around(origin: Vec3, axis: Vec3) -> Frame {
+        let (t, b) = sampling::orthonormal_basis(axis);
+        // t, b, axis is right handed and so is b, axis, t
+        Frame::new(origin, Mat3::from_cols(b, axis, t))
+    }
+
+    pub fn local_point(&self, p: Vec3) -> Vec3 {
+        self.rotation.transpose() * (p - self.origin)
+    }
+
+    pub fn world_point(&self, p: Vec3) -> Vec3 {
+        self.origin + self.rotation * p
+    }
+
+    pub fn world_direction(&self, d: Vec3) -> Vec3 {
+        self.rotation * d
+    }
+
+    pub fn local_ray(&self, r: &Ray) -> Ray {
+        Ray {
+            origin: self.local_point(r.origin),
+            dir: self.rotation.transpose() * r.dir,
+        }
+    }
+
+    // world space box around a box given in the frame
+    pub fn bounding_box(&self, min: Vec3, max: Vec3) -> Aabb {
+        (0..8).fold(Aabb::empty(), |acc, corner| {
+            let pick = |bit: u32, lo: f32, hi: f32| if corner & bit == 0 { lo } else { hi };
+            let p = Vec3::new(
+                pick(1, min.x, max.x),
+                pick(2, min.y, max.y),
+                pick(4, min.z, max.z),
+            );
+            acc.grow(self.world_point(p))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let axis = Vec3::new(1.0, 2.0, -0.5).normalize();
+        let frame = Frame::around(Vec3::new(3.0, -1.0, 2.0), axis);
+
+        assert!(frame.world_direction(Vec3::Y).distance(axis) < 1.0e-6);
+        assert!(frame.rotation.determinant() > 0.0);
+
+        let p = Vec3::new(0.5, 7.0, -2.0);
+        assert!(frame.world_point(frame.local_point(p)).distance(p) < 1.0e-5);
+
+        let r = Ray::new(Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.0, -1.0, 2.0));
+        let local = frame.local_ray(&r);
+        assert!((local.dir.length() - 1.0).abs() < 1.0e-6);
+        assert!(frame.world_point(local.at(2.5)).distance(r.at(2.5)) < 1.0e-5);
+    }
+}
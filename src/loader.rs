@@ -1,135 +1,1812 @@
+use crate::camera;
 use crate::camera::Camera;
+use crate::csg;
+use crate::csg::Csg;
+use crate::cuboid::Cuboid;
+use crate::cylinder::Cone;
+use crate::cylinder::Cylinder;
 use crate::material;
+use crate::mesh::TriangleMesh;
+use crate::obj;
+use crate::plane::Disk;
+use crate::plane::Plane;
+use crate::plane::Quad;
 use crate::scene::Hitable;
+use crate::schema;
+use crate::schema::EntryType;
+use crate::schema::MaterialType;
+use crate::schema::SdfType;
+use crate::sdf::Sdf;
+use crate::sdf::SdfShape;
 use crate::sphere::Sphere;
+use crate::tonemap;
+use crate::tonemap::ToneMapping;
+use crate::torus::Torus;
+use crate::transform::Transformed;
+use crate::triangle::Triangle;
 
+use glam::Mat3;
+use glam::Mat4;
+use glam::Vec2;
 use glam::Vec3;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-#[derive(PartialEq, Clone)]
+// fields read from an entry before the struct for its type, see schema.rs
+const HEADER: &[&str] = &["type"];
+// shapes, instances and groups can be placed too
+const PLACED_HEADER: &[&str] = &["type", "transform"];
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Kind {
     Object,
-    Light,
+    // how much light it emits relative to its color, only the path tracer cares
+    Light { intensity: f32 },
 }
 
-pub fn load(path: &str) -> (Vec<(Arc<dyn Hitable>, material::Material, Kind)>, Camera) {
-    let contents = fs::read_to_string(path).expect("file not found");
-    let j: Value = serde_json::from_str(&contents).unwrap();
-    let mut results = Vec::new();
+pub type SceneObject = (Arc<dyn Hitable>, material::Material, Kind);
+
+pub struct LoadedScene {
+    pub objects: Vec<SceneObject>,
+    pub camera: Camera,
+    // defaults unless the scene has an "output" entry
+    pub tone_mapping: ToneMapping,
+    // every file the scene was read from, the scene file itself first followed by the OBJ and
+    // MTL files of its meshes
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    Index(usize),
+    Key(String),
+}
+
+/**
+ * Anything wrong with the scene file. Points at the offending value both as a JSON path
+ * like `[3].material.fuzz` and as a line and column in the file, when the value doesn't exist
+ * (a missing field) the position is the one of the closest parent that does
+ */
+#[derive(Debug)]
+pub struct SceneError {
+    pub file: String,
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if self.line > 0 {
+            write!(f, ":{}:{}", self.line, self.column)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, ": {}", self.path)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+pub fn load(path: &str) -> Result<LoadedScene, SceneError> {
+    let contents = fs::read_to_string(path).map_err(|e| SceneError {
+        file: path.to_string(),
+        path: String::new(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+
+    // files referenced by the scene are relative to it
+    let base_dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+
+    let mut loaded = parse(&contents, path, base_dir)?;
+    loaded.files.insert(0, PathBuf::from(path));
+
+    Ok(loaded)
+}
+
+pub fn parse(contents: &str, file: &str, base_dir: &Path) -> Result<LoadedScene, SceneError> {
+    let src = Source {
+        file,
+        contents,
+        files: RefCell::new(Vec::new()),
+    };
+
+    let entries: Vec<Value> = serde_json::from_str(contents).map_err(|e| SceneError {
+        file: file.to_string(),
+        path: String::new(),
+        line: e.line(),
+        column: e.column(),
+        message: strip_position(&e.to_string()),
+    })?;
 
+    let mut results = Vec::new();
     let mut camera: Option<Camera> = None;
+    let mut tone_mapping = ToneMapping::default();
+    // by name, what instances are made of
+    let mut prototypes: HashMap<String, Vec<SceneObject>> = HashMap::new();
+
+    for (i, entry) in entries.iter().enumerate() {
+        let path = vec![PathSegment::Index(i)];
+        let header: schema::Entry = src.deserialize(entry, &path)?;
 
-    for item in j.as_array().unwrap() {
-        match item["type"].as_str().unwrap() {
-            "camera" => {
-                camera = Some(build_camera(item));
+        match header.kind {
+            EntryType::Camera => {
+                let def: schema::CameraDef = src.deserialize_rest(entry, &path, HEADER)?;
+                camera = Some(build_camera(&src, &def, &path)?);
             }
-            _ => {
-                let obj = build_object_from_string(item);
-                results.push(obj);
+            EntryType::Output => {
+                let def: schema::OutputDef = src.deserialize_rest(entry, &path, HEADER)?;
+                tone_mapping = build_tone_mapping(&src, &def, &path)?;
             }
+            EntryType::Prototype => {
+                let def: schema::PrototypeDef = src.deserialize_rest(entry, &path, HEADER)?;
+                if prototypes.contains_key(&def.name) {
+                    let message = format!("there's already a prototype called '{}'", def.name);
+                    return Err(src.error(&child(&path, "name"), &message));
+                }
+
+                let mut objects = Vec::new();
+                for (j, object) in def.objects.iter().enumerate() {
+                    let object_path =
+                        [child(&path, "objects"), vec![PathSegment::Index(j)]].concat();
+                    objects.extend(build_objects(
+                        &src,
+                        object,
+                        &object_path,
+                        base_dir,
+                        &prototypes,
+                        &Inherited::default(),
+                    )?);
+                }
+                if objects.is_empty() {
+                    return Err(src.error(&child(&path, "objects"), "must not be empty"));
+                }
+
+                prototypes.insert(def.name, objects);
+            }
+            _ => results.extend(build_objects(
+                &src,
+                entry,
+                &path,
+                base_dir,
+                &prototypes,
+                &Inherited::default(),
+            )?),
         }
     }
 
-    (results, camera.unwrap())
+    match camera {
+        Some(camera) => Ok(LoadedScene {
+            objects: results,
+            camera,
+            tone_mapping,
+            files: src.files.into_inner(),
+        }),
+        None => Err(src.error(&[], "the scene has no camera")),
+    }
 }
 
-fn build_camera(s: &Value) -> Camera {
-    let pos = Vec3::new(
-        s["pos"]["x"].as_f64().unwrap() as f32,
-        s["pos"]["y"].as_f64().unwrap() as f32,
-        s["pos"]["z"].as_f64().unwrap() as f32,
-    );
-    let lookat = Vec3::new(
-        s["lookat"]["x"].as_f64().unwrap() as f32,
-        s["lookat"]["y"].as_f64().unwrap() as f32,
-        s["lookat"]["z"].as_f64().unwrap() as f32,
-    );
-    let fov = s["fov"].as_f64().unwrap() as f32;
+// what the groups an entry is in pass down to it
+struct Inherited {
+    // of all the groups together
+    transform: Mat4,
+    // of the innermost group that has one, as it is in the file
+    material: Option<Value>,
+}
 
-    Camera::new(pos, lookat, fov)
+impl Default for Inherited {
+    fn default() -> Inherited {
+        Inherited {
+            transform: Mat4::IDENTITY,
+            material: None,
+        }
+    }
 }
 
-fn panic_on_range(x: f32) {
-    if x < 0.0 || x > 1.0 {
-        panic!("Range must be inside [0;1]")
+/**
+ * The objects a shape entry, an instance or a group puts in the scene. A mesh from an OBJ file
+ * can be several of them. With a "transform" they're all placed by it, within the groups the
+ * entry is in. Groups are flattened: what's in them ends up in the scene as if it were given on
+ * its own with the transforms of the groups put together
+ */
+fn build_objects(
+    src: &Source,
+    entry: &Value,
+    path: &[PathSegment],
+    base_dir: &Path,
+    prototypes: &HashMap<String, Vec<SceneObject>>,
+    inherited: &Inherited,
+) -> Result<Vec<SceneObject>, SceneError> {
+    let header: schema::Entry = src.deserialize(entry, path)?;
+    let placement: schema::Placement = src.deserialize(entry, path)?;
+    let own = match &placement.transform {
+        Some(def) => Some(build_transform(src, def, &child(path, "transform"))?),
+        None => None,
+    };
+
+    // the group's material goes in as if the entry had it, so it's read like any other.
+    // Instances keep the materials of their prototype
+    let with_material;
+    let entry = match (&inherited.material, entry) {
+        (Some(material), Value::Object(fields))
+            if !fields.contains_key("material") && header.kind != EntryType::Instance =>
+        {
+            let mut fields = fields.clone();
+            fields.insert("material".to_string(), material.clone());
+            with_material = Value::Object(fields);
+            &with_material
+        }
+        _ => entry,
+    };
+
+    let mut objects: Vec<SceneObject> = Vec::new();
+
+    match header.kind {
+        EntryType::Camera | EntryType::Output | EntryType::Prototype => {
+            return Err(src.error(
+                &child(path, "type"),
+                "only shapes, instances and groups can go here",
+            ))
+        }
+        EntryType::Group => {
+            let def: schema::GroupDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let inner = Inherited {
+                transform: inherited.transform * own.unwrap_or(Mat4::IDENTITY),
+                material: match &def.material {
+                    Some(material) => {
+                        build_material(src, material, &child(path, "material"))?;
+                        entry.get("material").cloned()
+                    }
+                    None => inherited.material.clone(),
+                },
+            };
+
+            for (i, object) in def.objects.iter().enumerate() {
+                let object_path = [child(path, "objects"), vec![PathSegment::Index(i)]].concat();
+                objects.extend(build_objects(
+                    src,
+                    object,
+                    &object_path,
+                    base_dir,
+                    prototypes,
+                    &inner,
+                )?);
+            }
+
+            // already placed
+            return Ok(objects);
+        }
+        EntryType::Instance => {
+            let def: schema::InstanceDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            match prototypes.get(&def.prototype) {
+                Some(prototype) => objects.extend(prototype.iter().cloned()),
+                None => {
+                    let message = format!("no prototype called '{}' before this", def.prototype);
+                    return Err(src.error(&child(path, "prototype"), &message));
+                }
+            }
+        }
+        EntryType::Sphere | EntryType::PointLight => {
+            let def: schema::SphereDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+            let kind = match header.kind {
+                EntryType::PointLight => Kind::Light {
+                    intensity: def.intensity,
+                },
+                _ => Kind::Object,
+            };
+
+            objects.push((Arc::new(build_sphere(&def)) as Arc<dyn Hitable>, mat, kind));
+        }
+        EntryType::Triangle => {
+            let def: schema::TriangleDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+
+            objects.push((Arc::new(build_triangle(&def)), mat, Kind::Object));
+        }
+        EntryType::Mesh => {
+            let def: schema::MeshDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            match &def.path {
+                Some(obj_path) => {
+                    objects.extend(build_obj_mesh(src, &def, obj_path, base_dir, path)?)
+                }
+                None => objects.push(build_mesh(src, &def, path)?),
+            }
+        }
+        EntryType::Plane => {
+            let def: schema::PlaneDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+
+            objects.push((Arc::new(build_plane(src, &def, path)?), mat, Kind::Object));
+        }
+        EntryType::Disk => {
+            let def: schema::DiskDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+
+            objects.push((Arc::new(build_disk(src, &def, path)?), mat, Kind::Object));
+        }
+        EntryType::Quad => {
+            let def: schema::QuadDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+
+            objects.push((Arc::new(build_quad(src, &def, path)?), mat, Kind::Object));
+        }
+        EntryType::Box => {
+            let def: schema::BoxDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+
+            objects.push((Arc::new(build_box(src, &def, path)?), mat, Kind::Object));
+        }
+        EntryType::Cylinder | EntryType::Cone => {
+            let def: schema::CylinderDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+            check_cylinder(src, &def, path)?;
+
+            let (base, top) = (def.base.into(), def.top.into());
+            let geometry: Arc<dyn Hitable> = match header.kind {
+                EntryType::Cone => Arc::new(Cone::new(base, top, def.radius, def.capped)),
+                _ => Arc::new(Cylinder::new(base, top, def.radius, def.capped)),
+            };
+            objects.push((geometry, mat, Kind::Object));
+        }
+        EntryType::Torus => {
+            let def: schema::TorusDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+
+            objects.push((Arc::new(build_torus(src, &def, path)?), mat, Kind::Object));
+        }
+        EntryType::Csg => {
+            let def: schema::CsgDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+
+            // the operands are read as if they had the material of the csg so they don't need one
+            let operands = Inherited {
+                transform: Mat4::IDENTITY,
+                material: entry.get("material").cloned(),
+            };
+            let solid = |key: &str, operand: &Value| {
+                let operand_path = child(path, key);
+                let mut built =
+                    build_objects(src, operand, &operand_path, base_dir, prototypes, &operands)?;
+                match built.len() {
+                    1 => Ok(built.remove(0).0),
+                    _ => Err(src.error(&operand_path, "must be a single solid")),
+                }
+            };
+            let (a, b) = (solid("a", &def.a)?, solid("b", &def.b)?);
+
+            let op = match def.op {
+                schema::CsgOperation::Union => csg::Operation::Union,
+                schema::CsgOperation::Intersection => csg::Operation::Intersection,
+                schema::CsgOperation::Difference => csg::Operation::Difference,
+            };
+            objects.push((Arc::new(Csg::new(op, a, b)), mat, Kind::Object));
+        }
+        EntryType::Sdf => {
+            let def: schema::SdfDef = src.deserialize_rest(entry, path, PLACED_HEADER)?;
+            let mat = build_material(src, &def.material, &child(path, "material"))?;
+            let sdf = build_sdf(src, &def.shape, &child(path, "shape"))?;
+
+            objects.push((Arc::new(SdfShape::new(sdf)), mat, Kind::Object));
+        }
     }
+
+    // instances are always wrapped, two objects sharing the same shape would be taken for one
+    // another when telling which light a shadow ray found
+    let matrix = match own {
+        Some(own) => Some(inherited.transform * own),
+        None if header.kind == EntryType::Instance => Some(inherited.transform),
+        None if inherited.transform != Mat4::IDENTITY => Some(inherited.transform),
+        None => None,
+    };
+
+    Ok(match matrix {
+        Some(matrix) => objects
+            .into_iter()
+            .map(|(geometry, mat, kind)| {
+                let placed: Arc<dyn Hitable> = Arc::new(Transformed::new(geometry, matrix));
+                (placed, mat, kind)
+            })
+            .collect(),
+        None => objects,
+    })
+}
+
+fn child(path: &[PathSegment], key: &str) -> Vec<PathSegment> {
+    let mut path = path.to_vec();
+    path.push(PathSegment::Key(key.to_string()));
+    path
 }
 
-fn build_object_from_string(s: &Value) -> (Arc<dyn Hitable>, material::Material, Kind) {
-    let mat: material::Material;
+fn format_path(path: &[PathSegment]) -> String {
+    let mut s = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Index(i) => s += &format!("[{}]", i),
+            PathSegment::Key(k) => {
+                if !s.is_empty() {
+                    s.push('.');
+                }
+                s += k;
+            }
+        }
+    }
+    s
+}
 
-    match &s["material"] {
-        m => match m {
-            material => {
-                let mut color = material::Color::default();
-                let col = &material["color"];
-                color.r = col["r"].as_f64().unwrap() as f32;
-                color.g = col["g"].as_f64().unwrap() as f32;
-                color.b = col["b"].as_f64().unwrap() as f32;
-                panic_on_range(color.r);
-                panic_on_range(color.g);
-                panic_on_range(color.b);
+// serde_json appends " at line X column Y" to its messages, we report that separately
+fn strip_position(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(pos) => message[..pos].to_string(),
+        None => message.to_string(),
+    }
+}
 
-                match material["type"].as_str().unwrap() {
-                    "lambertian" => {
-                        mat = material::Material::Lambertian(material::Lambertian {
-                            albedo: material["albedo"].as_f64().unwrap() as f32,
-                            color,
-                        });
+struct Source<'a> {
+    file: &'a str,
+    contents: &'a str,
+    // other files read while building the scene
+    files: RefCell<Vec<PathBuf>>,
+}
+
+impl<'a> Source<'a> {
+    fn error(&self, path: &[PathSegment], message: &str) -> SceneError {
+        let (line, column) = match locate(self.contents, path) {
+            Some(offset) => line_and_column(self.contents, offset),
+            None => (0, 0),
+        };
+
+        SceneError {
+            file: self.file.to_string(),
+            path: format_path(path),
+            line,
+            column,
+            message: message.to_string(),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(
+        &self,
+        value: &Value,
+        path: &[PathSegment],
+    ) -> Result<T, SceneError> {
+        serde_path_to_error::deserialize(value).map_err(|e| {
+            let mut full_path = path.to_vec();
+            for segment in e.path().iter() {
+                match segment {
+                    serde_path_to_error::Segment::Seq { index } => {
+                        full_path.push(PathSegment::Index(*index))
                     }
-                    "metal" => {
-                        mat = material::Material::Metal(material::Metal {
-                            fuzz: material["fuzz"].as_f64().unwrap() as f32,
-                            albedo: material["albedo"].as_f64().unwrap() as f32,
-                            color,
-                        });
+                    serde_path_to_error::Segment::Map { key } => {
+                        full_path.push(PathSegment::Key(key.clone()))
+                    }
+                    _ => {}
+                }
+            }
+
+            self.error(&full_path, &strip_position(&e.inner().to_string()))
+        })
+    }
+
+    // the rest of an object after the fields already read by another struct, like its type
+    fn deserialize_rest<T: DeserializeOwned>(
+        &self,
+        value: &Value,
+        path: &[PathSegment],
+        read: &[&str],
+    ) -> Result<T, SceneError> {
+        match value {
+            Value::Object(fields) => {
+                let mut rest = fields.clone();
+                for key in read {
+                    rest.remove(*key);
+                }
+                self.deserialize(&Value::Object(rest), path)
+            }
+            _ => self.deserialize(value, path),
+        }
+    }
+}
+
+fn line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = match before.rfind('\n') {
+        Some(nl) => before[nl + 1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+
+    (line, column)
+}
+
+/**
+ * Byte offset of the value at the given path in the JSON text. serde_json::Value doesn't keep
+ * track of where things came from, so we walk the text again, the file has already been parsed
+ * successfully at this point so we don't bother validating anything.
+ * If the path doesn't fully exist we stop at the deepest value that does
+ */
+fn locate(contents: &str, path: &[PathSegment]) -> Option<usize> {
+    let mut scanner = Scanner {
+        bytes: contents.as_bytes(),
+        pos: 0,
+    };
+
+    scanner.skip_ws();
+    let mut found = scanner.pos;
+
+    for segment in path {
+        let ok = match segment {
+            PathSegment::Index(i) => scanner.enter_index(*i),
+            PathSegment::Key(k) => scanner.enter_key(k),
+        };
+
+        if ok.is_none() {
+            break;
+        }
+        found = scanner.pos;
+    }
+
+    if found < contents.len() {
+        Some(found)
+    } else {
+        None
+    }
+}
+
+struct Scanner<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        self.skip_ws();
+        if self.peek()? == c {
+            self.pos += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    // returns the raw contents between the quotes
+    fn skip_string(&mut self) -> Option<&'a [u8]> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        loop {
+            match self.peek()? {
+                b'\\' => self.pos += 2,
+                b'"' => {
+                    self.pos += 1;
+                    return Some(&self.bytes[start..self.pos - 1]);
+                }
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    fn skip_value(&mut self) -> Option<()> {
+        self.skip_ws();
+        match self.peek()? {
+            b'"' => {
+                self.skip_string()?;
+            }
+            open @ (b'{' | b'[') => {
+                let close = if open == b'{' { b'}' } else { b']' };
+                self.pos += 1;
+                loop {
+                    self.skip_ws();
+                    match self.peek()? {
+                        c if c == close => {
+                            self.pos += 1;
+                            break;
+                        }
+                        b',' | b':' => self.pos += 1,
+                        _ => self.skip_value()?,
                     }
-                    "dielectric" => {
-                        mat = material::Material::Dielectric(material::Dielectric {
-                            refraction: material["refraction"].as_f64().unwrap() as f32,
-                            color,
-                        });
+                }
+            }
+            _ => {
+                while let Some(c) = self.peek() {
+                    if matches!(c, b',' | b']' | b'}' | b' ' | b'\t' | b'\n' | b'\r') {
+                        break;
                     }
-                    _ => panic!("material not supported"),
+                    self.pos += 1;
                 }
             }
+        }
+        Some(())
+    }
+
+    fn enter_index(&mut self, index: usize) -> Option<()> {
+        self.expect(b'[')?;
+        for _ in 0..index {
+            self.skip_value()?;
+            self.expect(b',')?;
+        }
+
+        self.skip_ws();
+        if self.peek()? == b']' {
+            return None;
+        }
+        Some(())
+    }
+
+    fn enter_key(&mut self, key: &str) -> Option<()> {
+        self.expect(b'{')?;
+        loop {
+            self.skip_ws();
+            if self.peek()? == b'}' {
+                return None;
+            }
+
+            let k = self.skip_string()?;
+            self.expect(b':')?;
+            self.skip_ws();
+            if k == key.as_bytes() {
+                return Some(());
+            }
+
+            self.skip_value()?;
+            self.skip_ws();
+            if self.peek()? == b',' {
+                self.pos += 1;
+            }
+        }
+    }
+}
+
+fn build_camera(
+    src: &Source,
+    def: &schema::CameraDef,
+    path: &[PathSegment],
+) -> Result<Camera, SceneError> {
+    let positive = |key: &str, value: Option<f32>| match value {
+        Some(v) if v <= 0.0 => Err(src.error(&child(path, key), "must be positive")),
+        _ => Ok(()),
+    };
+    positive("f_stop", def.f_stop)?;
+    positive("aperture_radius", def.aperture_radius)?;
+    positive("focus_distance", def.focus_distance)?;
+    positive("ortho_size", def.ortho_size)?;
+
+    let fov = match def.projection {
+        // not used but the camera has to have one
+        schema::Projection::Orthographic | schema::Projection::Equirectangular => {
+            def.fov.unwrap_or(90.0)
+        }
+        schema::Projection::Perspective | schema::Projection::Fisheye => {
+            let fov = def
+                .fov
+                .ok_or_else(|| src.error(path, "missing field `fov`"))?;
+
+            // a plane in front of the camera can't reach all the way to the sides, a fisheye can
+            // go all the way around
+            let in_range = match def.projection {
+                schema::Projection::Fisheye => fov > 0.0 && fov <= 360.0,
+                _ => fov > 0.0 && fov < 180.0,
+            };
+            if !in_range {
+                return Err(src.error(
+                    &child(path, "fov"),
+                    "must be more than 0 and less than 180 degrees, up to 360 for fisheye",
+                ));
+            }
+
+            fov
+        }
+    };
+
+    let projection = match def.projection {
+        schema::Projection::Perspective => camera::Projection::Perspective,
+        schema::Projection::Fisheye => camera::Projection::Fisheye,
+        schema::Projection::Equirectangular => camera::Projection::Equirectangular,
+        schema::Projection::Orthographic => match def.ortho_size {
+            Some(size) => camera::Projection::Orthographic { size },
+            None => return Err(src.error(path, "missing field `ortho_size`")),
         },
+    };
+
+    let aperture = match (def.f_stop, def.aperture_radius) {
+        (None, None) => camera::Aperture::Pinhole,
+        (Some(f_stop), None) => camera::Aperture::FStop(f_stop),
+        (None, Some(radius)) => camera::Aperture::Radius(radius),
+        (Some(_), Some(_)) => {
+            return Err(src.error(
+                &child(path, "aperture_radius"),
+                "give either f_stop or aperture_radius, not both",
+            ))
+        }
+    };
+
+    let fov_axis = match def.fov_axis {
+        schema::FovAxis::Horizontal => camera::FovAxis::Horizontal,
+        schema::FovAxis::Vertical => camera::FovAxis::Vertical,
+    };
+
+    let mut camera = Camera::new(def.pos.into(), def.lookat.into(), fov)
+        .with_fov_axis(fov_axis)
+        .with_projection(projection);
+    if let Some(vup) = def.vup {
+        camera = camera.with_vup(vup.into());
     }
+    if let Some(focus_distance) = def.focus_distance {
+        camera = camera.with_focus_distance(focus_distance);
+    }
+
+    Ok(camera
+        .with_aperture(aperture)
+        .with_blades(def.blades, def.blade_rotation))
+}
 
-    let obj: Arc<dyn Hitable>;
-    let kind: Kind;
+fn build_tone_mapping(
+    src: &Source,
+    def: &schema::OutputDef,
+    path: &[PathSegment],
+) -> Result<ToneMapping, SceneError> {
+    let operator = match def.tonemap {
+        schema::ToneMapOperator::Linear => tonemap::Operator::Linear,
+        schema::ToneMapOperator::Reinhard => tonemap::Operator::Reinhard,
+        schema::ToneMapOperator::ReinhardExtended => tonemap::Operator::ReinhardExtended,
+        schema::ToneMapOperator::Aces => tonemap::Operator::Aces,
+        schema::ToneMapOperator::Hable => tonemap::Operator::Hable,
+        schema::ToneMapOperator::Agx => tonemap::Operator::Agx,
+    };
 
-    match s["type"].as_str().unwrap() {
-        "sphere" => {
-            obj = Arc::new(build_sphere(s));
-            kind = Kind::Object;
+    let mut tone_mapping = ToneMapping {
+        operator,
+        exposure: def.exposure,
+        ..ToneMapping::default()
+    };
+    if let Some(white_point) = def.white_point {
+        if white_point <= 0.0 {
+            return Err(src.error(&child(path, "white_point"), "must be positive"));
         }
-        "point_light" => {
-            obj = Arc::new(build_sphere(s));
-            kind = Kind::Light;
+        tone_mapping.white_point = white_point;
+    }
+
+    Ok(tone_mapping)
+}
+
+fn build_material(
+    src: &Source,
+    def: &schema::MaterialDef,
+    path: &[PathSegment],
+) -> Result<material::Material, SceneError> {
+    let color = def.color.0;
+
+    Ok(match def.kind {
+        MaterialType::Lambertian => material::Material::Lambertian(material::Lambertian {
+            albedo: def.albedo,
+            color,
+        }),
+        MaterialType::Metal => material::Material::Metal(material::Metal {
+            fuzz: def.fuzz,
+            albedo: def.albedo,
+            color,
+        }),
+        MaterialType::Dielectric => {
+            let refraction = def.refraction.ok_or_else(|| {
+                src.error(
+                    &child(path, "refraction"),
+                    "dielectric material needs a refraction index",
+                )
+            })?;
+
+            material::Material::Dielectric(material::Dielectric { refraction, color })
+        }
+    })
+}
+
+fn build_sphere(def: &schema::SphereDef) -> Sphere {
+    Sphere::new(def.pos.into(), def.radius)
+}
+
+fn build_triangle(def: &schema::TriangleDef) -> Triangle {
+    let mut triangle = Triangle::new(def.v0.into(), def.v1.into(), def.v2.into());
+
+    if let Some(normals) = def.normals {
+        triangle = triangle.with_normals(normals.map(|n| Vec3::from(n).normalize()));
+    }
+
+    if let Some(uvs) = def.uvs {
+        triangle = triangle.with_uvs(uvs.map(Vec2::from));
+    }
+
+    triangle
+}
+
+fn positive(src: &Source, path: &[PathSegment], key: &str, value: f32) -> Result<(), SceneError> {
+    if value > 0.0 {
+        Ok(())
+    } else {
+        Err(src.error(&child(path, key), "must be positive"))
+    }
+}
+
+// normals and axes, they're normalized so only the direction matters
+fn direction(
+    src: &Source,
+    path: &[PathSegment],
+    key: &str,
+    value: schema::Point,
+) -> Result<Vec3, SceneError> {
+    let v = Vec3::from(value);
+    if v.length_squared() > 0.0 {
+        Ok(v.normalize())
+    } else {
+        Err(src.error(&child(path, key), "must not be zero"))
+    }
+}
+
+fn build_plane(
+    src: &Source,
+    def: &schema::PlaneDef,
+    path: &[PathSegment],
+) -> Result<Plane, SceneError> {
+    let normal = direction(src, path, "normal", def.normal)?;
+    Ok(Plane::new(def.point.into(), normal))
+}
+
+fn build_disk(
+    src: &Source,
+    def: &schema::DiskDef,
+    path: &[PathSegment],
+) -> Result<Disk, SceneError> {
+    let normal = direction(src, path, "normal", def.normal)?;
+    positive(src, path, "radius", def.radius)?;
+    Ok(Disk::new(def.center.into(), normal, def.radius))
+}
+
+fn build_quad(
+    src: &Source,
+    def: &schema::QuadDef,
+    path: &[PathSegment],
+) -> Result<Quad, SceneError> {
+    let (u, v) = (Vec3::from(def.u), Vec3::from(def.v));
+    if u.cross(v).length_squared() == 0.0 {
+        return Err(src.error(&child(path, "v"), "the edges must not be zero or parallel"));
+    }
+    Ok(Quad::new(def.corner.into(), u, v))
+}
+
+fn build_box(
+    src: &Source,
+    def: &schema::BoxDef,
+    path: &[PathSegment],
+) -> Result<Cuboid, SceneError> {
+    let (min, max) = (Vec3::from(def.min), Vec3::from(def.max));
+    if !min.cmplt(max).all() {
+        return Err(src.error(&child(path, "max"), "must be above min on every axis"));
+    }
+
+    let cuboid = Cuboid::new(min, max);
+    Ok(match def.rotation {
+        Some(degrees) => cuboid.with_rotation(rotation(degrees)),
+        None => cuboid,
+    })
+}
+
+// degrees around x, then y, then z
+fn rotation(degrees: schema::Point) -> Mat3 {
+    Mat3::from_rotation_z(degrees.z.to_radians())
+        * Mat3::from_rotation_y(degrees.y.to_radians())
+        * Mat3::from_rotation_x(degrees.x.to_radians())
+}
+
+fn build_transform(
+    src: &Source,
+    def: &schema::TransformDef,
+    path: &[PathSegment],
+) -> Result<Mat4, SceneError> {
+    let (matrix, key) = match def.matrix {
+        Some(rows) => {
+            if def.translate.is_some() || def.rotate.is_some() || def.scale.is_some() {
+                return Err(src.error(
+                    &child(path, "matrix"),
+                    "give either a matrix or translate, rotate and scale, not both",
+                ));
+            }
+            if rows[3] != [0.0, 0.0, 0.0, 1.0] {
+                return Err(src.error(&child(path, "matrix"), "the last row must be 0, 0, 0, 1"));
+            }
+
+            // glam takes the columns
+            (Mat4::from_cols_array_2d(&rows).transpose(), "matrix")
+        }
+        None => {
+            let scale = match def.scale {
+                Some(schema::ScaleDef::Uniform(s)) => Vec3::splat(s),
+                Some(schema::ScaleDef::PerAxis(s)) => s.into(),
+                None => Vec3::ONE,
+            };
+            let translate = def.translate.map_or(Vec3::ZERO, Vec3::from);
+            let rotate = def.rotate.map_or(Mat3::IDENTITY, rotation);
+
+            let matrix = Mat4::from_translation(translate)
+                * Mat4::from_mat3(rotate)
+                * Mat4::from_scale(scale);
+            (matrix, "scale")
+        }
+    };
+
+    if Mat3::from_mat4(matrix).determinant() == 0.0 {
+        return Err(src.error(&child(path, key), "must not flatten the shape"));
+    }
+
+    Ok(matrix)
+}
+
+fn check_cylinder(
+    src: &Source,
+    def: &schema::CylinderDef,
+    path: &[PathSegment],
+) -> Result<(), SceneError> {
+    positive(src, path, "radius", def.radius)?;
+    if Vec3::from(def.base) == Vec3::from(def.top) {
+        return Err(src.error(&child(path, "top"), "must not be the same as base"));
+    }
+    Ok(())
+}
+
+fn build_torus(
+    src: &Source,
+    def: &schema::TorusDef,
+    path: &[PathSegment],
+) -> Result<Torus, SceneError> {
+    let axis = match def.axis {
+        Some(axis) => direction(src, path, "axis", axis)?,
+        None => Vec3::Y,
+    };
+    positive(src, path, "major_radius", def.major_radius)?;
+    positive(src, path, "minor_radius", def.minor_radius)?;
+
+    Ok(Torus::new(
+        def.center.into(),
+        axis,
+        def.major_radius,
+        def.minor_radius,
+    ))
+}
+
+fn build_sdf(src: &Source, value: &Value, path: &[PathSegment]) -> Result<Sdf, SceneError> {
+    let node: schema::SdfNode = src.deserialize(value, path)?;
+    let center = |center: Option<schema::Point>| center.map_or(Vec3::ZERO, Vec3::from);
+
+    Ok(match node.kind {
+        SdfType::Sphere => {
+            let def: schema::SdfSphereDef = src.deserialize_rest(value, path, HEADER)?;
+            positive(src, path, "radius", def.radius)?;
+            Sdf::Sphere {
+                center: center(def.center),
+                radius: def.radius,
+            }
+        }
+        SdfType::Box | SdfType::RoundedBox => {
+            let def: schema::SdfBoxDef = src.deserialize_rest(value, path, HEADER)?;
+            let half = Vec3::from(def.size) * 0.5;
+            if !half.cmpgt(Vec3::ZERO).all() {
+                return Err(src.error(&child(path, "size"), "must be positive on every axis"));
+            }
+
+            match (node.kind, def.radius) {
+                (SdfType::Box, _) => Sdf::Box {
+                    center: center(def.center),
+                    half,
+                },
+                (_, Some(radius)) => {
+                    if radius <= 0.0 || radius > half.min_element() {
+                        return Err(src.error(
+                            &child(path, "radius"),
+                            "must be positive and at most half the smallest side",
+                        ));
+                    }
+                    Sdf::RoundedBox {
+                        center: center(def.center),
+                        half,
+                        radius,
+                    }
+                }
+                (_, None) => return Err(src.error(path, "missing field `radius`")),
+            }
+        }
+        SdfType::Torus => {
+            let def: schema::SdfTorusDef = src.deserialize_rest(value, path, HEADER)?;
+            positive(src, path, "major_radius", def.major_radius)?;
+            positive(src, path, "minor_radius", def.minor_radius)?;
+            Sdf::Torus {
+                center: center(def.center),
+                major: def.major_radius,
+                minor: def.minor_radius,
+            }
+        }
+        SdfType::Capsule => {
+            let def: schema::SdfCapsuleDef = src.deserialize_rest(value, path, HEADER)?;
+            positive(src, path, "radius", def.radius)?;
+            if Vec3::from(def.base) == Vec3::from(def.top) {
+                return Err(src.error(&child(path, "top"), "must not be the same as base"));
+            }
+            Sdf::Capsule {
+                base: def.base.into(),
+                top: def.top.into(),
+                radius: def.radius,
+            }
+        }
+        SdfType::SmoothUnion | SdfType::Subtraction => {
+            let def: schema::SdfPairDef = src.deserialize_rest(value, path, HEADER)?;
+            if def.smoothness < 0.0 {
+                return Err(src.error(&child(path, "smoothness"), "must not be negative"));
+            }
+
+            let a = Box::new(build_sdf(src, &def.a, &child(path, "a"))?);
+            let b = Box::new(build_sdf(src, &def.b, &child(path, "b"))?);
+            match node.kind {
+                SdfType::SmoothUnion => Sdf::SmoothUnion {
+                    a,
+                    b,
+                    smoothness: def.smoothness,
+                },
+                _ => Sdf::Subtraction {
+                    a,
+                    b,
+                    smoothness: def.smoothness,
+                },
+            }
+        }
+        SdfType::Repeat => {
+            let def: schema::SdfRepeatDef = src.deserialize_rest(value, path, HEADER)?;
+            let count = Vec3::from(def.count.map(|c| c as f32));
+            let spacing = Vec3::from(def.spacing);
+            let spaced = (spacing.cmpgt(Vec3::ZERO) | count.cmpeq(Vec3::ZERO)).all();
+            if !spaced {
+                return Err(src.error(
+                    &child(path, "spacing"),
+                    "must be positive along the axes with copies",
+                ));
+            }
+
+            Sdf::Repeat {
+                shape: Box::new(build_sdf(src, &def.shape, &child(path, "shape"))?),
+                spacing,
+                count,
+            }
+        }
+        SdfType::Twist => {
+            let def: schema::SdfTwistDef = src.deserialize_rest(value, path, HEADER)?;
+            Sdf::Twist {
+                shape: Box::new(build_sdf(src, &def.shape, &child(path, "shape"))?),
+                rate: def.degrees_per_unit.to_radians(),
+            }
+        }
+    })
+}
+
+// mesh given inline in the scene file
+fn build_mesh(
+    src: &Source,
+    def: &schema::MeshDef,
+    path: &[PathSegment],
+) -> Result<SceneObject, SceneError> {
+    let material = match &def.material {
+        Some(m) => build_material(src, m, &child(path, "material"))?,
+        None => return Err(src.error(&child(path, "material"), "mesh needs a material")),
+    };
+
+    if def.positions.is_empty() || def.indices.is_empty() {
+        return Err(src.error(
+            path,
+            "mesh needs either a path to an OBJ file or positions and indices",
+        ));
+    }
+
+    let vertex_count = def.positions.len();
+    if let Some(i) = def
+        .indices
+        .iter()
+        .position(|i| i.iter().any(|&i| i >= vertex_count))
+    {
+        let message = format!("index out of range, there are {} positions", vertex_count);
+        return Err(src.error(
+            &[child(path, "indices"), vec![PathSegment::Index(i)]].concat(),
+            &message,
+        ));
+    }
+
+    for (key, len) in [("normals", def.normals.len()), ("uvs", def.uvs.len())] {
+        if len != 0 && len != vertex_count {
+            let message = format!("expected one per position ({}), got {}", vertex_count, len);
+            return Err(src.error(&child(path, key), &message));
         }
-        _ => panic!("unrecognized type"),
     }
 
-    (obj, mat, kind)
+    let positions = def.positions.iter().map(|p| Vec3::from(*p)).collect();
+    let normals = def
+        .normals
+        .iter()
+        .map(|n| Vec3::from(*n).normalize())
+        .collect();
+    let uvs = def.uvs.iter().map(|uv| Vec2::from(*uv)).collect();
+    let mesh = TriangleMesh::new(positions, normals, uvs, def.indices.clone());
+
+    Ok((Arc::new(mesh), material, Kind::Object))
 }
 
-fn build_sphere(s: &Value) -> Sphere {
-    let pos = &s["pos"];
-    let x = &pos["x"];
-    let y = &pos["y"];
-    let z = &pos["z"];
+/**
+ * Mesh from a Wavefront OBJ file, every group/material combination in the file becomes its own
+ * object. Materials from the MTL libraries are used unless "ignore_mtl" is set, faces without
+ * one get the "material" of the entry
+ */
+fn build_obj_mesh(
+    src: &Source,
+    def: &schema::MeshDef,
+    obj_path: &str,
+    base_dir: &Path,
+    path: &[PathSegment],
+) -> Result<Vec<SceneObject>, SceneError> {
+    let model =
+        obj::load(&base_dir.join(obj_path)).map_err(|e| src.error(&child(path, "path"), &e))?;
+    src.files.borrow_mut().extend(model.files.iter().cloned());
+
+    let default_material = match &def.material {
+        Some(m) => Some(build_material(src, m, &child(path, "material"))?),
+        None => None,
+    };
+
+    let mut results = Vec::new();
+    for part in model.parts {
+        if part.indices.is_empty() {
+            continue;
+        }
+
+        let mtl = match &part.material {
+            Some(name) if !def.ignore_mtl => model.materials.get(name),
+            _ => None,
+        };
+
+        let mat = match (mtl, default_material) {
+            (Some(mtl), _) => mtl.to_material(),
+            (None, Some(m)) => m,
+            (None, None) => {
+                let message = format!("group '{}' of {} has no material", part.group, obj_path);
+                return Err(src.error(&child(path, "material"), &message));
+            }
+        };
+
+        let mesh = TriangleMesh::new(part.positions, part.normals, part.uvs, part.indices);
+        results.push((Arc::new(mesh) as Arc<dyn Hitable>, mat, Kind::Object));
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+
+    fn parse_str(contents: &str) -> Result<LoadedScene, SceneError> {
+        parse(contents, "scene.json", Path::new(""))
+    }
+
+    const CAMERA: &str = r#"{
+        "type": "camera",
+        "pos": { "x": 0, "y": 0, "z": 5 },
+        "lookat": { "x": 0, "y": 0, "z": 0 },
+        "fov": 60
+    }"#;
+
+    #[test]
+    fn test_optional_fields_have_defaults() {
+        let scene = parse_str(&format!(
+            r#"[{}, {{
+                "type": "sphere",
+                "pos": {{ "x": 0, "y": 0, "z": 0 }},
+                "radius": 1,
+                "material": {{ "type": "metal", "color": {{ "r": 1, "g": 0, "b": 0 }} }}
+            }}]"#,
+            CAMERA
+        ))
+        .unwrap();
+
+        match scene.objects[0].1 {
+            material::Material::Metal(m) => {
+                assert_eq!(m.fuzz, 0.0);
+                assert_eq!(m.albedo, 1.0);
+            }
+            m => panic!("expected metal, got {:?}", m),
+        }
+
+        assert_eq!(scene.camera.vup, Vec3::Y);
+        assert_eq!(scene.camera.fov_axis, camera::FovAxis::Vertical);
+        assert_eq!(scene.camera.aperture, camera::Aperture::Pinhole);
+        assert_eq!(scene.camera.get_focus_distance(), 5.0);
+    }
+
+    #[test]
+    fn test_errors_point_at_the_field() {
+        let err = parse_str(&format!(
+            r#"[{},
+{{
+    "type": "sphere",
+    "pos": {{ "x": 0, "y": 0, "z": 0 }},
+    "radius": 1,
+    "material": {{
+        "type": "metal",
+        "fuzz": "a lot",
+        "color": {{ "r": 1, "g": 0, "b": 0 }}
+    }}
+}}]"#,
+            CAMERA
+        ))
+        .err()
+        .unwrap();
+
+        assert_eq!(err.path, "[1].material.fuzz");
+        assert_eq!((err.line, err.column), (13, 17));
+        assert!(err.message.contains("expected f32"), "{}", err.message);
+        assert!(err
+            .to_string()
+            .starts_with("scene.json:13:17: [1].material.fuzz: "));
+    }
+
+    #[test]
+    fn test_missing_fields_and_unknown_types() {
+        let err = parse_str(r#"[{ "type": "camera", "pos": { "x": 0, "y": 0, "z": 0 } }]"#)
+            .err()
+            .unwrap();
+        assert_eq!(err.path, "[0]");
+        assert_eq!(err.message, "missing field `lookat`");
+
+        let err = parse_str(r#"[{ "type": "spher" }]"#).err().unwrap();
+        assert_eq!(err.path, "[0].type");
+        assert!(err.message.starts_with("unknown variant `spher`"));
+
+        let err = parse_str(&format!(
+            r#"[{}, {{
+                "type": "sphere",
+                "pos": {{ "x": 0, "y": 0, "z": 0 }},
+                "radius": 1,
+                "material": {{ "type": "dielectric", "color": {{ "r": 1, "g": 2, "b": 0 }} }}
+            }}]"#,
+            CAMERA
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err.path, "[1].material.color");
+        assert!(err.message.contains("inside [0;1]"));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        // a typo in an optional field would otherwise quietly leave it at the default
+        let err = parse_str(&format!(
+            r#"[{},
+{{
+    "type": "sphere",
+    "pos": {{ "x": 0, "y": 0, "z": 0 }},
+    "radius": 1,
+    "material": {{ "type": "metal", "fuz": 0.5 }}
+}}]"#,
+            CAMERA
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err.path, "[1].material.fuz");
+        assert_eq!((err.line, err.column), (11, 43));
+        assert!(
+            err.message.starts_with("unknown field `fuz`"),
+            "{}",
+            err.message
+        );
+
+        let error = |entry: &str| {
+            let err = parse_str(&format!("[{}, {}]", CAMERA, entry))
+                .err()
+                .unwrap();
+            assert!(err.message.starts_with("unknown field"), "{}", err.message);
+            err.path
+        };
+        assert_eq!(
+            error(
+                r#"{ "type": "sphere", "pos": { "x": 0, "y": 0, "z": 0 }, "radius": 1, "material": { "type": "dielectric", "refraction_idx": 1.5 } }"#
+            ),
+            "[1].material.refraction_idx"
+        );
+        assert_eq!(
+            error(
+                r#"{ "type": "camera", "pos": { "x": 0, "y": 0, "z": 5 }, "lookat": { "x": 0, "y": 0, "z": 0 }, "fov": 60, "fov_axs": "horizontal" }"#
+            ),
+            "[1].fov_axs"
+        );
+        assert_eq!(
+            error(
+                r#"{ "type": "sphere", "pos": { "x": 0, "y": 0, "z": 0, "w": 1 }, "radius": 1, "material": { "type": "lambertian" } }"#
+            ),
+            "[1].pos.w"
+        );
+        assert_eq!(
+            error(
+                r#"{ "type": "sdf", "shape": { "type": "sphere", "radius": 1, "centre": { "x": 0, "y": 0, "z": 0 } }, "material": { "type": "lambertian" } }"#
+            ),
+            "[1].shape.centre"
+        );
+
+        // shapes can be placed but cameras can't
+        assert_eq!(
+            error(
+                r#"{ "type": "camera", "pos": { "x": 0, "y": 0, "z": 5 }, "lookat": { "x": 0, "y": 0, "z": 0 }, "fov": 60, "transform": { "translate": { "x": 1, "y": 0, "z": 0 } } }"#
+            ),
+            "[1].transform"
+        );
+    }
+
+    #[test]
+    fn test_camera_aperture() {
+        let camera = |fields: &str| {
+            parse_str(&format!(
+                r#"[{{
+                    "type": "camera",
+                    "pos": {{ "x": 0, "y": 0, "z": 5 }},
+                    "lookat": {{ "x": 0, "y": 0, "z": 0 }},
+                    "fov": 60,
+                    {}
+                }}]"#,
+                fields
+            ))
+        };
+
+        let scene = camera(r#""f_stop": 2.8, "focus_distance": 3, "blades": 6"#).unwrap();
+        assert_eq!(scene.camera.aperture, camera::Aperture::FStop(2.8));
+        assert_eq!(scene.camera.get_focus_distance(), 3.0);
+        assert_eq!(scene.camera.blades, 6);
+
+        let err = camera(r#""f_stop": 2.8, "aperture_radius": 0.1"#)
+            .err()
+            .unwrap();
+        assert_eq!(err.path, "[0].aperture_radius");
+
+        let err = camera(r#""focus_distance": -1"#).err().unwrap();
+        assert_eq!(err.path, "[0].focus_distance");
+    }
+
+    #[test]
+    fn test_camera_projection() {
+        let camera = |fields: &str| {
+            parse_str(&format!(
+                r#"[{{
+                    "type": "camera",
+                    "pos": {{ "x": 0, "y": 0, "z": 5 }},
+                    "lookat": {{ "x": 0, "y": 0, "z": 0 }},
+                    {}
+                }}]"#,
+                fields
+            ))
+        };
 
-    let pos = Vec3::new(
-        x.as_f64().unwrap() as f32,
-        y.as_f64().unwrap() as f32,
-        z.as_f64().unwrap() as f32,
-    );
+        let scene = camera(r#""projection": "fisheye", "fov": 360"#).unwrap();
+        assert_eq!(scene.camera.projection, camera::Projection::Fisheye);
 
-    let radius = s["radius"].as_f64().unwrap();
+        let scene = camera(r#""projection": "orthographic", "ortho_size": 10"#).unwrap();
+        assert_eq!(
+            scene.camera.projection,
+            camera::Projection::Orthographic { size: 10.0 }
+        );
 
-    Sphere::new(pos, radius as f32)
+        let scene = camera(r#""projection": "equirectangular""#).unwrap();
+        assert_eq!(scene.camera.projection, camera::Projection::Equirectangular);
+
+        let err = camera(r#""projection": "orthographic""#).err().unwrap();
+        assert_eq!(err.message, "missing field `ortho_size`");
+
+        let err = camera(r#""fov": 180"#).err().unwrap();
+        assert_eq!(err.path, "[0].fov");
+    }
+
+    #[test]
+    fn test_output() {
+        let scene = parse_str(&format!("[{}]", CAMERA)).unwrap();
+        assert_eq!(scene.tone_mapping, ToneMapping::default());
+
+        let scene = parse_str(&format!(
+            r#"[{}, {{ "type": "output", "tonemap": "reinhard_extended", "exposure": -1.5, "white_point": 8 }}]"#,
+            CAMERA
+        ))
+        .unwrap();
+        assert_eq!(
+            scene.tone_mapping,
+            ToneMapping {
+                operator: tonemap::Operator::ReinhardExtended,
+                exposure: -1.5,
+                white_point: 8.0,
+            }
+        );
+
+        let err = parse_str(&format!(
+            r#"[{}, {{ "type": "output", "white_point": 0 }}]"#,
+            CAMERA
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err.path, "[1].white_point");
+
+        let err = parse_str(&format!(
+            r#"[{}, {{ "type": "output", "tonemap": "filmic" }}]"#,
+            CAMERA
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err.path, "[1].tonemap");
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let err = parse_str("[\n  { \"type\": \"camera\", }\n]")
+            .err()
+            .unwrap();
+        assert_eq!(err.path, "");
+        assert_eq!(err.line, 2);
+
+        let err = parse_str("[]").err().unwrap();
+        assert_eq!(err.message, "the scene has no camera");
+    }
+
+    #[test]
+    fn test_primitives() {
+        let material = r#""material": { "type": "lambertian" }"#;
+        let scene = parse_str(&format!(
+            r#"[{}, 
+            {{ "type": "plane", "point": {{ "x": 0, "y": -1, "z": 0 }}, "normal": {{ "x": 0, "y": 2, "z": 0 }}, {m} }},
+            {{ "type": "disk", "center": {{ "x": 0, "y": 0, "z": 0 }}, "normal": {{ "x": 0, "y": 0, "z": 1 }}, "radius": 1, {m} }},
+            {{ "type": "quad", "corner": {{ "x": 0, "y": 0, "z": 0 }}, "u": {{ "x": 1, "y": 0, "z": 0 }}, "v": {{ "x": 0, "y": 1, "z": 0 }}, {m} }},
+            {{ "type": "box", "min": {{ "x": -1, "y": -1, "z": -1 }}, "max": {{ "x": 1, "y": 1, "z": 1 }}, "rotation": {{ "x": 0, "y": 45, "z": 0 }}, {m} }},
+            {{ "type": "cylinder", "base": {{ "x": 0, "y": 0, "z": 0 }}, "top": {{ "x": 0, "y": 2, "z": 0 }}, "radius": 0.5, {m} }},
+            {{ "type": "cone", "base": {{ "x": 0, "y": 0, "z": 0 }}, "top": {{ "x": 0, "y": 2, "z": 0 }}, "radius": 0.5, "capped": false, {m} }},
+            {{ "type": "torus", "center": {{ "x": 0, "y": 0, "z": 0 }}, "major_radius": 2, "minor_radius": 0.5, {m} }}
+            ]"#,
+            CAMERA,
+            m = material
+        ))
+        .unwrap();
+        assert_eq!(scene.objects.len(), 7);
+
+        // the rotated box reaches further out along x with its corners
+        let bbox = scene.objects[3].0.bounding_box();
+        assert!((bbox.max.x - std::f32::consts::SQRT_2).abs() < 1.0e-5);
+        assert!((bbox.max.y - 1.0).abs() < 1.0e-5);
+
+        let error = |entry: &str| {
+            parse_str(&format!(r#"[{}, {{ {}, {} }}]"#, CAMERA, entry, material))
+                .err()
+                .unwrap()
+                .path
+        };
+        assert_eq!(
+            error(
+                r#""type": "plane", "point": { "x": 0, "y": 0, "z": 0 }, "normal": { "x": 0, "y": 0, "z": 0 }"#
+            ),
+            "[1].normal"
+        );
+        assert_eq!(
+            error(
+                r#""type": "box", "min": { "x": 0, "y": 0, "z": 0 }, "max": { "x": 1, "y": 0, "z": 1 }"#
+            ),
+            "[1].max"
+        );
+        assert_eq!(
+            error(
+                r#""type": "cone", "base": { "x": 0, "y": 0, "z": 0 }, "top": { "x": 0, "y": 0, "z": 0 }, "radius": 1"#
+            ),
+            "[1].top"
+        );
+        assert_eq!(
+            error(
+                r#""type": "torus", "center": { "x": 0, "y": 0, "z": 0 }, "major_radius": 2, "minor_radius": -1"#
+            ),
+            "[1].minor_radius"
+        );
+    }
+
+    #[test]
+    fn test_transforms_and_instances() {
+        let scene = parse_str(&format!(
+            r#"[{},
+            {{
+                "type": "sphere", "pos": {{ "x": 0, "y": 0, "z": 0 }}, "radius": 1,
+                "material": {{ "type": "lambertian" }},
+                "transform": {{ "scale": {{ "x": 2, "y": 1, "z": 1 }}, "translate": {{ "x": 0, "y": 5, "z": 0 }} }}
+            }},
+            {{
+                "type": "prototype", "name": "pair",
+                "objects": [
+                    {{ "type": "sphere", "pos": {{ "x": -1, "y": 0, "z": 0 }}, "radius": 0.5, "material": {{ "type": "lambertian" }} }},
+                    {{ "type": "sphere", "pos": {{ "x": 1, "y": 0, "z": 0 }}, "radius": 0.5, "material": {{ "type": "metal" }} }}
+                ]
+            }},
+            {{ "type": "instance", "prototype": "pair" }},
+            {{ "type": "instance", "prototype": "pair", "transform": {{ "rotate": {{ "x": 0, "y": 90, "z": 0 }}, "scale": 2 }} }},
+            {{
+                "type": "instance", "prototype": "pair",
+                "transform": {{ "matrix": [[1, 0, 0, 10], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, 1]] }}
+            }}
+            ]"#,
+            CAMERA
+        ))
+        .unwrap();
+
+        // the prototype itself isn't in the scene, each instance is
+        assert_eq!(scene.objects.len(), 7);
+
+        let bbox = |i: usize| scene.objects[i].0.bounding_box();
+        assert!(bbox(0).max.distance(Vec3::new(2.0, 6.0, 1.0)) < 1.0e-5);
+        assert!(bbox(1).min.distance(Vec3::new(-1.5, -0.5, -0.5)) < 1.0e-5);
+        // turned to lie along z and twice the size
+        assert!(bbox(3).max.distance(Vec3::new(1.0, 1.0, 3.0)) < 1.0e-4);
+        assert!(bbox(6).min.distance(Vec3::new(10.5, -0.5, -0.5)) < 1.0e-5);
+        assert!(matches!(scene.objects[6].1, material::Material::Metal(_)));
+
+        let error = |entries: &str| {
+            parse_str(&format!(r#"[{}, {}]"#, CAMERA, entries))
+                .err()
+                .unwrap()
+                .path
+        };
+        let sphere = r#""type": "sphere", "pos": { "x": 0, "y": 0, "z": 0 }, "radius": 1, "material": { "type": "lambertian" }"#;
+        assert_eq!(
+            error(&format!(
+                r#"{{ {}, "transform": {{ "scale": {{ "x": 1, "y": 0, "z": 1 }} }} }}"#,
+                sphere
+            )),
+            "[1].transform.scale"
+        );
+        assert_eq!(
+            error(&format!(
+                r#"{{ {}, "transform": {{ "matrix": [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0], [1, 0, 0, 1]] }} }}"#,
+                sphere
+            )),
+            "[1].transform.matrix"
+        );
+        assert_eq!(
+            error(r#"{ "type": "instance", "prototype": "nothing" }"#),
+            "[1].prototype"
+        );
+        assert_eq!(
+            error(&format!(
+                r#"{{ "type": "prototype", "name": "a", "objects": [{{ {} }}] }}, {{ "type": "prototype", "name": "a", "objects": [{{ {} }}] }}"#,
+                sphere, sphere
+            )),
+            "[2].name"
+        );
+        assert_eq!(
+            error(&format!(
+                r#"{{ "type": "prototype", "name": "a", "objects": [{}] }}"#,
+                CAMERA
+            )),
+            "[1].objects[0].type"
+        );
+    }
+
+    #[test]
+    fn test_groups() {
+        let scene = parse_str(&format!(
+            r#"[{},
+            {{
+                "type": "prototype", "name": "ball",
+                "objects": [{{ "type": "sphere", "pos": {{ "x": 0, "y": 0, "z": 0 }}, "radius": 1, "material": {{ "type": "dielectric", "refraction": 1.5 }} }}]
+            }},
+            {{
+                "type": "group",
+                "transform": {{ "translate": {{ "x": 10, "y": 0, "z": 0 }} }},
+                "material": {{ "type": "metal", "fuzz": 0.5 }},
+                "objects": [
+                    {{ "type": "sphere", "pos": {{ "x": 0, "y": 0, "z": 0 }}, "radius": 1 }},
+                    {{
+                        "type": "group",
+                        "transform": {{ "scale": 2, "translate": {{ "x": 0, "y": 5, "z": 0 }} }},
+                        "objects": [
+                            {{ "type": "sphere", "pos": {{ "x": 1, "y": 0, "z": 0 }}, "radius": 1 }},
+                            {{ "type": "instance", "prototype": "ball" }}
+                        ]
+                    }},
+                    {{
+                        "type": "group",
+                        "material": {{ "type": "lambertian" }},
+                        "objects": [
+                            {{ "type": "sphere", "pos": {{ "x": 0, "y": 0, "z": 0 }}, "radius": 1, "transform": {{ "translate": {{ "x": 0, "y": 0, "z": 3 }} }} }},
+                            {{ "type": "sphere", "pos": {{ "x": 0, "y": 0, "z": 0 }}, "radius": 1, "material": {{ "type": "metal" }} }}
+                        ]
+                    }}
+                ]
+            }}
+            ]"#,
+            CAMERA
+        ))
+        .unwrap();
+        assert_eq!(scene.objects.len(), 5);
+
+        let bbox = |i: usize| scene.objects[i].0.bounding_box();
+        let material = |i: usize| scene.objects[i].1;
+        assert!(bbox(0).min.distance(Vec3::new(9.0, -1.0, -1.0)) < 1.0e-5);
+        assert!(matches!(material(0), material::Material::Metal(m) if m.fuzz == 0.5));
+
+        // the transforms of both groups, the inner one scaling first
+        assert!(bbox(1).min.distance(Vec3::new(10.0, 3.0, -2.0)) < 1.0e-5);
+        assert!(bbox(1).max.distance(Vec3::new(14.0, 7.0, 2.0)) < 1.0e-5);
+        assert!(matches!(material(1), material::Material::Metal(m) if m.fuzz == 0.5));
+
+        // instances keep the materials of the prototype
+        assert!(bbox(2).max.distance(Vec3::new(12.0, 7.0, 2.0)) < 1.0e-5);
+        assert!(matches!(material(2), material::Material::Dielectric(_)));
+
+        // the innermost group's material wins, the entry's own over both
+        assert!(bbox(3).min.distance(Vec3::new(9.0, -1.0, 2.0)) < 1.0e-5);
+        assert!(matches!(material(3), material::Material::Lambertian(_)));
+        assert!(matches!(material(4), material::Material::Metal(m) if m.fuzz == 0.0));
+
+        let error = |group: &str| {
+            parse_str(&format!(
+                r#"[{}, {{ "type": "group", {} }}]"#,
+                CAMERA, group
+            ))
+            .err()
+            .unwrap()
+        };
+        let err = error(&format!(r#""objects": [{}]"#, CAMERA));
+        assert_eq!(err.path, "[1].objects[0].type");
+
+        let err = error(
+            r#""objects": [{ "type": "group", "objects": [{ "type": "sphere", "pos": { "x": 0, "y": 0, "z": 0 }, "radius": 1 }] }]"#,
+        );
+        assert_eq!(err.path, "[1].objects[0].objects[0]");
+        assert_eq!(err.message, "missing field `material`");
+
+        let err = error(r#""material": { "type": "dielectric" }, "objects": []"#);
+        assert_eq!(err.path, "[1].material.refraction");
+    }
+
+    #[test]
+    fn test_csg() {
+        let ball = |x: f32, radius: f32| {
+            format!(
+                r#"{{ "type": "sphere", "pos": {{ "x": {}, "y": 0, "z": 0 }}, "radius": {} }}"#,
+                x, radius
+            )
+        };
+        let scene = parse_str(&format!(
+            r#"[{}, {{
+                "type": "csg", "op": "difference",
+                "material": {{ "type": "metal" }},
+                "a": {{ "type": "box", "min": {{ "x": -1, "y": -1, "z": -1 }}, "max": {{ "x": 1, "y": 1, "z": 1 }} }},
+                "b": {{ "type": "csg", "op": "union", "a": {}, "b": {}, "transform": {{ "translate": {{ "x": 0, "y": 0, "z": 1 }} }} }}
+            }}]"#,
+            CAMERA,
+            ball(-0.5, 0.25),
+            ball(0.5, 0.25)
+        ))
+        .unwrap();
+        assert_eq!(scene.objects.len(), 1);
+        assert!(matches!(scene.objects[0].1, material::Material::Metal(_)));
+
+        // the block has two dents in the front, each a quarter deep
+        let geometry = &scene.objects[0].0;
+        let dent = geometry
+            .hit(&Ray::new(Vec3::new(0.5, 0.0, 5.0), -Vec3::Z))
+            .unwrap();
+        assert!((dent.point.z - 0.75).abs() < 1.0e-4, "{:?}", dent.point);
+        let flat = geometry
+            .hit(&Ray::new(Vec3::new(0.0, 0.0, 5.0), -Vec3::Z))
+            .unwrap();
+        assert!((flat.point.z - 1.0).abs() < 1.0e-4);
+
+        let error = |fields: &str| {
+            parse_str(&format!(
+                r#"[{}, {{ "type": "csg", "material": {{ "type": "metal" }}, {} }}]"#,
+                CAMERA, fields
+            ))
+            .err()
+            .unwrap()
+            .path
+        };
+        assert_eq!(
+            error(&format!(
+                r#""op": "xor", "a": {}, "b": {}"#,
+                ball(0.0, 1.0),
+                ball(1.0, 1.0)
+            )),
+            "[1].op"
+        );
+        assert_eq!(
+            error(&format!(
+                r#""op": "union", "a": {{ "type": "group", "objects": [{}, {}] }}, "b": {}"#,
+                ball(0.0, 1.0),
+                ball(1.0, 1.0),
+                ball(2.0, 1.0)
+            )),
+            "[1].a"
+        );
+        assert_eq!(
+            error(&format!(
+                r#""op": "union", "a": {}, "b": {{ "type": "sphere", "radius": 1 }}"#,
+                ball(0.0, 1.0)
+            )),
+            "[1].b"
+        );
+    }
+
+    #[test]
+    fn test_sdf() {
+        let sdf = |shape: &str| {
+            parse_str(&format!(
+                r#"[{}, {{ "type": "sdf", "material": {{ "type": "lambertian" }}, "shape": {} }}]"#,
+                CAMERA, shape
+            ))
+        };
+
+        let scene = sdf(r#"{
+            "type": "smooth_union", "smoothness": 0.2,
+            "a": { "type": "rounded_box", "size": { "x": 2, "y": 1, "z": 2 }, "radius": 0.2 },
+            "b": {
+                "type": "twist", "degrees_per_unit": 45,
+                "shape": { "type": "capsule", "base": { "x": 0, "y": 0, "z": 0 }, "top": { "x": 0, "y": 2, "z": 0 }, "radius": 0.3 }
+            }
+        }"#)
+        .unwrap();
+        let geometry = &scene.objects[0].0;
+        assert!(geometry.bounding_box().max.y > 2.3);
+
+        // the top of the capsule sticking out of the box
+        let top = geometry
+            .hit(&Ray::new(Vec3::new(0.0, 5.0, 0.0), -Vec3::Y))
+            .unwrap();
+        assert!((top.point.y - 2.3).abs() < 1.0e-3, "{:?}", top.point);
+        assert!(top.normal.distance(Vec3::Y) < 1.0e-2);
+
+        let scene = sdf(r#"{
+            "type": "repeat", "spacing": { "x": 3, "y": 0, "z": 0 }, "count": [1, 0, 0],
+            "shape": { "type": "torus", "major_radius": 1, "minor_radius": 0.25 }
+        }"#)
+        .unwrap();
+        let bbox = scene.objects[0].0.bounding_box();
+        assert!((bbox.max.x - 4.25).abs() < 1.0e-2);
+        assert!(scene.objects[0]
+            .0
+            .hit(&Ray::new(Vec3::new(3.0, 5.0, 1.0), -Vec3::Y))
+            .is_some());
+
+        let error = |shape: &str| sdf(shape).err().unwrap().path;
+        assert_eq!(error(r#"{ "type": "blob" }"#), "[1].shape.type");
+        assert_eq!(
+            error(
+                r#"{ "type": "subtraction", "a": { "type": "sphere", "radius": 1 }, "b": { "type": "rounded_box", "size": { "x": 1, "y": 1, "z": 1 }, "radius": 0.6 } }"#
+            ),
+            "[1].shape.b.radius"
+        );
+        assert_eq!(
+            error(
+                r#"{ "type": "repeat", "spacing": { "x": 0, "y": 0, "z": 0 }, "count": [2, 0, 0], "shape": { "type": "sphere", "radius": 1 } }"#
+            ),
+            "[1].shape.spacing"
+        );
+    }
 }
@@ -1,27 +1,53 @@
 use clap::Parser;
 
+mod aabb;
+mod aov;
 mod app;
+mod bvh;
 mod camera;
+mod checkpoint;
+mod csg;
+mod cuboid;
+mod cylinder;
+mod denoise;
+mod filter;
+mod frame;
 mod loader;
 mod material;
+mod mesh;
+mod obj;
+mod output;
+mod pathtracer;
+mod plane;
 mod ray;
 mod renderer;
+mod rng;
+mod sampler;
+mod sampling;
 mod scene;
+mod schema;
+mod sdf;
 mod sphere;
+mod tonemap;
+mod torus;
+mod transform;
+mod triangle;
 
-use glam::Vec3;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use std::time::SystemTime;
 
+// samples per pass when checkpointing without --progressive, so that there's something to save
+const CHECKPOINT_PASS_SAMPLES: u32 = 16;
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap(short, long)]
     width: u32,
 
-    #[clap(short, long)]
+    #[clap(long)]
     height: u32,
 
     #[clap(short, long)]
@@ -33,11 +59,91 @@ struct Args {
     #[clap(short, long)]
     per_pixel_samples: u32,
 
-    #[clap(short, long)]
+    // for the path tracer these are the light samples per bounce
+    #[clap(long)]
     shadow_samples: u32,
 
+    // keep sampling pixels until their relative error is below this, 0.05 is 5%
+    #[clap(long)]
+    adaptive_threshold: Option<f32>,
+
+    // the most samples adaptive sampling may take, 16 times per-pixel-samples unless given
+    #[clap(long, requires = "adaptive-threshold")]
+    max_samples: Option<u32>,
+
+    // render the whole frame in passes of this many samples per pixel, the image is written
+    // after every one so it can be watched getting better
+    #[clap(long)]
+    progressive: Option<u32>,
+
+    // where the passes are written, the output itself unless given
+    #[clap(long, requires = "progressive")]
+    preview: Option<String>,
+
+    // in seconds, write the passes at most this often rather than after every one
+    #[clap(long, requires = "progressive")]
+    preview_interval: Option<f32>,
+
+    // save the film here after every pass so the render can be resumed if it gets killed
+    #[clap(long)]
+    checkpoint: Option<String>,
+
+    // in seconds, save the checkpoint at most this often rather than after every pass
+    #[clap(long, requires = "checkpoint")]
+    checkpoint_interval: Option<f32>,
+
+    // keep adding passes until this much time has passed, e.g. 90s, 5m or 1.5h. Passes are
+    // per-pixel-samples each unless --progressive says otherwise
+    #[clap(long, parse(try_from_str = app::parse_duration))]
+    time_limit: Option<std::time::Duration>,
+
+    // carry on from a checkpoint of the same scene, usually with more samples per pixel
+    #[clap(long)]
+    resume: Option<String>,
+
+    // renders with the same seed and settings come out identical, random unless given
+    #[clap(long)]
+    seed: Option<u64>,
+
+    // where the numbers samples are made of come from: random, stratified, halton, sobol or
+    // blue_noise. All but random spread the samples of a pixel out evenly
+    #[clap(long, arg_enum, default_value = "random")]
+    sampler: sampler::SamplerKind,
+
+    // how samples are weighted into the pixels around them: box, tent, gaussian, mitchell or
+    // lanczos
+    #[clap(long, arg_enum, default_value = "box")]
+    filter: filter::FilterKind,
+
+    // in pixels, how far the filter reaches. Half a pixel for box, up to 3 for lanczos unless given
+    #[clap(long)]
+    filter_radius: Option<f32>,
+
     #[clap(short, long)]
     threads: usize,
+
+    #[clap(short, long, arg_enum, default_value = "whitted")]
+    integrator: app::Integrator,
+
+    // the ones below override the "output" entry of the scene, only used for PNG
+    #[clap(long, arg_enum)]
+    tonemap: Option<tonemap::Operator>,
+
+    // in stops, +1 is twice as bright
+    #[clap(long, allow_hyphen_values = true)]
+    exposure: Option<f32>,
+
+    // extended Reinhard only, radiance that becomes white
+    #[clap(long)]
+    white_point: Option<f32>,
+
+    // extra images to write, comma separated: --aov depth,normal,albedo
+    #[clap(long, arg_enum, use_value_delimiter = true)]
+    aov: Vec<aov::Aov>,
+
+    // filters the noise out of the image, best with at least a few samples per pixel
+    #[clap(long)]
+    denoise: bool,
 }
 
 fn main() {
@@ -45,39 +151,197 @@ fn main() {
     let width = args.width;
     let height = args.height;
 
+    // better to find out now than after the render
+    for path in std::iter::once(&args.output).chain(&args.preview) {
+        if let Err(e) = output::Format::from_path(path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if matches!(args.filter_radius, Some(r) if r <= 0.0 || !r.is_finite()) {
+        eprintln!("--filter-radius must be positive");
+        std::process::exit(1);
+    }
+    if args.progressive == Some(0) {
+        eprintln!("--progressive needs at least one sample per pass");
+        std::process::exit(1);
+    }
+
     let mut scene = scene::Scene::new(width as u64, height as u64);
 
-    let (objects, camera) = loader::load(&args.scene);
+    let loaded = match loader::load(&args.scene) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let camera = loaded.camera;
 
-    for obj in objects {
+    let mut tone_mapping = loaded.tone_mapping;
+    if let Some(operator) = args.tonemap {
+        tone_mapping.operator = operator;
+    }
+    if let Some(exposure) = args.exposure {
+        tone_mapping.exposure = exposure;
+    }
+    if let Some(white_point) = args.white_point {
+        if white_point <= 0.0 {
+            eprintln!("--white-point must be positive");
+            std::process::exit(1);
+        }
+        tone_mapping.white_point = white_point;
+    }
+
+    for obj in loaded.objects {
         match obj.2 {
             loader::Kind::Object => scene.add_object(obj.0, obj.1),
-            loader::Kind::Light => scene.add_light(obj.0, obj.1),
+            loader::Kind::Light { intensity } => scene.add_light(obj.0, obj.1, intensity),
         }
     }
+    scene.finalize();
 
     let start = SystemTime::now();
-    let renderer = Arc::new(Mutex::new(renderer::Renderer::new(width, height)));
+    let renderer = Arc::new(Mutex::new(
+        renderer::Renderer::new(width, height).with_aovs(args.aov.clone()),
+    ));
 
     // let settings = app::Settings::new(1, 4, 16);
-    let settings = app::Settings::new(
+    let mut settings = app::Settings::new(
         args.per_pixel_samples,
         args.threads,
         8, // todo:
         args.shadow_samples,
     );
+    settings.integrator = args.integrator;
+    settings.sampler = args.sampler;
+    settings.filter = filter::Filter::new(args.filter, args.filter_radius);
+    settings.seed = args.seed.unwrap_or_else(rand::random);
+    settings.aovs = !args.aov.is_empty() || args.denoise;
+    if let Some(threshold) = args.adaptive_threshold {
+        settings.adaptive_threshold = Some(threshold);
+        settings.max_samples = args
+            .max_samples
+            .unwrap_or(args.per_pixel_samples * 16)
+            .max(args.per_pixel_samples);
+    }
+
+    settings.pass_samples = args
+        .progressive
+        .or_else(|| args.time_limit.map(|_| args.per_pixel_samples))
+        .or_else(|| args.checkpoint.as_ref().map(|_| CHECKPOINT_PASS_SAMPLES));
+    if let Some(limit) = args.time_limit {
+        settings.time_limit = Some(limit);
+        // adaptive sampling keeps its cap, otherwise only the time stops the render
+        if settings.adaptive_threshold.is_none() {
+            settings.max_samples = u32::MAX;
+        }
+    }
+
+    let scene_hash = match checkpoint::scene_hash(&loaded.files) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("{}: {}", args.scene, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = &args.resume {
+        let checkpoint = checkpoint::read(path).and_then(|c| {
+            c.header
+                .check(scene_hash, width, height, &settings)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            Ok(c)
+        });
+        match checkpoint {
+            Ok(checkpoint) => {
+                // carrying on with the samples the checkpoint would have gotten next
+                if args.seed.is_none() {
+                    settings.seed = checkpoint.header.settings.seed;
+                }
+                println!("Resuming from {} samples per pixel", checkpoint.header.goal);
+                renderer.lock().unwrap().restore(checkpoint);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    println!("Seed {}", settings.seed);
+
+    let preview = args.preview.clone().unwrap_or_else(|| args.output.clone());
+    let mut last_preview = SystemTime::now();
+    let mut last_checkpoint = SystemTime::now();
+    let after_pass = |renderer: &mut renderer::Renderer, pass: u32, last: bool| {
+        if let Some(path) = &args.checkpoint {
+            let due = match args.checkpoint_interval {
+                Some(interval) => {
+                    last || last_checkpoint.elapsed().unwrap().as_secs_f32() >= interval
+                }
+                None => true,
+            };
+
+            if due {
+                last_checkpoint = SystemTime::now();
+                let header =
+                    checkpoint::Header::new(scene_hash, width, height, settings, renderer.goal());
+                match checkpoint::write(path, &renderer.checkpoint(header)) {
+                    Ok(()) => println!("Checkpoint saved at {}", path),
+                    Err(e) => eprintln!("{}: {}", path, e),
+                }
+            }
+        }
+
+        // the last one is written below anyway
+        if args.progressive.is_none() || last {
+            return;
+        }
+        if let Some(interval) = args.preview_interval {
+            if last_preview.elapsed().unwrap().as_secs_f32() < interval {
+                return;
+            }
+        }
+        last_preview = SystemTime::now();
+
+        if args.denoise {
+            renderer.denoise();
+        }
+        match renderer.save(&preview, &tone_mapping) {
+            Ok(()) => println!("Pass {} saved at {}", pass, preview),
+            Err(e) => eprintln!("{}: {}", preview, e),
+        }
+    };
 
     app::render(
         renderer.clone(),
         camera,
         Arc::new(scene),
-        width,
-        height,
         settings,
+        after_pass,
     );
     let elapsed = start.elapsed().unwrap();
 
     println!("Rendering took {}ms", elapsed.as_millis());
-    renderer.lock().unwrap().save(&args.output);
+    if args.time_limit.is_some() {
+        let (min, mean, max) = renderer.lock().unwrap().samples_per_pixel();
+        if min == max {
+            println!("Reached {} samples per pixel", min);
+        } else {
+            println!(
+                "Reached {:.1} samples per pixel on average, {} to {}",
+                mean, min, max
+            );
+        }
+    }
+    if args.denoise {
+        let start = SystemTime::now();
+        renderer.lock().unwrap().denoise();
+        println!("Denoising took {}ms", start.elapsed().unwrap().as_millis());
+    }
+    if let Err(e) = renderer.lock().unwrap().save(&args.output, &tone_mapping) {
+        eprintln!("{}: {}", args.output, e);
+        std::process::exit(1);
+    }
     println!("Saved at {}", args.output);
 }
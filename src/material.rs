@@ -2,16 +2,16 @@ use glam::Vec3;
 
 use std::ops;
 
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct Color {
     pub r: f32,
     pub g: f32,
     pub b: f32,
 }
 
-impl Into<Vec3> for Color {
-    fn into(self) -> Vec3 {
-        Vec3::new(self.r, self.g, self.b)
+impl From<Color> for Vec3 {
+    fn from(c: Color) -> Vec3 {
+        Vec3::new(c.r, c.g, c.b)
     }
 }
 
@@ -107,15 +107,34 @@ impl Color {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Material {
     Metal(Metal),
     Lambertian(Lambertian),
     Dielectric(Dielectric),
 }
 
+impl Material {
+    pub fn color(&self) -> Color {
+        match self {
+            Material::Metal(m) => m.color,
+            Material::Lambertian(m) => m.color,
+            Material::Dielectric(m) => m.color,
+        }
+    }
+
+    // the fraction of light reflected, glass lets all of it through
+    pub fn albedo(&self) -> Color {
+        match self {
+            Material::Metal(m) => m.color * m.albedo,
+            Material::Lambertian(m) => m.color * m.albedo,
+            Material::Dielectric(m) => m.color,
+        }
+    }
+}
+
 // has a color and fuzz factor which is how  much to scatter the rays
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Metal {
     pub color: Color,
     pub fuzz: f32,
@@ -123,14 +142,14 @@ pub struct Metal {
 }
 
 // has a color and refraction index by how much to bend the light
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Dielectric {
     pub color: Color,
     pub refraction: f32,
 }
 
 // has a color and albedo which means how much of light it "eats". 0 means - only it's color will be visible
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Lambertian {
     pub color: Color,
     pub albedo: f32,
@@ -0,0 +1,268 @@
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::ray::Ray;
+use crate::rng::Pcg32;
+use crate::scene::CollisionData;
+use crate::scene::Hitable;
+use crate::triangle;
+
+use glam::Vec2;
+use glam::Vec3;
+use rand::prelude::*;
+
+/**
+ * Indexed triangle mesh. Every vertex has a position and optionally a normal and a UV, the
+ * triangles index into those. The mesh has its own BVH over its triangles so to the scene it's
+ * a single object no matter how many triangles it has
+ */
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    // either empty or one per position
+    normals: Vec<Vec3>,
+    // either empty or one per position
+    uvs: Vec<Vec2>,
+    indices: Vec<[usize; 3]>,
+    bvh: Bvh,
+    bbox: Aabb,
+    // running sum of triangle areas, used to pick random points uniformly over the surface
+    area_cdf: Vec<f32>,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<Vec2>,
+        indices: Vec<[usize; 3]>,
+    ) -> TriangleMesh {
+        assert!(normals.is_empty() || normals.len() == positions.len());
+        assert!(uvs.is_empty() || uvs.len() == positions.len());
+        assert!(indices.iter().flatten().all(|&i| i < positions.len()));
+
+        let vertices = |idx: &[usize; 3]| [positions[idx[0]], positions[idx[1]], positions[idx[2]]];
+
+        let boxes: Vec<Aabb> = indices
+            .iter()
+            .map(|idx| triangle::bounding_box(&vertices(idx)))
+            .collect();
+        let bbox = boxes.iter().fold(Aabb::empty(), |acc, b| acc.union(b));
+
+        let mut total = 0.0;
+        let area_cdf = indices
+            .iter()
+            .map(|idx| {
+                total += triangle::area(&vertices(idx));
+                total
+            })
+            .collect();
+
+        TriangleMesh {
+            bvh: Bvh::build(&boxes),
+            positions,
+            normals,
+            uvs,
+            indices,
+            bbox,
+            area_cdf,
+        }
+    }
+
+    fn total_area(&self) -> f32 {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    // picks a triangle with probability proportional to its area, u is in [0;1)
+    fn random_triangle(&self, u: f32) -> usize {
+        let target = u * self.total_area();
+
+        self.area_cdf
+            .partition_point(|&a| a < target)
+            .min(self.indices.len() - 1)
+    }
+
+    fn vertices(&self, tri: usize) -> [Vec3; 3] {
+        let idx = &self.indices[tri];
+        [
+            self.positions[idx[0]],
+            self.positions[idx[1]],
+            self.positions[idx[2]],
+        ]
+    }
+
+    fn normals(&self, tri: usize) -> Option<[Vec3; 3]> {
+        if self.normals.is_empty() {
+            return None;
+        }
+
+        let idx = &self.indices[tri];
+        Some([
+            self.normals[idx[0]],
+            self.normals[idx[1]],
+            self.normals[idx[2]],
+        ])
+    }
+
+    fn uvs(&self, tri: usize) -> Option<[Vec2; 3]> {
+        if self.uvs.is_empty() {
+            return None;
+        }
+
+        let idx = &self.indices[tri];
+        Some([self.uvs[idx[0]], self.uvs[idx[1]], self.uvs[idx[2]]])
+    }
+}
+
+impl Hitable for TriangleMesh {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        if self.indices.is_empty() {
+            return Vec3::ZERO;
+        }
+
+        triangle::random_point(&self.vertices(self.random_triangle(u.z)), u.truncate()) - self.pos()
+    }
+
+    // the triangle is picked by area so the density per area is 1 / total area all over the mesh
+    fn sample_direction(&self, from: Vec3, rng: &mut Pcg32) -> Option<(Vec3, f32)> {
+        if self.indices.is_empty() {
+            return None;
+        }
+
+        triangle::sample_direction(
+            &self.vertices(self.random_triangle(rng.gen())),
+            from,
+            self.total_area(),
+            rng,
+        )
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.bbox.centroid()
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bbox
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let mut closest: Option<(f32, f32, f32)> = None;
+
+        let (tri, _) = self.bvh.traverse(r, |tri, t_max| {
+            let hit = triangle::intersect(&self.vertices(tri), r)?;
+            if hit.0 < t_max {
+                closest = Some(hit);
+            }
+            Some(hit.0)
+        })?;
+
+        Some(triangle::collision(
+            r,
+            closest.unwrap(),
+            &self.vertices(tri),
+            self.normals(tri).as_ref(),
+            self.uvs(tri).as_ref(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Face;
+    use crate::triangle::Triangle;
+
+    // n*n quads in the xy plane, normals bulge outwards as if it was a part of a sphere
+    fn grid(n: usize) -> TriangleMesh {
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut indices = Vec::new();
+
+        for j in 0..=n {
+            for i in 0..=n {
+                let p = Vec3::new(i as f32 / n as f32 - 0.5, j as f32 / n as f32 - 0.5, 0.0);
+                positions.push(p);
+                normals.push((p + Vec3::Z).normalize());
+                uvs.push(Vec2::new(i as f32 / n as f32, j as f32 / n as f32));
+            }
+        }
+
+        for j in 0..n {
+            for i in 0..n {
+                let a = j * (n + 1) + i;
+                let b = a + 1;
+                let c = a + n + 1;
+                let d = c + 1;
+                indices.push([a, b, d]);
+                indices.push([a, d, c]);
+            }
+        }
+
+        TriangleMesh::new(positions, normals, uvs, indices)
+    }
+
+    #[test]
+    fn test_triangle_hit() {
+        let tri = Triangle::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        )
+        .with_uvs([Vec2::ZERO, Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0)]);
+
+        let r = Ray::new(Vec3::new(0.25, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = tri.hit(&r).unwrap();
+        assert!((hit.point - Vec3::new(0.25, 0.25, 0.0)).length() < 1e-5);
+        assert!((hit.uv - Vec2::new(0.25, 0.25)).length() < 1e-5);
+        assert!(matches!(hit.face, Face::Front));
+        assert_eq!(hit.normal, Vec3::Z);
+
+        // from below it's the back face and the normal is turned around
+        let r = Ray::new(Vec3::new(0.25, 0.25, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = tri.hit(&r).unwrap();
+        assert!(matches!(hit.face, Face::Back));
+        assert_eq!(hit.normal, -Vec3::Z);
+
+        let r = Ray::new(Vec3::new(0.75, 0.75, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(tri.hit(&r).is_none());
+    }
+
+    #[test]
+    fn test_mesh_interpolates_normals_and_uvs() {
+        let mesh = grid(8);
+        assert_eq!(mesh.indices.len(), 128);
+
+        let r = Ray::new(Vec3::new(0.3, -0.2, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = mesh.hit(&r).unwrap();
+
+        assert!((hit.point - Vec3::new(0.3, -0.2, 0.0)).length() < 1e-5);
+        assert!((hit.uv - Vec2::new(0.8, 0.3)).length() < 1e-5);
+
+        // smooth shading tilts the normal towards the side that was hit
+        assert!(hit.normal.x > 0.1 && hit.normal.y < -0.1);
+        assert!((hit.normal.length() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_mesh_bvh_finds_closest_triangle() {
+        let mesh = grid(32);
+        let mut rng = Pcg32::new(3, 0);
+
+        for _ in 0..500 {
+            let origin = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 2.0);
+            let r = Ray::new(origin, Vec3::new(rng.gen_range(-0.3..0.3), 0.1, -1.0));
+
+            let brute = (0..mesh.indices.len())
+                .filter_map(|tri| triangle::intersect(&mesh.vertices(tri), &r))
+                .map(|hit| hit.0)
+                .fold(None, |acc: Option<f32>, t| {
+                    Some(acc.map_or(t, |a| a.min(t)))
+                });
+
+            match (brute, mesh.hit(&r)) {
+                (None, None) => {}
+                (Some(t), Some(hit)) => assert!((r.at(t) - hit.point).length() < 1e-4),
+                _ => panic!("bvh and brute force disagree"),
+            }
+        }
+    }
+}
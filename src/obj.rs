@@ -0,0 +1,471 @@
+use crate::material;
+use crate::material::Color;
+use crate::material::Material;
+
+use glam::Vec2;
+use glam::Vec3;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+// what glass gets when the MTL doesn't say, Ni of 1 would make it invisible
+const DEFAULT_REFRACTION: f32 = 1.5;
+
+/**
+ * Part of an OBJ file that shares a group and a material, faces are already triangulated and
+ * the vertices are unified, meaning a vertex is a unique combination of position, UV and normal
+ * the way TriangleMesh wants them
+ */
+#[derive(Debug, Default)]
+pub struct ObjPart {
+    pub group: String,
+    pub material: Option<String>,
+    pub positions: Vec<Vec3>,
+    // empty unless every vertex of the part has one
+    pub normals: Vec<Vec3>,
+    // empty unless every vertex of the part has one
+    pub uvs: Vec<Vec2>,
+    pub indices: Vec<[usize; 3]>,
+}
+
+#[derive(Debug, Default)]
+pub struct ObjModel {
+    pub parts: Vec<ObjPart>,
+    pub materials: HashMap<String, MtlMaterial>,
+    // the OBJ file and the MTL libraries it was read from, empty when parsed from a string
+    pub files: Vec<PathBuf>,
+}
+
+// the parameters of a single `newmtl` we know how to map to our materials
+#[derive(Debug, Default, Clone)]
+pub struct MtlMaterial {
+    pub kd: Option<Vec3>,
+    pub ks: Option<Vec3>,
+    pub ns: Option<f32>,
+    pub ni: Option<f32>,
+    pub d: Option<f32>,
+    pub illum: Option<u32>,
+}
+
+impl MtlMaterial {
+    /**
+     * Closest thing among our materials:
+     * - anything transparent (d < 1 or one of the refraction illumination models) is glass
+     * - anything with reflections turned on or with more specular than diffuse color is metal,
+     *   the shininess exponent is turned into fuzz, Ns of 0 being fully rough and 1000 a mirror
+     * - the rest is lambertian
+     */
+    pub fn to_material(&self) -> Material {
+        let kd = self.kd.unwrap_or(Vec3::splat(0.8));
+        let ks = self.ks.unwrap_or(Vec3::ZERO);
+        let illum = self.illum.unwrap_or(2);
+
+        let transparent = self.d.is_some_and(|d| d < 1.0) || matches!(illum, 4 | 6 | 7 | 9);
+        let reflective = matches!(illum, 3 | 5) || ks.max_element() > kd.max_element();
+
+        if transparent {
+            let refraction = match self.ni {
+                Some(ni) if ni > 1.0 => ni,
+                _ => DEFAULT_REFRACTION,
+            };
+
+            Material::Dielectric(material::Dielectric {
+                color: Color::white(),
+                refraction,
+            })
+        } else if reflective {
+            let ns = self.ns.unwrap_or(0.0).max(0.0);
+
+            Material::Metal(material::Metal {
+                color: to_color(ks),
+                fuzz: (2.0 / (ns + 2.0)).sqrt().min(1.0),
+                albedo: 1.0,
+            })
+        } else {
+            Material::Lambertian(material::Lambertian {
+                color: to_color(kd),
+                albedo: 1.0,
+            })
+        }
+    }
+}
+
+fn to_color(v: Vec3) -> Color {
+    let v = v.clamp(Vec3::ZERO, Vec3::ONE);
+    Color::new(v.x, v.y, v.z)
+}
+
+/**
+ * Reads an OBJ file along with the MTL libraries it references, those are looked up relative
+ * to the OBJ file
+ */
+pub fn load(path: &Path) -> Result<ObjModel, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut model = parse(&contents).map_err(|e| format!("{}:{}", path.display(), e))?;
+    model.files.push(path.to_path_buf());
+
+    for lib in mtllibs(&contents) {
+        let mtl_path = dir.join(&lib);
+        let contents =
+            fs::read_to_string(&mtl_path).map_err(|e| format!("{}: {}", mtl_path.display(), e))?;
+        let materials =
+            parse_mtl(&contents).map_err(|e| format!("{}:{}", mtl_path.display(), e))?;
+        model.materials.extend(materials);
+        model.files.push(mtl_path);
+    }
+
+    Ok(model)
+}
+
+fn mtllibs(contents: &str) -> Vec<String> {
+    lines(contents)
+        .filter_map(|(_, line)| line.strip_prefix("mtllib "))
+        .flat_map(|libs| libs.split_whitespace().map(String::from))
+        .collect()
+}
+
+// non empty lines without comments, with their line numbers for error messages
+fn lines(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents.lines().enumerate().filter_map(|(i, line)| {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        }
+        .trim();
+
+        if line.is_empty() {
+            None
+        } else {
+            Some((i + 1, line))
+        }
+    })
+}
+
+fn parse_floats<const N: usize>(args: &[&str], optional_from: usize) -> Result<[f32; N], String> {
+    if args.len() < optional_from {
+        return Err(format!("expected at least {} numbers", optional_from));
+    }
+
+    let mut out = [0.0; N];
+    for (i, arg) in args.iter().take(N).enumerate() {
+        out[i] = arg
+            .parse()
+            .map_err(|_| format!("'{}' is not a number", arg))?;
+    }
+
+    Ok(out)
+}
+
+// OBJ indices start at 1, negative ones count back from the last element defined so far
+fn resolve_index(s: &str, len: usize) -> Result<usize, String> {
+    let i: i64 = s.parse().map_err(|_| format!("bad index '{}'", s))?;
+
+    let resolved = if i > 0 { i - 1 } else { len as i64 + i };
+    if i == 0 || resolved < 0 || resolved >= len as i64 {
+        return Err(format!("index {} out of range", i));
+    }
+
+    Ok(resolved as usize)
+}
+
+// key of a unified vertex: position, uv and normal indices
+type VertexKey = (usize, Option<usize>, Option<usize>);
+
+#[derive(Default)]
+struct PartBuilder {
+    part: ObjPart,
+    vertices: HashMap<VertexKey, usize>,
+    missing_normals: bool,
+    missing_uvs: bool,
+}
+
+impl PartBuilder {
+    fn vertex(
+        &mut self,
+        key: VertexKey,
+        positions: &[Vec3],
+        uvs: &[Vec2],
+        normals: &[Vec3],
+    ) -> usize {
+        if let Some(&idx) = self.vertices.get(&key) {
+            return idx;
+        }
+
+        let idx = self.part.positions.len();
+        self.part.positions.push(positions[key.0]);
+
+        match key.1 {
+            Some(uv) => self.part.uvs.push(uvs[uv]),
+            None => {
+                self.missing_uvs = true;
+                self.part.uvs.push(Vec2::ZERO);
+            }
+        }
+
+        match key.2 {
+            Some(n) => self.part.normals.push(normals[n].normalize()),
+            None => {
+                self.missing_normals = true;
+                self.part.normals.push(Vec3::ZERO);
+            }
+        }
+
+        self.vertices.insert(key, idx);
+        idx
+    }
+
+    fn finish(mut self) -> ObjPart {
+        if self.missing_normals {
+            self.part.normals.clear();
+        }
+        if self.missing_uvs {
+            self.part.uvs.clear();
+        }
+
+        self.part
+    }
+}
+
+/**
+ * Parses the geometry of an OBJ file. Polygons are triangulated as a fan around their first
+ * vertex, which is fine for the convex polygons modelling tools export. Every change of group
+ * or material starts a new part, parts that end up the same are merged back together.
+ * Anything we don't use (smoothing groups, lines, free-form surfaces) is skipped
+ */
+pub fn parse(contents: &str) -> Result<ObjModel, String> {
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+
+    let mut parts: HashMap<(String, Option<String>), PartBuilder> = HashMap::new();
+    // keep the parts in the order they appear in
+    let mut order: Vec<(String, Option<String>)> = Vec::new();
+
+    let mut group = String::from("default");
+    let mut mtl: Option<String> = None;
+
+    for (line_nr, line) in lines(contents) {
+        let mut tokens = line.split_whitespace();
+        let keyword = tokens.next().unwrap_or("");
+        let args: Vec<&str> = tokens.collect();
+        let err = |e: String| format!("{}: {}", line_nr, e);
+
+        match keyword {
+            "v" => {
+                let v = parse_floats::<3>(&args, 3).map_err(err)?;
+                positions.push(Vec3::from(v));
+            }
+            "vt" => {
+                let vt = parse_floats::<2>(&args, 1).map_err(err)?;
+                uvs.push(Vec2::from(vt));
+            }
+            "vn" => {
+                let vn = parse_floats::<3>(&args, 3).map_err(err)?;
+                normals.push(Vec3::from(vn));
+            }
+            "g" | "o" => {
+                group = if args.is_empty() {
+                    String::from("default")
+                } else {
+                    args.join(" ")
+                };
+            }
+            "usemtl" => {
+                mtl = args.first().map(|s| s.to_string());
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err(String::from("face needs at least 3 vertices")));
+                }
+
+                let key = (group.clone(), mtl.clone());
+                if !parts.contains_key(&key) {
+                    order.push(key.clone());
+                }
+                let builder = parts.entry(key).or_insert_with(|| PartBuilder {
+                    part: ObjPart {
+                        group: group.clone(),
+                        material: mtl.clone(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                });
+
+                let mut face = Vec::with_capacity(args.len());
+                for arg in &args {
+                    let mut refs = arg.split('/');
+                    let v =
+                        resolve_index(refs.next().unwrap_or(""), positions.len()).map_err(err)?;
+                    let vt = match refs.next() {
+                        Some(s) if !s.is_empty() => Some(resolve_index(s, uvs.len()).map_err(err)?),
+                        _ => None,
+                    };
+                    let vn = match refs.next() {
+                        Some(s) if !s.is_empty() => {
+                            Some(resolve_index(s, normals.len()).map_err(err)?)
+                        }
+                        _ => None,
+                    };
+
+                    face.push(builder.vertex((v, vt, vn), &positions, &uvs, &normals));
+                }
+
+                for i in 1..face.len() - 1 {
+                    builder.part.indices.push([face[0], face[i], face[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let parts = order
+        .into_iter()
+        .map(|key| parts.remove(&key).unwrap().finish())
+        .collect();
+
+    Ok(ObjModel {
+        parts,
+        materials: HashMap::new(),
+        files: Vec::new(),
+    })
+}
+
+pub fn parse_mtl(contents: &str) -> Result<HashMap<String, MtlMaterial>, String> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (line_nr, line) in lines(contents) {
+        let mut tokens = line.split_whitespace();
+        let keyword = tokens.next().unwrap_or("");
+        let args: Vec<&str> = tokens.collect();
+        let err = |e: String| format!("{}: {}", line_nr, e);
+
+        if keyword == "newmtl" {
+            if let Some((name, mtl)) = current.take() {
+                materials.insert(name, mtl);
+            }
+            current = Some((args.join(" "), MtlMaterial::default()));
+            continue;
+        }
+
+        let mtl = match current.as_mut() {
+            Some((_, mtl)) => mtl,
+            None => continue,
+        };
+
+        match keyword {
+            "Kd" => mtl.kd = Some(Vec3::from(parse_floats::<3>(&args, 3).map_err(err)?)),
+            "Ks" => mtl.ks = Some(Vec3::from(parse_floats::<3>(&args, 3).map_err(err)?)),
+            "Ns" => mtl.ns = Some(parse_floats::<1>(&args, 1).map_err(err)?[0]),
+            "Ni" => mtl.ni = Some(parse_floats::<1>(&args, 1).map_err(err)?[0]),
+            "d" => mtl.d = Some(parse_floats::<1>(&args, 1).map_err(err)?[0]),
+            // Tr is the inverse of d
+            "Tr" => mtl.d = Some(1.0 - parse_floats::<1>(&args, 1).map_err(err)?[0]),
+            "illum" => {
+                let illum = args.first().and_then(|s| s.parse().ok());
+                mtl.illum = Some(illum.ok_or_else(|| err(String::from("bad illum")))?);
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((name, mtl)) = current.take() {
+        materials.insert(name, mtl);
+    }
+
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_triangulates_and_splits_parts() {
+        let obj = "
+            # a quad and a triangle in two groups
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            vn 0 0 2
+
+            g quad
+            usemtl red
+            f 1/1/1 2/2/1 3/3/1 4/4/1
+
+            g tri
+            f -4 -3 -1
+        ";
+
+        let model = parse(obj).unwrap();
+        assert_eq!(model.parts.len(), 2);
+
+        let quad = &model.parts[0];
+        assert_eq!(quad.group, "quad");
+        assert_eq!(quad.material.as_deref(), Some("red"));
+        assert_eq!(quad.indices, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(quad.uvs[2], Vec2::new(1.0, 1.0));
+        assert_eq!(quad.normals[0], Vec3::Z);
+
+        // the material carries over, the negative indices count from the end
+        let tri = &model.parts[1];
+        assert_eq!(tri.material.as_deref(), Some("red"));
+        assert_eq!(tri.positions, vec![Vec3::ZERO, Vec3::X, Vec3::Y]);
+        assert!(tri.normals.is_empty());
+        assert!(tri.uvs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors_point_to_the_line() {
+        let err = parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n").unwrap_err();
+        assert_eq!(err, "3: index 3 out of range");
+
+        let err = parse("v 0 zero 0\n").unwrap_err();
+        assert_eq!(err, "1: 'zero' is not a number");
+    }
+
+    #[test]
+    fn test_mtl_mapping() {
+        let mtl = "
+            newmtl matte
+            Kd 0.8 0.1 0.1
+            Ks 0 0 0
+
+            newmtl chrome
+            Kd 0.1 0.1 0.1
+            Ks 0.9 0.9 0.9
+            Ns 1000
+
+            newmtl glass
+            Kd 0 0 0
+            Ni 1.33
+            d 0.1
+            illum 7
+        ";
+
+        let materials = parse_mtl(mtl).unwrap();
+
+        match materials["matte"].to_material() {
+            Material::Lambertian(m) => assert_eq!(m.color.r, 0.8),
+            m => panic!("expected lambertian, got {:?}", m),
+        }
+
+        match materials["chrome"].to_material() {
+            Material::Metal(m) => assert!(m.fuzz < 0.1),
+            m => panic!("expected metal, got {:?}", m),
+        }
+
+        match materials["glass"].to_material() {
+            Material::Dielectric(m) => assert_eq!(m.refraction, 1.33),
+            m => panic!("expected dielectric, got {:?}", m),
+        }
+    }
+}
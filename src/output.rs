@@ -0,0 +1,326 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+/*
+    Image file writers. Only PNG goes through the png crate, the float formats are simple enough
+    to be written by hand:
+    - OpenEXR: single part scanline image, uncompressed 32 bit float channels
+    - Radiance HDR: RGBE, a shared exponent byte for three mantissa bytes, flat scanlines
+    - PFM: raw little endian floats with a text header, rows go from the bottom up
+    The float formats get the film as is, tone mapping only happens for PNG.
+*/
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Exr,
+    Hdr,
+    Pfm,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Result<Format, String> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        match extension.as_deref() {
+            Some("png") => Ok(Format::Png),
+            Some("exr") => Ok(Format::Exr),
+            Some("hdr") => Ok(Format::Hdr),
+            Some("pfm") => Ok(Format::Pfm),
+            _ => Err(format!(
+                "{}: unknown image format, use .png, .exr, .hdr or .pfm",
+                path
+            )),
+        }
+    }
+}
+
+fn create(path: &str) -> io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(File::create(path)?))
+}
+
+// 8 bit RGBA, rows from the top
+pub fn write_png(path: &str, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let w = create(path)?;
+
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_trns(vec![0xFFu8, 0xFFu8, 0xFFu8, 0xFFu8]);
+    encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455)); // 1.0 / 2.2, scaled by 100000
+    encoder.set_source_gamma(png::ScaledFloat::new(1.0 / 2.2)); // 1.0 / 2.2, unscaled, but rounded
+    let source_chromaticities = png::SourceChromaticities::new(
+        // Using unscaled instantiation here
+        (0.31270, 0.32900),
+        (0.64000, 0.33000),
+        (0.30000, 0.60000),
+        (0.15000, 0.06000),
+    );
+    encoder.set_source_chromaticities(source_chromaticities);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    Ok(())
+}
+
+// three floats per pixel, rows from the top
+pub fn write_pfm(path: &str, width: u32, height: u32, rgb: &[f32]) -> io::Result<()> {
+    let mut w = create(path)?;
+    encode_pfm(&mut w, width, height, rgb)?;
+    w.flush()
+}
+
+// negative scale means little endian
+fn encode_pfm(w: &mut impl Write, width: u32, height: u32, rgb: &[f32]) -> io::Result<()> {
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    let row = width as usize * 3;
+    for y in (0..height as usize).rev() {
+        for v in &rgb[y * row..(y + 1) * row] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+// three floats per pixel, rows from the top
+pub fn write_hdr(path: &str, width: u32, height: u32, rgb: &[f32]) -> io::Result<()> {
+    let mut w = create(path)?;
+    encode_hdr(&mut w, width, height, rgb)?;
+    w.flush()
+}
+
+fn encode_hdr(w: &mut impl Write, width: u32, height: u32, rgb: &[f32]) -> io::Result<()> {
+    write!(
+        w,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+
+    for pixel in rgb.chunks_exact(3) {
+        w.write_all(&rgbe(pixel[0], pixel[1], pixel[2]))?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Shared exponent encoding, the exponent is the one of the largest component and all three
+ * mantissas are stored as 8 bits relative to it. Values of 2^e * [0.5;1) map onto [128;256)
+ */
+fn rgbe(r: f32, g: f32, b: f32) -> [u8; 4] {
+    let (r, g, b) = (r.max(0.0), g.max(0.0), b.max(0.0));
+    let v = r.max(g).max(b);
+    if v < 1e-32 || !v.is_finite() {
+        return [0, 0, 0, 0];
+    }
+
+    // v = m * 2^e with m in [0.5;1)
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2.0_f32.powi(e);
+
+    [
+        (r * scale).min(255.0) as u8,
+        (g * scale).min(255.0) as u8,
+        (b * scale).min(255.0) as u8,
+        (e + 128) as u8,
+    ]
+}
+
+/**
+ * Any number of float channels, each one value per pixel with rows from the top. Channel names
+ * follow the EXR conventions: R, G, B for color and "layer.X" for other layers
+ */
+pub fn write_exr(
+    path: &str,
+    width: u32,
+    height: u32,
+    channels: &[(&str, &[f32])],
+) -> io::Result<()> {
+    let mut w = create(path)?;
+    encode_exr(&mut w, width, height, channels)?;
+    w.flush()
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+fn encode_exr(
+    w: &mut impl Write,
+    width: u32,
+    height: u32,
+    channels: &[(&str, &[f32])],
+) -> io::Result<()> {
+    // readers expect the channels sorted by name
+    let mut channels = channels.to_vec();
+    channels.sort_by(|a, b| a.0.cmp(b.0));
+
+    // magic number and version 2, single part scanline file
+    let mut header: Vec<u8> = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    let mut chlist = Vec::new();
+    for (name, _) in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        // pixel type FLOAT, not linear, 3 reserved bytes, no subsampling
+        chlist.extend_from_slice(&2_i32.to_le_bytes());
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1_i32.to_le_bytes());
+        chlist.extend_from_slice(&1_i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    let mut window = Vec::new();
+    for v in [0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    attribute(&mut header, "channels", "chlist", &chlist);
+    // no compression
+    attribute(&mut header, "compression", "compression", &[0]);
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    // increasing y
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    // every scanline is its own block, the offset table points at each of them
+    let line_size = width as usize * channels.len() * 4;
+    let block_size = 8 + line_size;
+    let first_block = header.len() + height as usize * 8;
+
+    w.write_all(&header)?;
+    for y in 0..height as usize {
+        w.write_all(&((first_block + y * block_size) as u64).to_le_bytes())?;
+    }
+
+    for y in 0..height as usize {
+        w.write_all(&(y as i32).to_le_bytes())?;
+        w.write_all(&(line_size as i32).to_le_bytes())?;
+
+        for (_, data) in &channels {
+            let row = &data[y * width as usize..(y + 1) * width as usize];
+            for v in row {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(Format::from_path("out/a.png"), Ok(Format::Png));
+        assert_eq!(Format::from_path("a.EXR"), Ok(Format::Exr));
+        assert_eq!(Format::from_path("a.hdr"), Ok(Format::Hdr));
+        assert_eq!(Format::from_path("a.pfm"), Ok(Format::Pfm));
+        assert!(Format::from_path("a.jpg").is_err());
+        assert!(Format::from_path("a").is_err());
+    }
+
+    #[test]
+    fn test_pfm_goes_bottom_up() {
+        // 1x2 image, top pixel is bright
+        let rgb = [10.0, 20.0, 30.0, 0.5, 0.25, 0.125];
+        let mut bytes = Vec::new();
+        encode_pfm(&mut bytes, 1, 2, &rgb).unwrap();
+
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + 6 * 4);
+        assert_eq!(f32_at(&bytes, header.len()), 0.5);
+        assert_eq!(f32_at(&bytes, header.len() + 12), 10.0);
+    }
+
+    #[test]
+    fn test_rgbe() {
+        assert_eq!(rgbe(1.0, 1.0, 1.0), [128, 128, 128, 129]);
+        assert_eq!(rgbe(0.0, 0.0, 0.0), [0, 0, 0, 0]);
+
+        // values above 1 are kept, decoding gives back roughly the same
+        let [r, g, b, e] = rgbe(1000.0, 10.0, 0.5);
+        let decode = |m: u8| (m as f32 + 0.5) * 2.0_f32.powi(e as i32 - 136);
+        assert!((decode(r) - 1000.0).abs() / 1000.0 < 0.01);
+        assert!((decode(g) - 10.0).abs() < 2.0);
+        assert_eq!(b, 0);
+    }
+
+    #[test]
+    fn test_exr_layout() {
+        let (width, height) = (3, 2);
+        let r: Vec<f32> = (0..6).map(|i| i as f32).collect();
+        let g = vec![0.5; 6];
+        let b = vec![100.0; 6];
+
+        let mut bytes = Vec::new();
+        encode_exr(
+            &mut bytes,
+            width,
+            height,
+            &[("R", &r), ("G", &g), ("B", &b)],
+        )
+        .unwrap();
+        assert_eq!(&bytes[..4], &[0x76, 0x2f, 0x31, 0x01]);
+
+        // the header ends with an empty attribute name right before the offset table
+        let header_end = bytes
+            .windows(b"screenWindowWidth".len())
+            .position(|w| w == b"screenWindowWidth")
+            .unwrap()
+            + b"screenWindowWidth\0float\0".len()
+            + 4
+            + 4;
+        assert_eq!(bytes[header_end], 0);
+
+        for y in 0..height as usize {
+            let at = header_end + 1 + y * 8;
+            let offset = u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+
+            assert_eq!(
+                i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()),
+                y as i32
+            );
+
+            // channels come sorted: B, G, R
+            let data = offset + 8;
+            assert_eq!(f32_at(&bytes, data), 100.0);
+            assert_eq!(f32_at(&bytes, data + 3 * 4), 0.5);
+            assert_eq!(f32_at(&bytes, data + 6 * 4 + 4), r[y * 3 + 1]);
+        }
+    }
+}
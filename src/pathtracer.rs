@@ -0,0 +1,261 @@
+use crate::loader;
+use crate::material::Color;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::rng::Pcg32;
+use crate::sampling;
+use crate::scene::collide;
+use crate::scene::random_point_in_circle;
+use crate::scene::reflect;
+use crate::scene::scatter_dielectric;
+use crate::scene::Media;
+use crate::scene::Radiance;
+use crate::scene::Scene;
+
+use glam::Vec3;
+use rand::prelude::*;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+// hard limit on the path length, Russian roulette ends nearly all paths long before it
+const MAX_DEPTH: u32 = 64;
+
+// bounces before Russian roulette kicks in, the first few carry most of the light
+const MIN_DEPTH: u32 = 3;
+
+// even the brightest paths get a chance to end
+const MAX_SURVIVAL: f32 = 0.95;
+
+/**
+ * Unidirectional path tracer. The ray bounces around the scene, at every bounce the material
+ * picks the next direction and the throughput keeps track of how much of the light found further
+ * down the path makes it back to the camera:
+ * - Lambertian surfaces bounce in a cosine weighted direction, with the BRDF being albedo / PI
+ *   the cosine and the density cancel out and the throughput is just multiplied by the albedo.
+ *   On top of that lights are sampled directly (next event estimation) as randomly bouncing into
+ *   a small light almost never happens
+ * - metal reflects around the normal with some fuzz, glass reflects or refracts depending on
+ *   Fresnel, neither can make use of direct light sampling so lights are counted when hit by them
+ *
+ * Paths are ended by Russian roulette: after a few bounces a path survives with the probability of
+ * its throughput and the survivors are weighted up, which keeps the estimate unbiased.
+ * Light found at the first surface is the direct part of the result, anything after is indirect
+ */
+pub fn path_color(r: &Ray, scn: &Scene, light_samples: u32, rng: &mut Pcg32) -> Radiance {
+    let mut ray = *r;
+    let mut radiance = Radiance::default();
+    let mut throughput = Color::white();
+
+    let add = |radiance: &mut Radiance, depth: u32, light: Color| {
+        if depth == 0 {
+            radiance.direct = radiance.direct + light;
+        } else {
+            radiance.indirect = radiance.indirect + light;
+        }
+    };
+
+    // after a diffuse bounce the light was already counted by next event estimation
+    let mut count_emission = true;
+    let mut media = Media::default();
+
+    for depth in 0..MAX_DEPTH {
+        // nothing out there, the background is black same as for the Whitted integrator
+        let (hit, obj) = match collide(&ray, scn) {
+            Some(collision) => collision,
+            None => break,
+        };
+
+        if let loader::Kind::Light { .. } = obj.kind {
+            if count_emission {
+                add(&mut radiance, depth, throughput * obj.emission());
+            }
+            break;
+        }
+
+        let dir = match obj.mat {
+            Material::Lambertian(m) => {
+                let albedo = m.color * m.albedo;
+                let direct = direct_light(hit.point, hit.normal, scn, light_samples, rng);
+
+                add(&mut radiance, depth, throughput * albedo * direct / PI);
+                throughput = throughput * albedo;
+                count_emission = false;
+
+                sampling::cosine_hemisphere(hit.normal, rng)
+            }
+            Material::Metal(m) => {
+                let dir = reflect(&ray.dir, &hit.normal) + random_point_in_circle(rng) * m.fuzz;
+
+                // fuzz can push the reflection under the surface, it gets absorbed then
+                if dir.dot(hit.normal) <= 0.0 {
+                    break;
+                }
+
+                throughput = throughput * m.color * m.albedo;
+                count_emission = true;
+
+                dir
+            }
+            Material::Dielectric(m) => {
+                throughput = throughput * m.color;
+                count_emission = true;
+
+                scatter_dielectric(
+                    &ray.dir,
+                    &hit.normal,
+                    &hit.face,
+                    m.refraction,
+                    &mut media,
+                    rng,
+                )
+            }
+        };
+
+        if depth >= MIN_DEPTH {
+            let survival = throughput
+                .r
+                .max(throughput.g)
+                .max(throughput.b)
+                .min(MAX_SURVIVAL);
+
+            if rng.gen::<f32>() >= survival {
+                break;
+            }
+            throughput = throughput / survival;
+        }
+
+        ray = Ray::new(hit.point, dir);
+    }
+
+    radiance
+}
+
+/**
+ * Light arriving at the point directly from the light sources, weighted by the cosine to the
+ * normal. Every light gets its own samples: a direction towards it is picked, if nothing is in the
+ * way its emission is divided by the density of picking that direction
+ */
+fn direct_light(point: Vec3, normal: Vec3, scn: &Scene, samples: u32, rng: &mut Pcg32) -> Color {
+    let samples = samples.max(1);
+    let mut total = Color::default();
+
+    for light in scn.lights() {
+        let mut sum = Color::default();
+
+        for _ in 0..samples {
+            let (dir, pdf) = match light.geometry.sample_direction(point, rng) {
+                Some(sample) => sample,
+                None => continue,
+            };
+
+            let cos = normal.dot(dir);
+            if cos <= 0.0 || pdf <= 0.0 {
+                continue;
+            }
+
+            // unlike for the Whitted integrator glass blocks the shadow ray, the light getting
+            // through it is found by the paths refracting through it
+            if let Some((_, blocker)) = collide(&Ray::new(point, dir), scn) {
+                if Arc::ptr_eq(&blocker.geometry, &light.geometry) {
+                    sum = sum + light.emission() * (cos / pdf);
+                }
+            }
+        }
+
+        total = total + sum / samples as f32;
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material;
+    use crate::sphere::Sphere;
+
+    fn lambertian(albedo: f32) -> Material {
+        Material::Lambertian(material::Lambertian {
+            color: Color::white(),
+            albedo,
+        })
+    }
+
+    fn mean(r: &Ray, scn: &Scene, paths: u32, part: fn(&Radiance) -> Color) -> f32 {
+        let total = (0..paths)
+            .map(|i| part(&path_color(r, scn, 1, &mut Pcg32::new(i as u64, 0))))
+            .fold(Color::default(), |acc, c| acc + c);
+
+        (total.r + total.g + total.b) / (3 * paths) as f32
+    }
+
+    fn mean_radiance(r: &Ray, scn: &Scene, paths: u32) -> f32 {
+        mean(r, scn, paths, Radiance::total)
+    }
+
+    #[test]
+    fn test_indirect_light() {
+        // the side of the ball facing the camera is turned away from the light
+        let mut scene = Scene::new(1, 1);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, 1.0)), lambertian(1.0));
+        let light = Sphere::new(Vec3::new(0.0, 0.0, -10.0), 1.0);
+        scene.add_light(Arc::new(light), lambertian(1.0), 100.0);
+        scene.finalize();
+
+        let r = Ray::new(Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(mean_radiance(&r, &scene, 1000), 0.0);
+
+        // until there's a wall behind the camera to bounce the light back
+        let mut scene = Scene::new(1, 1);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, 1.0)), lambertian(1.0));
+        let wall = Sphere::new(Vec3::new(0.0, 0.0, 8.0), 4.0);
+        scene.add_object(Arc::new(wall), lambertian(1.0));
+        let light = Sphere::new(Vec3::new(0.0, 0.0, -10.0), 1.0);
+        scene.add_light(Arc::new(light), lambertian(1.0), 100.0);
+        scene.finalize();
+
+        assert!(mean_radiance(&r, &scene, 1000) > 0.01);
+    }
+
+    #[test]
+    fn test_light_inside_a_white_room() {
+        // inside a sphere every point of the wall sees every other one the same, so the light
+        // bouncing around is spread evenly and the radiance of the wall works out to be
+        // albedo * emission * (r / R)^2 / (1 - albedo * (1 - (r / R)^2))
+        // where the last term accounts for the light blocking the view of the wall behind it
+        let albedo = 0.8;
+        let emission = 50.0;
+        let (r, big_r) = (1.0_f32, 10.0_f32);
+
+        let mut scene = Scene::new(1, 1);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, big_r)), lambertian(albedo));
+        scene.add_light(
+            Arc::new(Sphere::new(Vec3::ZERO, r)),
+            lambertian(1.0),
+            emission,
+        );
+        scene.finalize();
+
+        let ratio = (r / big_r).powi(2);
+        let expected = albedo * emission * ratio / (1.0 - albedo * (1.0 - ratio));
+
+        let ray = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(1.0, 0.2, 0.3));
+        let radiance = mean_radiance(&ray, &scene, 20000);
+        assert!(
+            (radiance - expected).abs() < expected * 0.05,
+            "expected {}, got {}",
+            expected,
+            radiance
+        );
+
+        // the first term of the sum is the light coming straight from the light source
+        let expected = albedo * emission * ratio;
+        let direct = mean(&ray, &scene, 20000, |r| r.direct);
+        assert!(
+            (direct - expected).abs() < expected * 0.05,
+            "expected {}, got {}",
+            expected,
+            direct
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use crate::aabb::Aabb;
+use crate::frame::Frame;
+use crate::ray::Ray;
+use crate::rng::Pcg32;
+use crate::sampling;
+use crate::scene::CollisionData;
+use crate::scene::Hitable;
+
+use glam::Vec2;
+use glam::Vec3;
+use rand::prelude::*;
+use std::f32::consts::PI;
+
+/*
+    Flat shapes: the infinite plane, the disk and the quad. All of them are intersected with the
+    plane they lie in first and then checked for whether the point is within the shape. They
+    have no inside, the front face is the side the normal points to.
+*/
+
+const THRESHOLD: f32 = 0.001;
+
+// rays this close to parallel with the plane are considered to miss it
+const PARALLEL_EPSILON: f32 = 1e-8;
+
+// the BVH needs finite boxes, a plane's is cut off this far from its point. Further than anything
+// in a scene is from the camera
+const PLANE_EXTENT: f32 = 1.0e6;
+
+// distance to where the ray crosses the y = 0 plane of the frame, for a ray given in the frame
+fn hit_plane(local: &Ray) -> Option<f32> {
+    if local.dir.y.abs() < PARALLEL_EPSILON {
+        return None;
+    }
+
+    let t = -local.origin.y / local.dir.y;
+    if t <= THRESHOLD {
+        return None;
+    }
+    Some(t)
+}
+
+/**
+ * Direction towards the point on a flat shape, picked uniformly over its area. Like for
+ * triangles the density per solid angle is distance^2 / (cos * area)
+ */
+fn sample_area(point: Vec3, normal: Vec3, area: f32, from: Vec3) -> Option<(Vec3, f32)> {
+    let to_point = point - from;
+    let distance_squared = to_point.length_squared();
+    let dir = to_point.normalize();
+
+    let cos = normal.dot(dir).abs();
+    if cos < PARALLEL_EPSILON || area <= 0.0 {
+        return None;
+    }
+
+    Some((dir, distance_squared / (cos * area)))
+}
+
+/**
+ * Infinite plane through the point. UVs repeat every unit along the two axes of the plane, good
+ * for a checkerboard floor
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Plane {
+    frame: Frame,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3) -> Plane {
+        Plane {
+            frame: Frame::around(point, normal.normalize()),
+        }
+    }
+
+    fn normal(&self) -> Vec3 {
+        self.frame.rotation.y_axis
+    }
+}
+
+impl Hitable for Plane {
+    // there's no area to pick from, points come from the unit square around the point
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        self.frame
+            .world_direction(Vec3::new(u.x - 0.5, 0.0, u.y - 0.5))
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.frame.origin
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.frame.bounding_box(
+            Vec3::new(-PLANE_EXTENT, 0.0, -PLANE_EXTENT),
+            Vec3::new(PLANE_EXTENT, 0.0, PLANE_EXTENT),
+        )
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let local = self.frame.local_ray(r);
+        let t = hit_plane(&local)?;
+        let p = local.at(t);
+
+        let uv = Vec2::new(p.x.rem_euclid(1.0), p.z.rem_euclid(1.0));
+        Some(CollisionData::new(r, t, self.normal(), uv))
+    }
+}
+
+/**
+ * Round and flat, facing where the normal points. u goes around the center, v from the center
+ * out to the rim
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Disk {
+    frame: Frame,
+    radius: f32,
+}
+
+impl Disk {
+    pub fn new(center: Vec3, normal: Vec3, radius: f32) -> Disk {
+        Disk {
+            frame: Frame::around(center, normal.normalize()),
+            radius,
+        }
+    }
+
+    fn normal(&self) -> Vec3 {
+        self.frame.rotation.y_axis
+    }
+
+    // relative to the center, uniform over the area when u is uniform
+    fn point(&self, u: Vec2) -> Vec3 {
+        let p = sampling::concentric_disk(u) * self.radius;
+        self.frame.world_direction(Vec3::new(p.x, 0.0, p.y))
+    }
+}
+
+impl Hitable for Disk {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        self.point(u.truncate())
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.frame.origin
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = self.radius;
+        self.frame
+            .bounding_box(Vec3::new(-r, 0.0, -r), Vec3::new(r, 0.0, r))
+    }
+
+    fn sample_direction(&self, from: Vec3, rng: &mut Pcg32) -> Option<(Vec3, f32)> {
+        let point = self.pos() + self.point(Vec2::new(rng.gen(), rng.gen()));
+        sample_area(point, self.normal(), PI * self.radius * self.radius, from)
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let local = self.frame.local_ray(r);
+        let t = hit_plane(&local)?;
+        let p = local.at(t);
+
+        let distance = (p.x * p.x + p.z * p.z).sqrt();
+        if distance > self.radius {
+            return None;
+        }
+
+        let uv = Vec2::new((p.z.atan2(p.x) + PI) / (2.0 * PI), distance / self.radius);
+        Some(CollisionData::new(r, t, self.normal(), uv))
+    }
+}
+
+/**
+ * Parallelogram spanned by the two edges from the corner, a rectangle when they're
+ * perpendicular. The front faces the side u x v points to, UVs go from 0 to 1 along the edges
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Quad {
+    corner: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    // turns the offset from the corner into coordinates along the edges, see hit
+    w: Vec3,
+}
+
+impl Quad {
+    pub fn new(corner: Vec3, u: Vec3, v: Vec3) -> Quad {
+        let n = u.cross(v);
+
+        Quad {
+            corner,
+            u,
+            v,
+            normal: n.normalize(),
+            w: n / n.dot(n),
+        }
+    }
+
+    fn area(&self) -> f32 {
+        self.u.cross(self.v).length()
+    }
+}
+
+impl Hitable for Quad {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        self.u * (u.x - 0.5) + self.v * (u.y - 0.5)
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.corner + (self.u + self.v) * 0.5
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::empty()
+            .grow(self.corner)
+            .grow(self.corner + self.u)
+            .grow(self.corner + self.v)
+            .grow(self.corner + self.u + self.v)
+    }
+
+    fn sample_direction(&self, from: Vec3, rng: &mut Pcg32) -> Option<(Vec3, f32)> {
+        let point = self.corner + self.u * rng.gen::<f32>() + self.v * rng.gen::<f32>();
+        sample_area(point, self.normal, self.area(), from)
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let denominator = self.normal.dot(r.dir);
+        if denominator.abs() < PARALLEL_EPSILON {
+            return None;
+        }
+
+        let t = self.normal.dot(self.corner - r.origin) / denominator;
+        if t <= THRESHOLD {
+            return None;
+        }
+
+        // the offset is a * u + b * v, crossing with v and u leaves a and b on their own
+        let offset = r.at(t) - self.corner;
+        let a = self.w.dot(offset.cross(self.v));
+        let b = self.w.dot(self.u.cross(offset));
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return None;
+        }
+
+        Some(CollisionData::new(r, t, self.normal, Vec2::new(a, b)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Face;
+
+    #[test]
+    fn test_plane() {
+        let plane = Plane::new(Vec3::new(0.0, -1.0, 0.0), Vec3::Y);
+
+        let hit = plane
+            .hit(&Ray::new(
+                Vec3::new(3.0, 2.0, 0.0),
+                Vec3::new(0.0, -1.0, 0.0),
+            ))
+            .unwrap();
+        assert!(hit.point.distance(Vec3::new(3.0, -1.0, 0.0)) < 1.0e-5);
+        assert_eq!(hit.normal, Vec3::Y);
+        assert!(matches!(hit.face, Face::Front));
+        assert!((0.0..1.0).contains(&hit.uv.x) && (0.0..1.0).contains(&hit.uv.y));
+
+        let below = plane
+            .hit(&Ray::new(
+                Vec3::new(0.0, -5.0, 0.0),
+                Vec3::new(1.0, 1.0, 0.0),
+            ))
+            .unwrap();
+        assert!(matches!(below.face, Face::Back));
+        assert_eq!(below.normal, -Vec3::Y);
+
+        assert!(plane
+            .hit(&Ray::new(Vec3::ZERO, Vec3::new(1.0, 0.0, 1.0)))
+            .is_none());
+        assert!(plane.hit(&Ray::new(Vec3::ZERO, Vec3::Y)).is_none());
+        assert!(plane.bounding_box().extent().y < 1.0e-3);
+    }
+
+    #[test]
+    fn test_disk() {
+        let disk = Disk::new(Vec3::new(0.0, 0.0, -2.0), Vec3::Z, 1.0);
+        let towards = Vec3::new(0.0, 0.0, -1.0);
+
+        let hit = disk
+            .hit(&Ray::new(Vec3::new(0.5, 0.0, 0.0), towards))
+            .unwrap();
+        assert!(hit.point.distance(Vec3::new(0.5, 0.0, -2.0)) < 1.0e-5);
+        assert!(matches!(hit.face, Face::Front));
+        assert!((hit.uv.y - 0.5).abs() < 1.0e-5);
+
+        assert!(disk
+            .hit(&Ray::new(Vec3::new(0.8, 0.8, 0.0), towards))
+            .is_none());
+
+        for i in 0..100 {
+            let u = Vec3::new(i as f32 / 100.0, (i * 37 % 100) as f32 / 100.0, 0.5);
+            let p = disk.get_random_point(u);
+            assert!(p.length() <= 1.0 + 1.0e-5 && p.z.abs() < 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn test_quad() {
+        // 2 by 1 in the xz plane facing up
+        let quad = Quad::new(
+            Vec3::ZERO,
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+        );
+        assert!(quad.normal.distance(Vec3::Y) < 1.0e-6);
+        assert_eq!(quad.area(), 2.0);
+
+        let hit = quad
+            .hit(&Ray::new(Vec3::new(1.5, 1.0, 0.25), -Vec3::Y))
+            .unwrap();
+        assert!(hit.uv.distance(Vec2::new(0.25, 0.75)) < 1.0e-5);
+        assert!(matches!(hit.face, Face::Front));
+
+        assert!(quad
+            .hit(&Ray::new(Vec3::new(2.5, 1.0, 0.25), -Vec3::Y))
+            .is_none());
+        let from_below = quad
+            .hit(&Ray::new(Vec3::new(0.5, -1.0, 0.5), Vec3::Y))
+            .unwrap();
+        assert!(matches!(from_below.face, Face::Back));
+
+        // shadow rays towards it have to find it
+        let mut rng = Pcg32::new(1, 2);
+        let from = Vec3::new(1.0, 3.0, 0.5);
+        for _ in 0..50 {
+            let (dir, pdf) = quad.sample_direction(from, &mut rng).unwrap();
+            assert!(pdf > 0.0);
+            assert!(quad.hit(&Ray::new(from, dir)).is_some());
+        }
+    }
+}
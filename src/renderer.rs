@@ -1,63 +1,253 @@
+use crate::aov;
+use crate::aov::Aov;
+use crate::aov::AovAccumulator;
+use crate::aov::AovPixel;
+use crate::checkpoint::Checkpoint;
+use crate::checkpoint::Header;
+use crate::denoise;
+use crate::filter::Splat;
+use crate::filter::Tile;
 use crate::material::Color;
+use crate::output;
+use crate::output::Format;
+use crate::scene::Pixel;
+use crate::scene::Totals;
+use crate::tonemap::ToneMapping;
 
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
+use std::io;
 
+/**
+ * The film, keeps the rendered colors as floats without any clamping. The range is only squashed
+ * when saving to an 8 bit format. Pixels are kept as sums of their samples so that progressive
+ * passes can keep adding to them. The totals count the samples taken in the pixel, for adaptive
+ * sampling and the noise estimates, while the colors come from the filtered splats
+ */
 pub struct Renderer {
     width: u32,
     height: u32,
-    buffer: Vec<u8>,
+    film: Vec<Totals>,
+    splats: Vec<Splat>,
+    aov_film: Vec<AovAccumulator>,
+    // the ones saved alongside the image
+    aovs: Vec<Aov>,
+    // replaces the film's colors when saving, until more samples come in
+    denoised: Option<Vec<Color>>,
+    // samples per pixel of the last finished pass, less where adaptive sampling stopped early
+    goal: u32,
 }
 
 impl Renderer {
     pub fn new(width: u32, height: u32) -> Renderer {
-        let len = (width * height * 4) as usize;
-        let mut data = Vec::with_capacity(len);
-        for _i in 0..len as usize {
-            data.push(0);
-        }
-
         Renderer {
             width,
             height,
-            buffer: data,
+            film: vec![Totals::default(); (width * height) as usize],
+            splats: vec![Splat::default(); (width * height) as usize],
+            aov_film: vec![AovAccumulator::default(); (width * height) as usize],
+            aovs: Vec::new(),
+            denoised: None,
+            goal: 0,
+        }
+    }
+
+    pub fn with_aovs(mut self, aovs: Vec<Aov>) -> Renderer {
+        self.aovs = aovs;
+        self
+    }
+
+    // adds the samples of the pixel to what the film has there
+    pub fn add(&mut self, pixel: &Pixel) {
+        let i = (pixel.y * self.width as u64 + pixel.x) as usize;
+        self.film[i].merge(&pixel.totals);
+        self.aov_film[i].merge(&pixel.aov);
+        self.denoised = None;
+    }
+
+    // adds the samples the filter spread over the tile
+    pub fn add_splats(&mut self, tile: &Tile) {
+        for row in 0..tile.height {
+            let start = ((tile.y + row) * self.width + tile.x) as usize;
+            let splats =
+                &tile.splats[(row * tile.width) as usize..((row + 1) * tile.width) as usize];
+            for (film, splat) in self.splats[start..].iter_mut().zip(splats) {
+                film.merge(splat);
+            }
+        }
+        self.denoised = None;
+    }
+
+    pub fn goal(&self) -> u32 {
+        self.goal
+    }
+
+    pub fn set_goal(&mut self, goal: u32) {
+        self.goal = goal;
+    }
+
+    pub fn checkpoint(&self, header: Header) -> Checkpoint {
+        let aov_film = if header.settings.aovs {
+            self.aov_film.clone()
+        } else {
+            Vec::new()
+        };
+
+        Checkpoint {
+            header,
+            film: self.film.clone(),
+            splats: self.splats.clone(),
+            aov_film,
+        }
+    }
+
+    // continues from the checkpoint, which has to be the size of the film
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        self.film = checkpoint.film;
+        self.splats = checkpoint.splats;
+        if !checkpoint.aov_film.is_empty() {
+            self.aov_film = checkpoint.aov_film;
+        }
+        self.goal = checkpoint.header.goal;
+        self.denoised = None;
+    }
+
+    // fewest, average and most samples a pixel got
+    pub fn samples_per_pixel(&self) -> (u32, f32, u32) {
+        let samples = self.film.iter().map(|t| t.samples);
+        let total: u64 = samples.clone().map(|s| s as u64).sum();
+
+        (
+            samples.clone().min().unwrap_or(0),
+            total as f32 / self.film.len().max(1) as f32,
+            samples.max().unwrap_or(0),
+        )
+    }
+
+    pub fn totals(&self) -> Vec<Totals> {
+        self.film.clone()
+    }
+
+    // the current estimate of every pixel
+    pub fn colors(&self) -> Vec<Color> {
+        match &self.denoised {
+            Some(denoised) => denoised.clone(),
+            None => self.filtered(),
+        }
+    }
+
+    // a pixel no sample reached with any weight, only possible with the sharpening filters, gets
+    // the plain average of its own samples
+    fn filtered(&self) -> Vec<Color> {
+        self.splats
+            .iter()
+            .zip(&self.film)
+            .map(|(splat, totals)| splat.color().unwrap_or_else(|| totals.mean()))
+            .collect()
+    }
+
+    fn aov_buffer(&self) -> Vec<AovPixel> {
+        self.aov_film
+            .iter()
+            .zip(&self.film)
+            .map(|(aov, totals)| AovPixel {
+                samples: totals.samples,
+                ..aov.finish()
+            })
+            .collect()
+    }
+
+    // needs the AOVs to have been rendered, they guide the filter
+    pub fn denoise(&mut self) {
+        let color = self.filtered();
+        let variance: Vec<f32> = self.film.iter().map(|t| t.variance()).collect();
+
+        self.denoised = Some(denoise::denoise(
+            self.width,
+            self.height,
+            &color,
+            &variance,
+            &self.aov_buffer(),
+        ));
+    }
+
+    fn rgb(&self) -> Vec<f32> {
+        self.colors().iter().flat_map(|c| [c.r, c.g, c.b]).collect()
+    }
+
+    fn rgba8(&self, tone_mapping: &ToneMapping) -> Vec<u8> {
+        self.colors()
+            .iter()
+            .flat_map(|c| {
+                let [r, g, b] = tone_mapping.to_srgb8(*c);
+                [r, g, b, 255]
+            })
+            .collect()
+    }
+
+    /**
+     * The format is picked by the extension of the path, tone mapping is only used for PNG. AOVs
+     * become layers of an EXR, for the other formats each gets a file of its own next to the image
+     */
+    pub fn save(&self, path: &str, tone_mapping: &ToneMapping) -> io::Result<()> {
+        let format =
+            Format::from_path(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let (w, h) = (self.width, self.height);
+        let aov_buffer = self.aov_buffer();
+
+        match format {
+            Format::Png => {
+                output::write_png(path, w, h, &self.rgba8(tone_mapping))?;
+                for &a in &self.aovs {
+                    let rgba = aov::rgba8(a, &aov_buffer, tone_mapping);
+                    output::write_png(&aov::sidecar_path(path, a), w, h, &rgba)?;
+                }
+                Ok(())
+            }
+            Format::Pfm => {
+                output::write_pfm(path, w, h, &self.rgb())?;
+                for &a in &self.aovs {
+                    let rgb = aov::rgb(a, &aov_buffer);
+                    output::write_pfm(&aov::sidecar_path(path, a), w, h, &rgb)?;
+                }
+                Ok(())
+            }
+            Format::Hdr => {
+                output::write_hdr(path, w, h, &self.rgb())?;
+                for &a in &self.aovs {
+                    let rgb = aov::rgb(a, &aov_buffer);
+                    output::write_hdr(&aov::sidecar_path(path, a), w, h, &rgb)?;
+                }
+                Ok(())
+            }
+            Format::Exr => {
+                let channels = self.exr_channels();
+                let channels: Vec<(&str, &[f32])> = channels
+                    .iter()
+                    .map(|(name, values)| (name.as_str(), values.as_slice()))
+                    .collect();
+
+                output::write_exr(path, w, h, &channels)
+            }
         }
     }
 
-    pub fn putpixel(&mut self, x: u32, y: u32, color: Color) {
-        let base = ((y * self.width + x) * 4) as usize;
+    // beauty as R, G and B, AOVs as "layer.channel"
+    fn exr_channels(&self) -> Vec<(String, Vec<f32>)> {
+        let colors = self.colors();
+        let aov_buffer = self.aov_buffer();
+        let channel = |f: fn(&Color) -> f32| -> Vec<f32> { colors.iter().map(f).collect() };
+        let mut channels = vec![
+            ("R".to_string(), channel(|c| c.r)),
+            ("G".to_string(), channel(|c| c.g)),
+            ("B".to_string(), channel(|c| c.b)),
+        ];
 
-        fn gamma(i: f32) -> f32 {
-            i.sqrt()
+        for &a in &self.aovs {
+            for (i, name) in a.channels().iter().enumerate() {
+                let values = aov_buffer.iter().map(|p| a.values(p)[i]).collect();
+                channels.push((format!("{}.{}", a.name(), name), values));
+            }
         }
 
-        self.buffer[base + 0] = (gamma(color.r) * 255.0) as u8;
-        self.buffer[base + 1] = (gamma(color.g) * 255.0) as u8;
-        self.buffer[base + 2] = (gamma(color.b) * 255.0) as u8;
-        self.buffer[base + 3] = 255;
-    }
-
-    pub fn save(&self, path: &str) {
-        let path = Path::new(path);
-        let file = File::create(path).unwrap();
-        let w = &mut BufWriter::new(file);
-
-        let mut encoder = png::Encoder::new(w, self.width, self.height);
-        encoder.set_color(png::ColorType::Rgba);
-        encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_trns(vec![0xFFu8, 0xFFu8, 0xFFu8, 0xFFu8]);
-        encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455)); // 1.0 / 2.2, scaled by 100000
-        encoder.set_source_gamma(png::ScaledFloat::new(1.0 / 2.2)); // 1.0 / 2.2, unscaled, but rounded
-        let source_chromaticities = png::SourceChromaticities::new(
-            // Using unscaled instantiation here
-            (0.31270, 0.32900),
-            (0.64000, 0.33000),
-            (0.30000, 0.60000),
-            (0.15000, 0.06000),
-        );
-        encoder.set_source_chromaticities(source_chromaticities);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&self.buffer).unwrap();
+        channels
     }
 }
@@ -0,0 +1,128 @@
+use rand::RngCore;
+
+/*
+    Random numbers of the renderer. Every sample of every pixel gets a generator of its own,
+    keyed by the seed of the render, the pixel and the index of the sample within the pixel, and
+    passed down to everything that needs randomness for it: the position in the pixel and on the
+    lens, shadow rays and the bounces. What a sample computes thus doesn't depend on the thread
+    it runs on or on the order of the tiles, and a render with a given seed comes out the same
+    bit for bit. Sample indices carry on over passes and checkpoints so that no sample repeats.
+    The generator is PCG32 (O'Neill 2014): small, fast and statistically good, with a 64 bit state
+    and a selectable stream.
+*/
+
+const MULTIPLIER: u64 = 6364136223846793005;
+
+#[derive(Clone, Debug)]
+pub struct Pcg32 {
+    state: u64,
+    // odd, selects one of 2^63 streams
+    increment: u64,
+}
+
+impl Pcg32 {
+    // the seeding of the reference implementation, pcg32_srandom
+    pub fn new(seed: u64, stream: u64) -> Pcg32 {
+        let mut rng = Pcg32 {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.step();
+
+        rng
+    }
+
+    // generator of the sample of the pixel, see the top of the file
+    pub fn for_sample(seed: u64, pixel: u64, sample: u32) -> Pcg32 {
+        let key = mix(mix(seed ^ mix(pixel)) ^ sample as u64);
+        Pcg32::new(key, pixel)
+    }
+
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(MULTIPLIER)
+            .wrapping_add(self.increment);
+    }
+}
+
+// SplitMix64 finalizer, keys that differ in a single bit end up unrelated
+pub fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// so that rand's Rng methods like gen and gen_range work on it
+impl RngCore for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_matches_the_reference() {
+        // the output of pcg32-demo from the reference C implementation
+        let mut rng = Pcg32::new(42, 54);
+        let expected = [
+            0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e,
+        ];
+
+        for value in expected {
+            assert_eq!(rng.next_u32(), value);
+        }
+    }
+
+    #[test]
+    fn test_samples_get_different_numbers() {
+        let first = |seed, pixel, sample| Pcg32::for_sample(seed, pixel, sample).next_u32();
+
+        assert_eq!(first(1, 2, 3), first(1, 2, 3));
+        assert_ne!(first(1, 2, 3), first(0, 2, 3));
+        assert_ne!(first(1, 2, 3), first(1, 3, 3));
+        assert_ne!(first(1, 2, 3), first(1, 2, 4));
+    }
+
+    #[test]
+    fn test_uniform() {
+        // the first number of neighbouring samples is what a pixel sees, it should look random
+        let n = 10000;
+        let mut buckets = [0; 10];
+        for sample in 0..n {
+            let v: f32 = Pcg32::for_sample(7, 100, sample).gen();
+            assert!((0.0..1.0).contains(&v));
+            buckets[(v * 10.0) as usize] += 1;
+        }
+
+        for count in buckets {
+            assert!((count - n as i32 / 10).abs() < 150, "{:?}", buckets);
+        }
+    }
+}
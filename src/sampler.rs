@@ -0,0 +1,504 @@
+use crate::rng::mix;
+use crate::rng::Pcg32;
+
+use glam::Vec2;
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+/*
+    Samplers hand out the numbers in [0;1) a camera sample is built from, one dimension at a time:
+    the position in the pixel, the position on the lens, the points on the lights shadow rays go
+    to. Random numbers clump and leave gaps, the other samplers spread the samples of a pixel
+    evenly over every dimension so the same number of samples gets closer to the right answer:
+    - stratified: every batch of samples gets a jittered grid, shuffled differently for every
+      dimension so the dimensions don't line up
+    - halton: the radical inverse in a different prime base for every dimension, shifted by a
+      random offset per pixel (Cranley-Patterson rotation). Past the primes in the table it's
+      random numbers
+    - sobol: Sobol points with hash based Owen scrambling (Burley 2020). Every request gets the
+      first two dimensions of the sequence with a scramble of its own and the index shuffled, so
+      there's no limit on how many dimensions are used and they don't correlate
+    - blue_noise: the same scrambled Sobol points for every pixel, each pixel shifted by the value
+      of a blue noise mask. What error is left is spread out as high frequency noise which looks a
+      lot less blotchy than the white noise of the others
+    Whatever needs more than the sampler gives, Russian roulette or picking between reflection and
+    refraction, takes numbers from the random generator of the sample.
+*/
+
+#[derive(clap::ArgEnum, Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+#[clap(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerKind {
+    Random,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+// bases of the Halton dimensions
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+// side of the square blue noise mask, it's tiled over the image
+const MASK_SIZE: usize = 64;
+
+// of the Gaussian the void and cluster method measures how crowded a spot is with
+const MASK_SIGMA: f32 = 1.5;
+
+/**
+ * The numbers of one camera sample. Sample index is counted over all the samples the pixel ever
+ * got so passes carry on with the sequence, batch is how many samples are stratified together
+ */
+pub struct Sampler {
+    kind: SamplerKind,
+    seed: u64,
+    x: u32,
+    y: u32,
+    index: u32,
+    batch: u32,
+    dimension: u32,
+    rng: Pcg32,
+}
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, seed: u64, x: u32, y: u32, index: u32, batch: u32) -> Sampler {
+        let pixel = ((y as u64) << 32) | x as u64;
+
+        Sampler {
+            kind,
+            seed,
+            x,
+            y,
+            index,
+            batch: batch.max(1),
+            dimension: 0,
+            rng: Pcg32::for_sample(seed, pixel, index),
+        }
+    }
+
+    // for what the sampler doesn't cover
+    pub fn rng(&mut self) -> &mut Pcg32 {
+        &mut self.rng
+    }
+
+    pub fn get_1d(&mut self) -> f32 {
+        let dimension = self.next_dimension();
+
+        match self.kind {
+            SamplerKind::Random => self.rng.gen(),
+            SamplerKind::Stratified => {
+                let strata = self.batch;
+                let (set, i) = (self.index / strata, self.index % strata);
+                let stratum = permute(i, strata, self.key(&[dimension as u64, set as u64]));
+
+                (stratum as f32 + self.rng.gen::<f32>()) / strata as f32
+            }
+            SamplerKind::Halton => self.halton(dimension),
+            SamplerKind::Sobol => {
+                let key = self.key(&[dimension as u64]);
+                let index = nested_uniform_scramble(self.index, key as u32);
+                to_float(nested_uniform_scramble(
+                    index.reverse_bits(),
+                    (key >> 32) as u32,
+                ))
+            }
+            SamplerKind::BlueNoise => {
+                let key = self.shared_key(&[dimension as u64]);
+                let index = nested_uniform_scramble(self.index, key as u32);
+                let v = to_float(nested_uniform_scramble(
+                    index.reverse_bits(),
+                    (key >> 32) as u32,
+                ));
+
+                (v + self.mask_value(dimension)).fract()
+            }
+        }
+    }
+
+    pub fn get_2d(&mut self) -> Vec2 {
+        match self.kind {
+            SamplerKind::Random | SamplerKind::Halton => Vec2::new(self.get_1d(), self.get_1d()),
+            SamplerKind::Stratified => {
+                let dimension = self.next_dimension();
+                self.next_dimension();
+
+                // as square a grid as the batch allows, some cells stay empty when it isn't one
+                let columns = (self.batch as f32).sqrt().ceil() as u32;
+                let rows = self.batch.div_ceil(columns);
+                let (set, i) = (self.index / self.batch, self.index % self.batch);
+                let cell = permute(i, columns * rows, self.key(&[dimension as u64, set as u64]));
+
+                Vec2::new(
+                    ((cell % columns) as f32 + self.rng.gen::<f32>()) / columns as f32,
+                    ((cell / columns) as f32 + self.rng.gen::<f32>()) / rows as f32,
+                )
+            }
+            SamplerKind::Sobol => {
+                let dimension = self.next_dimension();
+                self.next_dimension();
+
+                sobol_2d(self.index, self.key(&[dimension as u64]))
+            }
+            SamplerKind::BlueNoise => {
+                let dimension = self.next_dimension();
+                self.next_dimension();
+
+                let p = sobol_2d(self.index, self.shared_key(&[dimension as u64]));
+                let shift = Vec2::new(self.mask_value(dimension), self.mask_value(dimension + 1));
+
+                Vec2::new((p.x + shift.x).fract(), (p.y + shift.y).fract())
+            }
+        }
+    }
+
+    fn next_dimension(&mut self) -> u32 {
+        self.dimension += 1;
+        self.dimension - 1
+    }
+
+    // differs for every pixel
+    fn key(&self, values: &[u64]) -> u64 {
+        let pixel = ((self.y as u64) << 32) | self.x as u64;
+        values
+            .iter()
+            .fold(mix(self.seed ^ mix(pixel)), |key, &v| mix(key ^ v))
+    }
+
+    // the same for every pixel
+    fn shared_key(&self, values: &[u64]) -> u64 {
+        values.iter().fold(mix(self.seed), |key, &v| mix(key ^ v))
+    }
+
+    fn halton(&mut self, dimension: u32) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => {
+                let offset = to_float(self.key(&[dimension as u64]) as u32);
+                (radical_inverse(base, self.index) + offset).fract()
+            }
+            None => self.rng.gen(),
+        }
+    }
+
+    // every dimension looks at the mask from a different spot
+    fn mask_value(&self, dimension: u32) -> f32 {
+        let offset = self.shared_key(&[dimension as u64, u64::MAX]);
+        let x = (self.x as usize + offset as usize) % MASK_SIZE;
+        let y = (self.y as usize + (offset >> 32) as usize) % MASK_SIZE;
+
+        blue_noise_mask()[y * MASK_SIZE + x]
+    }
+}
+
+// the top 24 bits, as many as an f32 holds, so that the result stays below 1
+fn to_float(x: u32) -> f32 {
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+// digits of the index in the base mirrored around the decimal point
+fn radical_inverse(base: u32, index: u32) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut index = index;
+    let mut result = 0.0;
+    let mut scale = inverse_base;
+
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+
+    (result as f32).min(1.0 - f32::EPSILON / 2.0)
+}
+
+/**
+ * Permutation of 0..n picked by the key, "Correlated Multi-Jittered Sampling" (Kensler 2013).
+ * Hashes within the next power of two and walks until the result falls into the range
+ */
+fn permute(i: u32, n: u32, key: u64) -> u32 {
+    let key = key as u32;
+    let mut w = n.max(1) - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    let mut i = i;
+    loop {
+        i ^= key;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= key >> 16;
+        i ^= (i & w) >> 4;
+        i ^= key >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= key >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | key >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < n {
+            return i;
+        }
+    }
+}
+
+// hash that only lets a bit change the bits above it, Laine and Karras 2011
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+// Owen scrambling of the bits of x as a fraction, the most significant bit first
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// the first two Sobol dimensions of the (shuffled) index, both Owen scrambled
+fn sobol_2d(index: u32, key: u64) -> Vec2 {
+    let index = nested_uniform_scramble(index, key as u32);
+
+    let x = index.reverse_bits();
+    let mut y = 0;
+    let mut v = 1 << 31;
+    let mut bits = index;
+    while bits != 0 {
+        if bits & 1 == 1 {
+            y ^= v;
+        }
+        bits >>= 1;
+        v ^= v >> 1;
+    }
+
+    let seed = mix(key);
+    Vec2::new(
+        to_float(nested_uniform_scramble(x, seed as u32)),
+        to_float(nested_uniform_scramble(y, (seed >> 32) as u32)),
+    )
+}
+
+/**
+ * Blue noise mask made with the void and cluster method (Ulichney 1993). Pixels get ranks one by
+ * one, each going to the emptiest spot left as measured by a Gaussian around the ranked ones, the
+ * value of a pixel is its rank. Made once, from a fixed seed so that it's the same every run
+ */
+fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| void_and_cluster(MASK_SIZE))
+}
+
+fn void_and_cluster(size: usize) -> Vec<f32> {
+    let n = size * size;
+    let radius = (MASK_SIGMA * 4.0).ceil() as i32;
+    let mut kernel = Vec::new();
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let d2 = (dx * dx + dy * dy) as f32;
+            kernel.push((dx, dy, (-d2 / (2.0 * MASK_SIGMA * MASK_SIGMA)).exp()));
+        }
+    }
+
+    // how crowded every pixel's neighbourhood is, wrapping around the edges
+    let update = |energy: &mut [f32], p: usize, sign: f32| {
+        let (px, py) = ((p % size) as i32, (p / size) as i32);
+        for &(dx, dy, weight) in &kernel {
+            let x = (px + dx).rem_euclid(size as i32) as usize;
+            let y = (py + dy).rem_euclid(size as i32) as usize;
+            energy[y * size + x] += sign * weight;
+        }
+    };
+    let tightest_cluster = |energy: &[f32], on: &[bool]| {
+        (0..n)
+            .filter(|&p| on[p])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+    let largest_void = |energy: &[f32], on: &[bool]| {
+        (0..n)
+            .filter(|&p| !on[p])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    // a tenth of the pixels at random, then moved around until they're evenly spread
+    let mut rng = Pcg32::new(0x5eed, 0);
+    let mut on = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let initial = n / 10;
+    let mut placed = 0;
+    while placed < initial {
+        let p = rng.gen_range(0..n);
+        if !on[p] {
+            on[p] = true;
+            update(&mut energy, p, 1.0);
+            placed += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&energy, &on);
+        on[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+
+        let void = largest_void(&energy, &on);
+        on[void] = true;
+        update(&mut energy, void, 1.0);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; n];
+
+    // the initial pixels get the lowest ranks, the most crowded one the highest of them
+    let (mut ranked_on, mut ranked_energy) = (on.clone(), energy.clone());
+    for r in (0..initial).rev() {
+        let cluster = tightest_cluster(&ranked_energy, &ranked_on);
+        ranked_on[cluster] = false;
+        update(&mut ranked_energy, cluster, -1.0);
+        rank[cluster] = r;
+    }
+
+    // the rest fill the voids
+    for r in initial..n {
+        let void = largest_void(&energy, &on);
+        on[void] = true;
+        update(&mut energy, void, 1.0);
+        rank[void] = r;
+    }
+
+    rank.iter().map(|&r| (r as f32 + 0.5) / n as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = [
+        SamplerKind::Random,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+
+    #[test]
+    fn test_values_are_in_range_and_repeat() {
+        for kind in KINDS {
+            for index in 0..64 {
+                let values = |seed| {
+                    let mut sampler = Sampler::new(kind, seed, 3, 5, index, 16);
+                    let mut values = Vec::new();
+                    for _ in 0..20 {
+                        values.push(sampler.get_1d());
+                        let p = sampler.get_2d();
+                        values.extend([p.x, p.y]);
+                    }
+                    values
+                };
+
+                let v = values(1);
+                assert!(v.iter().all(|v| (0.0..1.0).contains(v)), "{:?}", kind);
+                assert_eq!(v, values(1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_permute() {
+        for n in [1, 5, 16, 17] {
+            let mut seen: Vec<u32> = (0..n).map(|i| permute(i, n, 99)).collect();
+            seen.sort();
+            assert_eq!(seen, (0..n).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn test_sobol_points_are_stratified() {
+        // every power of two prefix of a (0, 2) sequence has one point per elementary interval
+        let points: Vec<Vec2> = (0..16).map(|i| sobol_2d(i, 12345)).collect();
+
+        for (columns, rows) in [(16, 1), (8, 2), (4, 4), (2, 8), (1, 16)] {
+            let mut cells = [0; 16];
+            for p in &points {
+                let cell = (p.y * rows as f32) as usize * columns + (p.x * columns as f32) as usize;
+                cells[cell] += 1;
+            }
+            assert!(cells.iter().all(|&c| c == 1), "{}x{}", columns, rows);
+        }
+    }
+
+    #[test]
+    fn test_blue_noise_mask() {
+        let mask = blue_noise_mask();
+        assert_eq!(mask.len(), MASK_SIZE * MASK_SIZE);
+
+        // every rank once
+        let mut sorted = mask.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        for (i, v) in sorted.iter().enumerate() {
+            assert_eq!(*v, (i as f32 + 0.5) / sorted.len() as f32);
+        }
+
+        // neighbours differ a lot more than random values would (1/3 on average)
+        let mut difference = 0.0;
+        for y in 0..MASK_SIZE {
+            for x in 0..MASK_SIZE {
+                let right = mask[y * MASK_SIZE + (x + 1) % MASK_SIZE];
+                difference += (mask[y * MASK_SIZE + x] - right).abs();
+            }
+        }
+        assert!(difference / (MASK_SIZE * MASK_SIZE) as f32 > 0.4);
+    }
+
+    /**
+     * Integrates a disk over the unit square with 64 samples in a lot of pixels. The error of a
+     * pixel shrinks with the number of samples for all of them but the ones that spread the
+     * samples out shrink it a lot faster, at 64 it's already well below random sampling
+     */
+    #[test]
+    fn test_lower_error_than_random_at_equal_samples() {
+        let samples = 64;
+        let pixels = 256;
+        let inside = |p: Vec2| ((p - Vec2::splat(0.5)).length() < 0.4) as u32 as f32;
+        let exact = std::f32::consts::PI * 0.16;
+
+        let rmse = |kind: SamplerKind| {
+            let mut total = 0.0;
+            for pixel in 0..pixels {
+                let mut sum = 0.0;
+                for index in 0..samples {
+                    let mut sampler = Sampler::new(kind, 7, pixel % 16, pixel / 16, index, samples);
+                    // past the first dimensions, like the shadow rays after the pixel and lens
+                    sampler.get_2d();
+                    sampler.get_1d();
+                    sum += inside(sampler.get_2d());
+                }
+                total += (sum / samples as f32 - exact).powi(2);
+            }
+            (total / pixels as f32).sqrt()
+        };
+
+        let random = rmse(SamplerKind::Random);
+        for kind in &KINDS[1..] {
+            let error = rmse(*kind);
+            assert!(error < random * 0.6, "{:?}: {} vs {}", kind, error, random);
+        }
+    }
+}
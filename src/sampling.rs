@@ -0,0 +1,111 @@
+use crate::rng::Pcg32;
+use glam::Vec2;
+use glam::Vec3;
+use rand::prelude::*;
+use std::f32::consts::PI;
+
+/**
+ * Two unit vectors perpendicular to the given unit vector and to each other, together with it they
+ * form a right handed frame. Branchless version from "Building an Orthonormal Basis, Revisited"
+ * (Duff et al.) which doesn't fall apart for any direction of n
+ */
+pub fn orthonormal_basis(n: Vec3) -> (Vec3, Vec3) {
+    let sign = 1.0_f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    (
+        Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vec3::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+// expresses the local direction (z being the axis) in the frame around the axis
+fn to_world(axis: Vec3, local: Vec3) -> Vec3 {
+    let (t, b) = orthonormal_basis(axis);
+    t * local.x + b * local.y + axis * local.z
+}
+
+/**
+ * Direction in the hemisphere around the normal with density cos(theta) / PI. Points picked
+ * uniformly on a disk and projected up onto the hemisphere have exactly that distribution (Malley's
+ * method), which matches the cosine term of the rendering equation so for Lambertian surfaces it
+ * cancels out
+ */
+pub fn cosine_hemisphere(normal: Vec3, rng: &mut Pcg32) -> Vec3 {
+    let u1: f32 = rng.gen();
+    let u2: f32 = rng.gen();
+
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    let local = Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt());
+
+    to_world(normal, local)
+}
+
+// uniformly distributed point inside the unit ball, the radius follows from u.z
+pub fn uniform_ball(u: Vec3) -> Vec3 {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+
+    Vec3::new(r * phi.cos(), r * phi.sin(), z) * u.z.cbrt()
+}
+
+// uniformly distributed direction, density 1 / (4 * PI)
+pub fn uniform_sphere(rng: &mut Pcg32) -> Vec3 {
+    let z: f32 = 1.0 - 2.0 * rng.gen::<f32>();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f32>();
+
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// uniformly distributed direction inside the cone around the axis, density 1 / (2 * PI * (1 - cos_max))
+pub fn uniform_cone(axis: Vec3, cos_max: f32, rng: &mut Pcg32) -> Vec3 {
+    let cos = 1.0 - rng.gen::<f32>() * (1.0 - cos_max);
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f32>();
+
+    to_world(axis, Vec3::new(sin * phi.cos(), sin * phi.sin(), cos))
+}
+
+/**
+ * Maps a point of the unit square uniformly onto the unit disk. Concentric mapping (Shirley and
+ * Chiu) rather than just taking sqrt(u) as the radius as it keeps neighbouring points neighbours,
+ * which matters once the samples are stratified
+ */
+pub fn concentric_disk(u: Vec2) -> Vec2 {
+    let offset = u * 2.0 - Vec2::ONE;
+    if offset.x == 0.0 && offset.y == 0.0 {
+        return Vec2::ZERO;
+    }
+
+    let (r, theta) = if offset.x.abs() > offset.y.abs() {
+        (offset.x, PI / 4.0 * (offset.y / offset.x))
+    } else {
+        (offset.y, PI / 2.0 - PI / 4.0 * (offset.x / offset.y))
+    };
+
+    Vec2::new(theta.cos(), theta.sin()) * r
+}
+
+/**
+ * Maps a point of the unit square uniformly onto a regular polygon inscribed in the unit circle,
+ * rotated by the given angle in radians. The polygon is a fan of identical triangles around the
+ * center, u.x picks the triangle and is then reused for the position inside it
+ */
+pub fn regular_polygon(u: Vec2, sides: u32, rotation: f32) -> Vec2 {
+    let sides_f = sides as f32;
+    let scaled = u.x * sides_f;
+    let side = scaled.floor().min(sides_f - 1.0);
+    let u1 = scaled - side;
+
+    let angle = |i: f32| rotation + 2.0 * PI * i / sides_f;
+    let a = Vec2::new(angle(side).cos(), angle(side).sin());
+    let b = Vec2::new(angle(side + 1.0).cos(), angle(side + 1.0).sin());
+
+    // uniform point in the triangle (center, a, b)
+    let s = u1.sqrt();
+    a * (s * (1.0 - u.y)) + b * (s * u.y)
+}
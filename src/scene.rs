@@ -1,13 +1,22 @@
+use crate::aabb::Aabb;
 use crate::app::PartialRenderMessagePixels;
+use crate::bvh::Bvh;
 use crate::camera::Camera;
 
 use crate::material;
 use crate::material::Material;
 use crate::ray::Ray;
 
+use crate::aov::AovAccumulator;
+use crate::aov::FirstHit;
 use crate::app;
+use crate::filter::Tile;
 use crate::loader;
 use crate::material::Color;
+use crate::pathtracer;
+use crate::rng::Pcg32;
+use crate::sampler::Sampler;
+use glam::Vec2;
 use glam::Vec3;
 use rand::prelude::*;
 use std::sync::mpsc;
@@ -17,25 +26,102 @@ use std::sync::Mutex;
 use crate::app::BBox;
 use crate::app::PartialRenderMessage;
 
-pub trait RenderCallbacks {
-    fn progress(&mut self, v: f32);
-}
-
 const AMBIENT_R: f32 = 0.0;
 const AMBIENT_G: f32 = 0.0;
 const AMBIENT_B: f32 = 0.0;
 
+// brightness below which the error of adaptive sampling is taken as absolute rather than relative
+const ADAPTIVE_DARK_FLOOR: f32 = 0.05;
+
+// how many glass surfaces a shadow ray may pass through before we consider it blocked
+const MAX_SHADOW_TRANSMISSIONS: usize = 16;
+
+// a ray going through more surfaces of a shape than this is cut short, those are found by
+// following it from hit to hit and a shape that keeps finding the same point would never end
+const MAX_CROSSINGS: usize = 64;
+
+// glass inside of glass this many times over, any deeper and the innermost ones share a medium
+const MAX_MEDIA: usize = 8;
+
 pub struct Pixel {
     pub x: u64,
     pub y: u64,
-    pub color: material::Color,
+    // of the samples the pass added, not of everything on the film
+    pub totals: Totals,
+    // only filled in when settings.aovs is on
+    pub aov: AovAccumulator,
+}
+
+// what render_block made of its pixels
+pub struct Block {
+    pub pixels: Vec<Pixel>,
+    // the samples weighted by the filter, reaching past the block by the filter's radius
+    pub splats: Tile,
+}
+
+// running sums of the samples of a pixel, what the film keeps so that later passes can add to it
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Totals {
+    pub sum: Color,
+    pub squares: Color,
+    pub samples: u32,
+}
+
+impl Totals {
+    pub fn add(&mut self, color: Color) {
+        self.sum = self.sum + color;
+        self.squares = self.squares + color * color;
+        self.samples += 1;
+    }
+
+    pub fn merge(&mut self, other: &Totals) {
+        self.sum = self.sum + other.sum;
+        self.squares = self.squares + other.squares;
+        self.samples += other.samples;
+    }
+
+    // black when there are no samples yet
+    pub fn mean(&self) -> Color {
+        self.sum / self.samples.max(1) as f32
+    }
+
+    // of the mean, averaged over the channels. 0 when there's a single sample
+    pub fn variance(&self) -> f32 {
+        variance_of_mean(self.mean(), self.squares, self.samples)
+    }
+}
+
+// light reaching the camera, split by whether it bounced off more than one surface on the way
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Radiance {
+    pub direct: Color,
+    pub indirect: Color,
+}
+
+impl Radiance {
+    pub fn total(&self) -> Color {
+        self.direct + self.indirect
+    }
 }
 
 #[derive(Clone)]
 pub struct Object {
-    mat: Material,
+    pub mat: Material,
     pub geometry: Arc<dyn Hitable>,
-    kind: loader::Kind,
+    pub kind: loader::Kind,
+    // counted from 1 in the order objects and lights were added, for the id AOVs
+    pub id: u32,
+    pub material_id: u32,
+}
+
+impl Object {
+    // radiance given off by the surface, lights glow in their material color
+    pub fn emission(&self) -> Color {
+        match self.kind {
+            loader::Kind::Light { intensity } => self.mat.color() * intensity,
+            loader::Kind::Object => Color::default(),
+        }
+    }
 }
 
 pub struct Scene {
@@ -43,6 +129,10 @@ pub struct Scene {
     height: u64,
     objects: Vec<Object>,
     lights: Vec<Object>,
+    // distinct materials, an object's material id is the index in here plus one
+    materials: Vec<Material>,
+    // built by finalize() over objects followed by lights
+    bvh: Option<Bvh>,
 }
 
 impl Scene {
@@ -52,29 +142,64 @@ impl Scene {
             height,
             objects: Vec::new(),
             lights: Vec::new(),
+            materials: Vec::new(),
+            bvh: None,
         }
     }
 
     pub fn add_object(&mut self, g: Arc<dyn Hitable>, m: Material) {
-        self.objects.push(Object {
-            kind: loader::Kind::Object,
-            mat: m,
-            geometry: g,
-        });
+        let object = self.new_object(g, m, loader::Kind::Object);
+        self.objects.push(object);
     }
 
-    pub fn add_light(&mut self, g: Arc<dyn Hitable>, m: Material) {
-        self.lights.push(Object {
-            kind: loader::Kind::Light,
+    pub fn add_light(&mut self, g: Arc<dyn Hitable>, m: Material, intensity: f32) {
+        let light = self.new_object(g, m, loader::Kind::Light { intensity });
+        self.lights.push(light);
+    }
+
+    fn new_object(&mut self, g: Arc<dyn Hitable>, m: Material, kind: loader::Kind) -> Object {
+        let material_id = match self.materials.iter().position(|other| *other == m) {
+            Some(idx) => idx + 1,
+            None => {
+                self.materials.push(m);
+                self.materials.len()
+            }
+        };
+
+        Object {
+            kind,
             mat: m,
             geometry: g,
-        });
+            id: (self.objects.len() + self.lights.len() + 1) as u32,
+            material_id: material_id as u32,
+        }
     }
 
     // todo: slice?
     pub fn lights(&self) -> &Vec<Object> {
         &self.lights
     }
+
+    // builds the acceleration structure, has to be called after all the objects are added
+    pub fn finalize(&mut self) {
+        let boxes: Vec<Aabb> = self
+            .objects
+            .iter()
+            .chain(self.lights.iter())
+            .map(|o| o.geometry.bounding_box())
+            .collect();
+
+        self.bvh = Some(Bvh::build(&boxes));
+    }
+
+    // objects and lights share one index space, the same one the BVH is built with
+    fn object(&self, idx: usize) -> &Object {
+        if idx < self.objects.len() {
+            &self.objects[idx]
+        } else {
+            &self.lights[idx - self.objects.len()]
+        }
+    }
 }
 
 pub enum Face {
@@ -86,6 +211,39 @@ pub struct CollisionData {
     pub face: Face,
     pub normal: Vec3,
     pub point: Vec3,
+    // surface parametrization at the point, both in [0;1]. Nothing is textured yet
+    #[allow(dead_code)]
+    pub uv: Vec2,
+}
+
+impl CollisionData {
+    /**
+     * Hit t along the ray on a surface with the given normal, pointing out of the shape and of
+     * unit length. Like for spheres the face follows from the side the ray comes from and the
+     * normal is turned towards the ray
+     */
+    pub fn new(r: &Ray, t: f32, outward: Vec3, uv: Vec2) -> CollisionData {
+        let (face, normal) = if outward.dot(r.dir) > 0.0 {
+            (Face::Back, -outward)
+        } else {
+            (Face::Front, outward)
+        };
+
+        CollisionData {
+            face,
+            normal,
+            point: r.at(t),
+            uv,
+        }
+    }
+
+    // the normal pointing out of the shape whichever side the ray came from
+    pub fn outward(&self) -> Vec3 {
+        match self.face {
+            Face::Front => self.normal,
+            Face::Back => -self.normal,
+        }
+    }
 }
 
 pub trait Hitable: Send + Sync {
@@ -93,13 +251,44 @@ pub trait Hitable: Send + Sync {
     // todo: should be split into hitable and other traits
     fn hit(&self, r: &Ray) -> Option<CollisionData>;
     fn pos(&self) -> Vec3;
-    fn pos_mut(&mut self) -> &mut Vec3;
-    fn get_random_point(&self) -> Vec3;
-}
+    // point inside or on the shape relative to pos, u are three numbers in [0;1). Uniform ones
+    // give points spread uniformly over the shape
+    fn get_random_point(&self, u: Vec3) -> Vec3;
+    fn bounding_box(&self) -> Aabb;
+
+    /**
+     * Direction from the point towards a random point on the surface and the probability density
+     * of picking it, per solid angle. The path tracer uses it to aim shadow rays at lights, shapes
+     * that can't be sampled this way are only found by bounces off mirrors and glass
+     */
+    fn sample_direction(&self, _from: Vec3, _rng: &mut Pcg32) -> Option<(Vec3, f32)> {
+        None
+    }
 
-pub fn random_point_in_circle() -> Vec3 {
-    let mut rng = rand::thread_rng();
+    /**
+     * Everywhere the ray goes through the surface with the distance along it, nearest first. The
+     * face tells whether it's going into the shape or out of it, which is what CSG needs. By
+     * default the ray is followed from each hit to the next
+     */
+    fn crossings(&self, r: &Ray) -> Vec<(f32, CollisionData)> {
+        let mut crossings = Vec::new();
+        let mut origin = r.origin;
+
+        while crossings.len() < MAX_CROSSINGS {
+            let hit = match self.hit(&Ray { origin, dir: r.dir }) {
+                Some(hit) => hit,
+                None => break,
+            };
+
+            origin = hit.point;
+            crossings.push(((hit.point - r.origin).dot(r.dir), hit));
+        }
 
+        crossings
+    }
+}
+
+pub fn random_point_in_circle(rng: &mut Pcg32) -> Vec3 {
     loop {
         let x: f32 = 1.0 - rng.gen::<f32>() * 2.0;
         let y: f32 = 1.0 - rng.gen::<f32>() * 2.0;
@@ -114,90 +303,155 @@ pub fn random_point_in_circle() -> Vec3 {
     }
 }
 
-fn render_block(
+/**
+ * Renders the pixels of the box for one pass, carrying on from what the film already has for
+ * them. Samples are added in batches of settings.samples until the pixel has the number the pass
+ * asks for, or with adaptive sampling until it's converged. The pixels returned only have the
+ * samples of this pass, pixels that needed none are left out. The samples are splatted with the
+ * filter of the settings too, onto the pixels around them whether in the block or not
+ */
+pub fn render_block(
     scene: Arc<Scene>,
     camera: Camera,
     settings: app::Settings,
+    pass: app::Pass,
     bbox: BBox,
     tx: mpsc::Sender<f64>,
-) -> Vec<Pixel> {
+) -> Block {
     let mut pixels = Vec::new();
 
     let scnheight = scene.height;
     let scnwidth = scene.width;
 
-    let aspect = 1.0;
-    let theta = (camera.fov).to_radians(); // 50mm ff -> 46.8
-    let h = (theta / 2.0).tan();
-    let viewport_height = 2.0 * h;
-    let viewport_width = aspect * viewport_height as f32;
-
-    let vup = Vec3::new(0.0, 1.0, 0.0);
-    let w = (camera.pos - camera.lookat).normalize();
-    let u = w.cross(vup).normalize();
-    let v = w.cross(u);
-
-    let origin = camera.pos;
-    let horizontal = viewport_width * u;
-    let vertical = viewport_height * v;
-    let lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - w;
-
-    for j in bbox.x..(bbox.x + bbox.w) {
-        for i in bbox.y..(bbox.y + bbox.h) {
-            let mut final_color = Color::default();
-            let mut rng = rand::thread_rng();
-
-            for _ in 0..settings.samples {
-                let xoff: f32 = 1.0 - (2.0 * rng.gen::<f32>());
-                let yoff: f32 = 1.0 - (2.0 * rng.gen::<f32>());
-
-                let u = (i as f32 + xoff) / ((scnwidth - 1) as f32);
-                let v = (j as f32 + yoff) / ((scnheight - 1) as f32);
-                let r = Ray::new(
-                    origin,
-                    lower_left_corner + horizontal * u as f32 + vertical * v as f32 - origin,
-                );
+    let filter = settings.filter;
+    let mut splats = Tile::new(
+        &filter,
+        bbox.x as u32,
+        bbox.y as u32,
+        bbox.w as u32,
+        bbox.h as u32,
+        scnwidth as u32,
+        scnheight as u32,
+    );
+
+    let aspect = scnwidth as f32 / scnheight as f32;
+
+    for y in bbox.y..(bbox.y + bbox.h) {
+        for x in bbox.x..(bbox.x + bbox.w) {
+            let pixel = y as u64 * scnwidth + x as u64;
+            let before = pass.film.get(pixel as usize).copied().unwrap_or_default();
+            let mut totals = Totals::default();
+            let mut aovs = AovAccumulator::default();
+
+            loop {
+                let mut all = before;
+                all.merge(&totals);
+
+                if all.samples >= pass.goal {
+                    break;
+                }
+                if let Some(threshold) = settings.adaptive_threshold {
+                    if all.samples >= settings.samples
+                        && relative_error(all.mean(), all.variance()) < threshold
+                    {
+                        break;
+                    }
+                }
 
-                let color = ray_color(&r, &scene.clone(), 100, settings.shadow_samples);
-                final_color = final_color + color;
+                let batch = settings.samples.min(pass.goal - all.samples);
+                for _ in 0..batch {
+                    let mut sampler = Sampler::new(
+                        settings.sampler,
+                        settings.seed,
+                        x as u32,
+                        y as u32,
+                        before.samples + totals.samples,
+                        settings.samples,
+                    );
+
+                    // point inside the pixel
+                    let position = Vec2::new(x as f32, y as f32) + sampler.get_2d();
+                    let s = position.x / scnwidth as f32;
+                    let t = position.y / scnheight as f32;
+                    let lens_sample = sampler.get_2d();
+                    let r = camera.get_ray(s, t, aspect, lens_sample);
+                    let radiance = match r {
+                        None => Radiance::default(),
+                        Some(r) => match settings.integrator {
+                            app::Integrator::Whitted => ray_color(
+                                &r,
+                                &scene,
+                                &Media::default(),
+                                100,
+                                settings.shadow_samples,
+                                &mut sampler,
+                            ),
+                            app::Integrator::Path => pathtracer::path_color(
+                                &r,
+                                &scene,
+                                settings.shadow_samples,
+                                sampler.rng(),
+                            ),
+                        },
+                    };
+                    totals.add(radiance.total());
+                    splats.add(&filter, position, radiance.total());
+
+                    if settings.aovs {
+                        aovs.add(r.and_then(|r| first_hit(&r, &scene)), radiance);
+                    }
+                }
             }
 
-            final_color = final_color / settings.samples as f32;
-            final_color.r = final_color.r.clamp(0.0, 1.0);
-            final_color.g = final_color.g.clamp(0.0, 1.0);
-            final_color.b = final_color.b.clamp(0.0, 1.0);
-
-            pixels.push(Pixel {
-                x: i as u64,
-                y: j as u64,
-                color: final_color,
-            })
+            if totals.samples > 0 {
+                pixels.push(Pixel {
+                    x: x as u64,
+                    y: y as u64,
+                    totals,
+                    aov: aovs,
+                })
+            }
         }
     }
 
     // todo: could be simplified if not sending inside the loop, another place listens for 1.0
     tx.send(1.0).unwrap();
 
-    pixels
+    Block { pixels, splats }
+}
+
+/**
+ * Sample variance of the colors divided by the sample count, which is how far the average is
+ * expected to be off. Computed from the mean and the sum of squares
+ */
+fn variance_of_mean(mean: Color, squares: Color, samples: u32) -> f32 {
+    if samples < 2 {
+        return 0.0;
+    }
+
+    let n = samples as f32;
+    let variance = |mean: f32, squares: f32| (squares / n - mean * mean).max(0.0) / (n - 1.0);
+
+    (variance(mean.r, squares.r) + variance(mean.g, squares.g) + variance(mean.b, squares.b)) / 3.0
+}
+
+/**
+ * Standard error of the pixel relative to its brightness, what adaptive sampling drives down.
+ * Dark pixels are compared against a floor instead as a few percent of almost nothing is noise
+ * nobody would see
+ */
+fn relative_error(mean: Color, variance: f32) -> f32 {
+    let brightness = (mean.r + mean.g + mean.b) / 3.0;
+    variance.sqrt() / brightness.max(ADAPTIVE_DARK_FLOOR)
 }
 
 pub fn draw(
     scene: Arc<Scene>,
     camera: Camera,
     settings: app::Settings,
+    pass: app::Pass,
     tx: mpsc::Sender<PartialRenderMessage>,
 ) {
-    let aspect = 1.0;
-    let theta = (camera.fov).to_radians(); // 50mm ff -> 46.8 // todo: show focal length and angle in gui
-    let h = (theta / 2.0).tan();
-    let viewport_height = 2.0 * h;
-    let viewport_width = aspect * viewport_height as f32;
-
-    let horizontal = Vec3::new(viewport_width as f32, 0.0, 0.0);
-    let vertical = Vec3::new(0.0, viewport_height as f32, 0.0);
-    let origin = camera.pos;
-    // let _lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - camera.dir;
-
     let scnheight = scene.height;
     let scnwidth = scene.width;
 
@@ -207,7 +461,7 @@ pub fn draw(
     let mut bboxes = get_bboxes_for(scnwidth as i32, scnheight as i32, settings.bboxes as i32);
     bboxes.reverse();
 
-    let pool = threadpool::ThreadPool::new(settings.threads as usize);
+    let pool = threadpool::ThreadPool::new(settings.threads);
     // let mut handles: Vec<std::thread::JoinHandle<_>> = Vec::new();
 
     let progress = Arc::new(Mutex::new(0));
@@ -220,43 +474,29 @@ pub fn draw(
 
     let total_progress = Arc::new(Mutex::new(0.0));
 
-    let tx_clone3 = tx.clone();
-    std::thread::spawn(move || loop {
-        match progrx.try_recv() {
-            Ok(p) => {
-                let progress: f64 = *total_progress.lock().unwrap() + p * progress_ratio;
-                let progress_bar_symbols = 30;
-                let ratio: f64 = 1.0 / progress_bar_symbols as f64;
-
-                let symbol_count: usize = (progress / ratio) as usize;
-                let progress_str: String = (0..symbol_count).into_iter().map(|_| ".").collect();
-
-                let padding: String = (0..(progress_bar_symbols - symbol_count))
-                    .into_iter()
-                    .map(|_| " ")
-                    .collect();
-
-                let progress_full_str =
-                    String::from("[") + &progress_str + &padding + &String::from("]");
-
-                println!(
-                    "Progress: {} {}%",
-                    progress_full_str,
-                    (progress * 100.0) as usize
-                );
-                *total_progress.lock().unwrap() = progress;
-
-                tx_clone3
-                    .send(PartialRenderMessage::Progress {
-                        0: *total_progress.lock().unwrap(),
-                    })
-                    .unwrap();
-
-                if *total_progress.lock().unwrap() >= 1.0 {
-                    break;
-                }
-            }
-            Err(_) => {} // todo
+    // ends once every block is done and the senders are gone
+    std::thread::spawn(move || {
+        for p in progrx.iter() {
+            let progress: f64 = *total_progress.lock().unwrap() + p * progress_ratio;
+            let progress_bar_symbols = 30;
+            let ratio: f64 = 1.0 / progress_bar_symbols as f64;
+
+            let symbol_count: usize = (progress / ratio) as usize;
+            let progress_str: String = (0..symbol_count).map(|_| ".").collect();
+
+            let padding: String = (0..(progress_bar_symbols - symbol_count))
+                .map(|_| " ")
+                .collect();
+
+            let progress_full_str =
+                String::from("[") + &progress_str + &padding + &String::from("]");
+
+            println!(
+                "Progress: {} {}%",
+                progress_full_str,
+                (progress * 100.0) as usize
+            );
+            *total_progress.lock().unwrap() = progress;
         }
     });
 
@@ -267,25 +507,64 @@ pub fn draw(
         let progress_clone = progress.clone();
 
         let progtx = progtx.clone();
+        let pass = pass.clone();
         pool.execute(move || {
-            let pixels = render_block(scene_clone, camera, settings, bbox, progtx);
+            let block = render_block(scene_clone, camera, settings, pass, bbox, progtx);
             *progress_clone.lock().unwrap() += 1;
 
             tx_clone2
-                .send(PartialRenderMessage::PixelData {
-                    0: PartialRenderMessagePixels {
-                        pixel_data: Arc::new(pixels),
+                .send(PartialRenderMessage::PixelData(
+                    PartialRenderMessagePixels {
                         bbox,
+                        pixel_data: Arc::new(block.pixels),
+                        splats: block.splats,
                     },
-                })
+                ))
                 .unwrap();
         });
     }
 
+    drop(progtx);
     pool.join();
 }
 
-fn collide(r: &Ray, scn: &Scene) -> Option<(CollisionData, Object)> {
+fn first_hit(r: &Ray, scn: &Scene) -> Option<FirstHit> {
+    let (hit, obj) = collide(r, scn)?;
+
+    Some(FirstHit {
+        depth: (hit.point - r.origin).length(),
+        normal: hit.normal.normalize(),
+        position: hit.point,
+        albedo: obj.mat.albedo(),
+        object_id: obj.id,
+        material_id: obj.material_id,
+    })
+}
+
+pub fn collide(r: &Ray, scn: &Scene) -> Option<(CollisionData, Object)> {
+    let bvh = match &scn.bvh {
+        Some(bvh) => bvh,
+        None => return collide_linear(r, scn),
+    };
+
+    let mut closest_data: Option<CollisionData> = None;
+
+    let closest = bvh.traverse(r, |idx, t_max| {
+        let data = scn.object(idx).geometry.hit(r)?;
+        let distance = (r.origin - data.point).length();
+
+        if distance < t_max {
+            closest_data = Some(data);
+        }
+
+        Some(distance)
+    });
+
+    closest.map(|(idx, _)| (closest_data.unwrap(), scn.object(idx).clone()))
+}
+
+// tests every object against the ray, what collide() falls back to before the scene is finalized
+fn collide_linear(r: &Ray, scn: &Scene) -> Option<(CollisionData, Object)> {
     let mut closest_obj: Option<Object> = None;
     let mut closest_data: Option<CollisionData> = None;
     let mut closest_distance: f32 = 99999999999.9;
@@ -324,138 +603,190 @@ fn collide(r: &Ray, scn: &Scene) -> Option<(CollisionData, Object)> {
 /**
  * We hit the scene with a ray, if it hit something then we take the objects material into
  * account how to render it but also do a shadow ray towards all sources of light to see if we should
- * light the pixel in order to produce soft shadows
+ * light the pixel in order to produce soft shadows. Whatever comes from reflected and refracted
+ * rays is the indirect part
  */
-fn ray_color(r: &Ray, scn: &Scene, depth: i16, shadow_samples: u32) -> Color {
+fn ray_color(
+    r: &Ray,
+    scn: &Scene,
+    media: &Media,
+    depth: i16,
+    shadow_samples: u32,
+    sampler: &mut Sampler,
+) -> Radiance {
     if depth <= 0 {
-        return Color::default();
+        return Radiance::default();
     }
 
     match collide(r, scn) {
         Some(collision_data) => {
-            let _mat = collision_data.1.mat;
-
             match collision_data.1.kind {
                 // todo: should be actual color of light?
-                loader::Kind::Light => Color::white(),
+                loader::Kind::Light { .. } => direct(Color::white()),
                 loader::Kind::Object => {
                     let collision_point = collision_data.0.point;
                     let collision_normal = collision_data.0.normal;
 
-                    let mut color = Vec3::new(0.0, 0.0, 0.0);
-
                     match collision_data.1.mat {
                         material::Material::Lambertian(m) => {
-                            color.x = m.color.r;
-                            color.y = m.color.g;
-                            color.z = m.color.b;
-                        }
-                        material::Material::Metal(m) => {
-                            color.x = m.color.r;
-                            color.y = m.color.g;
-                            color.z = m.color.b;
-                        }
-                        material::Material::Dielectric(m) => {
-                            color.x = m.color.r;
-                            color.y = m.color.g;
-                            color.z = m.color.b;
-                        }
-                    }
-
-                    // nowe as we've hit the object in the scene, we need to determine
-                    // it's relation to the light sources, it might be in the shadow or might be
-                    // lit. In order to find that out we collide another ray from collision point towards
-                    // all the light sources in the scene and light the pixel accordingly
-                    // we do not care about light's color at the moment
-
-                    let mut intensities: Vec<f32> = Vec::new();
-                    for light in scn.lights() {
-                        let mut collisions = 0;
-
-                        let rays_cnt = shadow_samples;
-                        let ll = light.clone();
-
-                        let rays = (0..rays_cnt).into_iter().map(|_| {
-                            let geom = &ll.geometry;
-                            Ray::new(
+                            let color: Vec3 = m.color.into();
+                            let light_intensity = light_intensity(
                                 collision_point,
-                                (geom.pos() + geom.get_random_point()) - collision_point,
-                            )
-                        });
-
-                        rays.for_each(|r| match collide(&r, scn) {
-                            None => {}
-                            Some(shadow_coll) => match shadow_coll.1.kind {
-                                // todo: check if it's the same light source
-                                loader::Kind::Light => {
-                                    collisions += 1;
-                                }
-                                loader::Kind::Object => {}
-                            },
-                        });
-
-                        let n = light.geometry.pos();
-                        let m = collision_normal.normalize();
-                        let dot = m.dot(n).clamp(0.0, 1.0) as f32;
-
-                        let intense = collisions as f32 / rays_cnt as f32;
-                        intensities.push(dot * intense);
-                    }
+                                collision_normal,
+                                scn,
+                                shadow_samples,
+                                sampler,
+                            );
 
-                    let light_intensity =
-                        intensities.iter().sum::<f32>() / intensities.len() as f32;
-
-                    match collision_data.1.mat {
-                        material::Material::Lambertian(m) => {
-                            return (color * light_intensity * m.albedo).into();
+                            direct((color * light_intensity * m.albedo).into())
                         }
 
                         material::Material::Metal(m) => {
-                            let norm = collision_data.0.normal.normalize();
+                            let color: Vec3 = m.color.into();
+                            let light_intensity = light_intensity(
+                                collision_point,
+                                collision_normal,
+                                scn,
+                                shadow_samples,
+                                sampler,
+                            );
+
+                            let norm = collision_normal.normalize();
                             let reflected_dir = reflect(&r.dir, &norm).normalize()
-                                + random_point_in_circle() * m.fuzz;
+                                + random_point_in_circle(sampler.rng()) * m.fuzz;
 
                             let reflected_ray =
-                                Ray::new(collision_data.0.point, reflected_dir.normalize());
-
-                            let rcol: Vec3 =
-                                ray_color(&reflected_ray, scn, depth - 1, shadow_samples).into();
-                            return (color * light_intensity * m.albedo + rcol * m.albedo).into();
+                                Ray::new(collision_point, reflected_dir.normalize());
+
+                            let rcol: Vec3 = ray_color(
+                                &reflected_ray,
+                                scn,
+                                media,
+                                depth - 1,
+                                shadow_samples,
+                                sampler,
+                            )
+                            .total()
+                            .into();
+                            Radiance {
+                                direct: (color * light_intensity * m.albedo).into(),
+                                indirect: (rcol * m.albedo).into(),
+                            }
+                        }
+                        material::Material::Dielectric(m) => {
+                            let mut media = *media;
+                            let dir = scatter_dielectric(
+                                &r.dir,
+                                &collision_normal,
+                                &collision_data.0.face,
+                                m.refraction,
+                                &mut media,
+                                sampler.rng(),
+                            );
+
+                            let scattered = Ray::new(collision_point, dir);
+                            Radiance {
+                                direct: Color::default(),
+                                indirect: ray_color(
+                                    &scattered,
+                                    scn,
+                                    &media,
+                                    depth - 1,
+                                    shadow_samples,
+                                    sampler,
+                                )
+                                .total()
+                                    * m.color,
+                            }
                         }
-                        material::Material::Dielectric(_m) => {
-                            // double cos_theta = fmin(dot(-unit_direction, rec.normal), 1.0);
-                            // double sin_theta = sqrt(1.0 - cos_theta*cos_theta);
+                    }
+                }
+            }
+        }
+        None => direct(Color::new(AMBIENT_R, AMBIENT_G, AMBIENT_B)),
+    }
+}
 
-                            // bool cannot_refract = refraction_ratio * sin_theta > 1.0;
-                            // vec3 direction;
+fn direct(color: Color) -> Radiance {
+    Radiance {
+        direct: color,
+        indirect: Color::default(),
+    }
+}
+
+/**
+ * As we've hit the object in the scene, we need to determine it's relation to the light sources,
+ * it might be in the shadow or might be lit. In order to find that out we collide another ray
+ * from collision point towards all the light sources in the scene and light the pixel accordingly.
+ * We do not care about light's color at the moment
+ */
+fn light_intensity(
+    collision_point: Vec3,
+    collision_normal: Vec3,
+    scn: &Scene,
+    shadow_samples: u32,
+    sampler: &mut Sampler,
+) -> f32 {
+    let mut intensities: Vec<f32> = Vec::new();
+    for light in scn.lights() {
+        let mut collisions = 0.0;
+
+        let rays_cnt = shadow_samples;
+        let ll = light.clone();
+
+        let rays = (0..rays_cnt).map(|_| {
+            let geom = &ll.geometry;
+            let u = sampler.get_2d();
+            let point = geom.get_random_point(Vec3::new(u.x, u.y, sampler.get_1d()));
+            Ray::new(collision_point, (geom.pos() + point) - collision_point)
+        });
 
-                            // if (cannot_refract)
-                            // direction = reflect(unit_direction, rec.normal);
-                            // else
-                            // direction = refract(unit_direction, rec.normal, refraction_ratio);
+        rays.for_each(|r| collisions += shadow_ray_transmission(r, scn));
 
-                            // scattered = ray(rec.p, direction);
-                            todo!();
-                        }
-                    }
+        let n = light.geometry.pos();
+        let m = collision_normal.normalize();
+        let dot = m.dot(n).clamp(0.0, 1.0);
 
-                    // for matte
-                    // let target =
-                    //     collision_data.0.point + collision_data.0.normal + random_point_in_circle();
+        let intense = collisions / rays_cnt as f32;
+        intensities.push(dot * intense);
+    }
 
-                    // mirror/metal
+    intensities.iter().sum::<f32>() / intensities.len() as f32
+}
 
-                    // todo: hardcoded material number
-                }
-            }
+/**
+ * How much of the light gets through along the shadow ray. Glass doesn't block the light, it
+ * only tints it, so we keep going through dielectrics. The bending of the ray is ignored as
+ * that would need caustics to get right, but it beats glass casting pitch black shadows
+ */
+fn shadow_ray_transmission(r: Ray, scn: &Scene) -> f32 {
+    let mut r = r;
+    let mut transmission = 1.0;
+
+    for _ in 0..MAX_SHADOW_TRANSMISSIONS {
+        match collide(&r, scn) {
+            None => return 0.0,
+            Some(shadow_coll) => match shadow_coll.1.kind {
+                // todo: check if it's the same light source
+                loader::Kind::Light { .. } => return transmission,
+                loader::Kind::Object => match shadow_coll.1.mat {
+                    material::Material::Dielectric(m) => {
+                        transmission *= (m.color.r + m.color.g + m.color.b) / 3.0;
+                        r = Ray::new(shadow_coll.0.point, r.dir);
+                    }
+                    _ => return 0.0,
+                },
+            },
         }
-        None => Color::new(AMBIENT_R, AMBIENT_G, AMBIENT_B),
     }
+
+    0.0
 }
 
 fn get_bboxes_for(w: i32, h: i32, subdivisions: i32) -> Vec<BBox> {
-    let block_w = w / subdivisions;
-    let block_h = h / subdivisions;
+    // block edges are rounded down so that sizes not divisible by subdivisions still get covered
+    let edge_x = |x: i32| x * w / subdivisions;
+    let edge_y = |y: i32| y * h / subdivisions;
 
     let mut bboxes: Vec<BBox> = Vec::new();
 
@@ -478,10 +809,10 @@ fn get_bboxes_for(w: i32, h: i32, subdivisions: i32) -> Vec<BBox> {
 
     for _i in 0..subdivisions * subdivisions {
         bboxes.push(BBox {
-            x: x * block_w,
-            y: y * block_h,
-            w: block_w,
-            h: block_h,
+            x: edge_x(x),
+            y: edge_y(y),
+            w: edge_x(x + 1) - edge_x(x),
+            h: edge_y(y + 1) - edge_y(y),
         });
 
         match dir {
@@ -550,9 +881,136 @@ pub fn reflect(v: &glam::Vec3, normal: &glam::Vec3) -> glam::Vec3 {
     *v - (*normal * 2.0 * v.dot(*normal))
 }
 
+/**
+ * Refractive indices of the media a ray is in, innermost last, with air around all of them.
+ * Refracting into a dielectric adds its index and refracting out of one takes the innermost off
+ * again, which holds as long as the surfaces of solids nested in each other don't cross
+ */
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Media {
+    indices: [f32; MAX_MEDIA],
+    depth: usize,
+}
+
+impl Media {
+    fn index(&self, depth: usize) -> f32 {
+        match depth.min(MAX_MEDIA) {
+            0 => 1.0,
+            d => self.indices[d - 1],
+        }
+    }
+
+    // the medium the ray is in
+    fn current(&self) -> f32 {
+        self.index(self.depth)
+    }
+
+    // the medium around the one the ray is in
+    fn outside(&self) -> f32 {
+        self.index(self.depth.saturating_sub(1))
+    }
+
+    fn enter(&mut self, refraction: f32) {
+        if self.depth < MAX_MEDIA {
+            self.indices[self.depth] = refraction;
+        }
+        self.depth += 1;
+    }
+
+    fn leave(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+}
+
+/**
+ * Direction a ray continues in after hitting glass, either reflected or refracted. The ratio of
+ * the two is given by the Fresnel equations so we pick one at random with that probability.
+ * The indices on both sides come from the media the ray is in, which refraction updates
+ */
+pub fn scatter_dielectric(
+    dir: &Vec3,
+    normal: &Vec3,
+    face: &Face,
+    refraction: f32,
+    media: &mut Media,
+    rng: &mut Pcg32,
+) -> Vec3 {
+    // the normal always faces the incoming ray so the only thing the face
+    // tells us is whether we're entering the material or leaving it
+    let refraction_ratio = match face {
+        Face::Front => media.current() / refraction,
+        Face::Back => refraction / media.outside(),
+    };
+
+    let unit_dir = dir.normalize();
+    let norm = normal.normalize();
+    let cos_theta = (-unit_dir).dot(norm).min(1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    // past the critical angle there is no refracted ray at all
+    let cannot_refract = refraction_ratio * sin_theta > 1.0;
+
+    if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen::<f32>() {
+        return reflect(&unit_dir, &norm);
+    }
+
+    match face {
+        Face::Front => media.enter(refraction),
+        Face::Back => media.leave(),
+    }
+    refract(&unit_dir, &norm, refraction_ratio)
+}
+
+/*
+    refract unit vector through the surface with the given normal and return a new vector.
+    Snell's law says that eta * sin(theta) = eta' * sin(theta'), so if we split the refracted
+    vector into the part perpendicular to the normal and the part parallel to it:
+    - the perpendicular part is the incoming vector with its normal component removed
+    (v + cos(theta) * N), scaled by eta / eta'
+    - the parallel part is whatever is left to make the result a unit vector, pointing
+    against the normal: -sqrt(1 - |perpendicular|^2) * N
+    Both vectors are expected to be normalized and the normal must face the incoming vector.
+    The caller has to check for total internal reflection, here we assume the ray can refract.
+*/
+pub fn refract(v: &glam::Vec3, normal: &glam::Vec3, etai_over_etat: f32) -> glam::Vec3 {
+    let cos_theta = (-*v).dot(*normal).min(1.0);
+    let r_out_perp = (*v + *normal * cos_theta) * etai_over_etat;
+    let r_out_parallel = -*normal * (1.0 - r_out_perp.length_squared()).abs().sqrt();
+
+    r_out_perp + r_out_parallel
+}
+
+/*
+    Fresnel reflectance using Schlick's approximation: R(theta) = R0 + (1 - R0)(1 - cos)^5.
+    R0 is symmetric in the two indices so the ratio can be given either way around, however
+    the cosine must be taken on the less dense side, which is the refracted one when leaving
+    the material, otherwise glass looks far too transparent at grazing exit angles.
+*/
+pub fn reflectance(cos_theta: f32, etai_over_etat: f32) -> f32 {
+    // with the same index on both sides there's no surface to speak of
+    if etai_over_etat == 1.0 {
+        return 0.0;
+    }
+
+    let r0 = ((1.0 - etai_over_etat) / (1.0 + etai_over_etat)).powi(2);
+
+    let cos = if etai_over_etat > 1.0 {
+        let sin2_t = etai_over_etat * etai_over_etat * (1.0 - cos_theta * cos_theta);
+        if sin2_t >= 1.0 {
+            return 1.0;
+        }
+        (1.0 - sin2_t).sqrt()
+    } else {
+        cos_theta
+    };
+
+    r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::get_bboxes_for;
+    use super::*;
+    use crate::sphere::Sphere;
 
     #[test]
     fn test_bbox_generator4() {
@@ -562,4 +1020,450 @@ mod tests {
         let bboxes = get_bboxes_for(4, 4, 4);
         assert_eq!(bboxes.len(), 16);
     }
+
+    #[test]
+    fn test_bboxes_cover_every_pixel_once() {
+        for (w, h, subdivisions) in [(1920, 1080, 8), (100, 37, 8), (5, 3, 2)] {
+            let mut covered = vec![0; (w * h) as usize];
+
+            for bbox in get_bboxes_for(w, h, subdivisions) {
+                for y in bbox.y..bbox.y + bbox.h {
+                    for x in bbox.x..bbox.x + bbox.w {
+                        covered[(y * w + x) as usize] += 1;
+                    }
+                }
+            }
+
+            assert!(covered.iter().all(|&c| c == 1), "{}x{}", w, h);
+        }
+    }
+
+    fn lambertian(r: f32, g: f32, b: f32) -> Material {
+        Material::Lambertian(material::Lambertian {
+            color: Color::new(r, g, b),
+            albedo: 1.0,
+        })
+    }
+
+    fn glass(refraction: f32) -> Material {
+        Material::Dielectric(material::Dielectric {
+            color: Color::white(),
+            refraction,
+        })
+    }
+
+    // a wall of alternating black and white spheres lit from the camera side. White gets brighter to the right, that way
+    // an image flipped by a lens doesn't average out to the same thing
+    fn checker_scene(size: u64) -> Scene {
+        let mut scene = Scene::new(size, size);
+
+        for i in -2..=2 {
+            for j in -2..=2 {
+                let mat = if (i + j) % 2 == 0 {
+                    let v = (i + 3) as f32 / 5.0;
+                    lambertian(v, v, v)
+                } else {
+                    lambertian(0.0, 0.0, 0.0)
+                };
+                let pos = Vec3::new(i as f32, j as f32, -3.0);
+                scene.add_object(Arc::new(Sphere::new(pos, 0.5)), mat);
+            }
+        }
+
+        let light = Sphere::new(Vec3::new(0.0, 0.0, 10.0), 1.0);
+        scene.add_light(Arc::new(light), lambertian(1.0, 1.0, 1.0), 1.0);
+
+        scene
+    }
+
+    fn render(scene: Scene, size: u64) -> Vec<Color> {
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, 0.0), 60.0);
+        let settings = app::Settings::new(16, 1, 1, 4);
+        let bbox = BBox {
+            x: 0,
+            y: 0,
+            w: size as i32,
+            h: size as i32,
+        };
+
+        let mut scene = scene;
+        scene.finalize();
+
+        let (tx, _rx) = mpsc::channel();
+        let pass = app::Pass::single(&settings);
+        let mut pixels = render_block(Arc::new(scene), camera, settings, pass, bbox, tx).pixels;
+        pixels.sort_by_key(|p| (p.y, p.x));
+        pixels.iter().map(|p| p.totals.mean()).collect()
+    }
+
+    // compares 4x4 block averages so that the sampling noise doesn't drown the actual difference
+    fn mean_difference(a: &[Color], b: &[Color], size: u64) -> f32 {
+        let block = 4;
+        let blocks = size / block;
+        let mut total = 0.0;
+
+        for by in 0..blocks {
+            for bx in 0..blocks {
+                let mut d = Color::default();
+                for y in by * block..(by + 1) * block {
+                    for x in bx * block..(bx + 1) * block {
+                        let i = (y * size + x) as usize;
+                        d = d + a[i] - b[i];
+                    }
+                }
+                d = d / (block * block) as f32;
+                total += d.r.abs() + d.g.abs() + d.b.abs();
+            }
+        }
+
+        total / (blocks * blocks * 3) as f32
+    }
+
+    // a square part of the image
+    fn crop(image: &[Color], size: u64, x: u64, y: u64, w: u64) -> Vec<Color> {
+        (y..y + w)
+            .flat_map(|y| (x..x + w).map(move |x| image[(y * size + x) as usize]))
+            .collect()
+    }
+
+    #[test]
+    fn test_adaptive_sampling_spends_samples_on_noise() {
+        let size = 16;
+        let mut scene = checker_scene(size);
+        scene.finalize();
+
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, 0.0), 60.0);
+        let mut settings = app::Settings::new(4, 1, 1, 1);
+        settings.adaptive_threshold = Some(0.05);
+        settings.max_samples = 64;
+
+        let bbox = BBox {
+            x: 0,
+            y: 0,
+            w: size as i32,
+            h: size as i32,
+        };
+        let (tx, _rx) = mpsc::channel();
+        let pass = app::Pass::single(&settings);
+        let pixels = render_block(Arc::new(scene), camera, settings, pass, bbox, tx).pixels;
+
+        // black spheres and the background have nothing to converge
+        for p in pixels.iter().filter(|p| p.totals.sum.r == 0.0) {
+            assert_eq!(p.totals.samples, 4);
+        }
+
+        // the lit ones with a single light sample do
+        assert!(pixels.iter().any(|p| p.totals.samples == 64));
+        assert!(pixels
+            .iter()
+            .all(|p| p.totals.samples % 4 == 0 && p.totals.samples <= 64));
+    }
+
+    #[test]
+    fn test_passes_carry_on_from_the_film() {
+        let size = 8;
+        let mut scene = checker_scene(size);
+        scene.finalize();
+        let scene = Arc::new(scene);
+
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, 0.0), 60.0);
+        let settings = app::Settings::new(2, 1, 1, 1);
+        let bbox = BBox {
+            x: 0,
+            y: 0,
+            w: size as i32,
+            h: size as i32,
+        };
+        let (tx, _rx) = mpsc::channel();
+
+        let mut film = vec![Totals::default(); (size * size) as usize];
+        for goal in [3, 5] {
+            let pass = app::Pass {
+                goal,
+                film: Arc::new(film.clone()),
+            };
+            for p in render_block(scene.clone(), camera, settings, pass, bbox, tx.clone()).pixels {
+                film[(p.y * size + p.x) as usize].merge(&p.totals);
+            }
+            assert!(film.iter().all(|t| t.samples == goal));
+        }
+
+        // nothing left to do
+        let pass = app::Pass {
+            goal: 5,
+            film: Arc::new(film),
+        };
+        assert!(render_block(scene, camera, settings, pass, bbox, tx)
+            .pixels
+            .is_empty());
+    }
+
+    #[test]
+    fn test_refraction_obeys_snells_law() {
+        let incoming = Vec3::new(1.0, -1.0, 0.0).normalize();
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let refracted = refract(&incoming, &normal, 1.0 / 1.5);
+
+        let sin_in = incoming.cross(normal).length();
+        let sin_out = refracted.cross(normal).length();
+        assert!((sin_in - 1.5 * sin_out).abs() < 1e-5);
+        assert!((refracted.length() - 1.0).abs() < 1e-5);
+        assert!(refracted.y < 0.0);
+    }
+
+    #[test]
+    fn test_total_internal_reflection() {
+        // leaving glass at a grazing angle can't refract so everything is reflected
+        let cos_theta = 0.3;
+        assert_eq!(reflectance(cos_theta, 1.5), 1.0);
+
+        // while head on glass reflects roughly 4%
+        let r = reflectance(1.0, 1.0 / 1.5);
+        assert!((r - 0.04).abs() < 1e-3);
+    }
+
+    // the way through the surface, whatever number comes up reflections are skipped
+    fn refracted(dir: Vec3, face: &Face, refraction: f32, media: &mut Media) -> Vec3 {
+        let normal = Vec3::Y;
+        (0..)
+            .map(|seed| {
+                let mut after = *media;
+                let dir = scatter_dielectric(
+                    &dir,
+                    &normal,
+                    face,
+                    refraction,
+                    &mut after,
+                    &mut Pcg32::new(seed, 0),
+                );
+                (dir, after)
+            })
+            .find(|(dir, _)| dir.dot(normal) < 0.0)
+            .map(|(dir, after)| {
+                *media = after;
+                dir
+            })
+            .unwrap()
+    }
+
+    fn assert_snell(incoming: Vec3, refracted: Vec3, from: f32, to: f32) {
+        let sin_in = incoming.cross(Vec3::Y).length();
+        let sin_out = refracted.cross(Vec3::Y).length();
+        assert!((from * sin_in - to * sin_out).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_nested_media() {
+        let incoming = Vec3::new(1.0, -2.0, 0.0).normalize();
+
+        // from air into glass and from there into water in it
+        let mut media = Media::default();
+        let in_glass = refracted(incoming, &Face::Front, 1.5, &mut media);
+        assert_snell(incoming, in_glass, 1.0, 1.5);
+        let in_water = refracted(in_glass, &Face::Front, 1.33, &mut media);
+        assert_snell(in_glass, in_water, 1.5, 1.33);
+
+        // out of the water is back into the glass, not out into the air
+        let out_of_water = refracted(in_water, &Face::Back, 1.33, &mut media);
+        assert_snell(in_water, out_of_water, 1.33, 1.5);
+        let out_of_glass = refracted(out_of_water, &Face::Back, 1.5, &mut media);
+        assert_snell(out_of_water, out_of_glass, 1.5, 1.0);
+        assert!(out_of_glass.distance(incoming) < 1e-5);
+        assert_eq!(media.current(), 1.0);
+
+        // glass in glass of the same kind isn't there at all, the ray never even reflects off it
+        let mut media = Media::default();
+        refracted(incoming, &Face::Front, 1.5, &mut media);
+        let dir = Vec3::new(0.5, -1.0, 0.0).normalize();
+        for seed in 0..100 {
+            let mut media = media;
+            let through = scatter_dielectric(
+                &dir,
+                &Vec3::Y,
+                &Face::Front,
+                1.5,
+                &mut media,
+                &mut Pcg32::new(seed, 0),
+            );
+            assert!(through.distance(dir) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_glass_ball_renders() {
+        // a ball this small focuses the rays from the camera well in front of the checker
+        let size = 48;
+        let reference = render(checker_scene(size), size);
+
+        let mut scene = checker_scene(size);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, 0.5)), glass(1.5));
+        let image = render(scene, size);
+        assert!(image
+            .iter()
+            .all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()));
+
+        // head on the rays go straight through to the sphere right behind the ball, only the
+        // little that's reflected on the way is missing
+        let center = |image: &[Color]| {
+            let c = crop(image, size, 23, 23, 2)
+                .into_iter()
+                .fold(Color::default(), |acc, c| acc + c);
+            (c.r + c.g + c.b) / 12.0
+        };
+        let (seen, behind) = (center(&image), center(&reference));
+        assert!(seen < behind && seen > behind * 0.85, "{} {}", seen, behind);
+
+        // everywhere else the checker is seen upside down and mirrored, the brighter spheres on
+        // the right show up on the left side of the ball
+        let halves = |image: &[Color]| {
+            let (mut left, mut right) = (0.0, 0.0);
+            for y in 17..31 {
+                for x in 17..31 {
+                    let (dx, dy) = (x as f32 - 23.5, y as f32 - 23.5);
+                    if dx * dx + dy * dy > 36.0 {
+                        continue;
+                    }
+                    let c = image[(y * size + x) as usize];
+                    match x < size / 2 {
+                        true => left += c.r + c.g + c.b,
+                        false => right += c.r + c.g + c.b,
+                    }
+                }
+            }
+            left / right
+        };
+        assert!((halves(&reference) - 1.0).abs() < 0.05);
+        assert!(halves(&image) > 1.2, "{}", halves(&image));
+    }
+
+    #[test]
+    fn test_matched_index_glass_is_invisible() {
+        let size = 32;
+        let reference = render(checker_scene(size), size);
+
+        let mut scene = checker_scene(size);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, 1.0)), glass(1.0));
+        let image = render(scene, size);
+
+        assert!(mean_difference(&reference, &image, size) < 0.03);
+
+        // while a real glass ball distorts the checker behind it
+        let mut scene = checker_scene(size);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, 1.0)), glass(1.5));
+        let image = render(scene, size);
+
+        assert!(mean_difference(&reference, &image, size) > 0.03);
+    }
+
+    #[test]
+    fn test_hollow_glass_ball() {
+        // a negative radius flips the normals so the inner sphere is the inside of the shell
+        let size = 32;
+        let reference = render(checker_scene(size), size);
+
+        let mut scene = checker_scene(size);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, 1.0)), glass(1.5));
+        let solid = render(scene, size);
+
+        let mut scene = checker_scene(size);
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, 1.0)), glass(1.5));
+        scene.add_object(Arc::new(Sphere::new(Vec3::ZERO, -0.9)), glass(1.5));
+        let hollow = render(scene, size);
+
+        // only the middle of the image where the ball is, the rest is the same for all of them
+        let ball = |image: &[Color]| crop(image, size, 8, 8, 16);
+
+        // the shell bends the rays a little on the way in and out again
+        assert!(mean_difference(&ball(&reference), &ball(&hollow), 16) > 0.03);
+        // but nowhere near as much as a solid ball
+        assert!(mean_difference(&ball(&solid), &ball(&hollow), 16) > 0.1);
+    }
+
+    // same kind of scene as generator.py makes but with n*n spheres
+    fn generated_scene(n: i32) -> Scene {
+        let mut scene = Scene::new(1, 1);
+        let mut rng = Pcg32::new(7, 0);
+
+        for i in 0..n {
+            for j in 0..n {
+                let pos = Vec3::new(i as f32, rng.gen_range(1..=10) as f32 / 2.0, j as f32);
+                let radius = 0.2 + rng.gen_range(1..=100) as f32 / 400.0;
+                scene.add_object(
+                    Arc::new(Sphere::new(pos, radius)),
+                    lambertian(1.0, 0.0, 0.0),
+                );
+            }
+        }
+
+        let ground = Sphere::new(Vec3::new(0.0, -99.0, 0.0), 100.0);
+        scene.add_object(Arc::new(ground), lambertian(0.4, 0.8, 0.1));
+        let light = Sphere::new(Vec3::new(100.0, 100.0, 10.0), 40.0);
+        scene.add_light(Arc::new(light), lambertian(1.0, 1.0, 1.0), 1.0);
+
+        scene
+    }
+
+    fn random_rays(n: usize, origin: Vec3) -> Vec<Ray> {
+        let mut rng = Pcg32::new(1, 0);
+        (0..n)
+            .map(|_| Ray::new(origin, random_point_in_circle(&mut rng)))
+            .collect()
+    }
+
+    #[test]
+    fn test_bvh_finds_the_same_hits_as_linear_search() {
+        let mut scene = generated_scene(20);
+        scene.finalize();
+
+        for r in random_rays(2000, Vec3::new(10.0, 3.0, -5.0)) {
+            let linear = collide_linear(&r, &scene);
+            let bvh = collide(&r, &scene);
+
+            match (linear, bvh) {
+                (None, None) => {}
+                (Some(a), Some(b)) => {
+                    assert!((a.0.point - b.0.point).length() < 1e-4);
+                    assert!(Arc::ptr_eq(&a.1.geometry, &b.1.geometry));
+                }
+                _ => panic!("bvh and linear search disagree"),
+            }
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_bvh
+    #[test]
+    #[ignore]
+    fn bench_bvh_against_linear_search() {
+        let mut scene = generated_scene(100);
+
+        let start = std::time::Instant::now();
+        scene.finalize();
+        println!(
+            "BVH over {} objects built in {:?}",
+            scene.objects.len(),
+            start.elapsed()
+        );
+
+        let rays = random_rays(20_000, Vec3::new(50.0, 3.0, -10.0));
+
+        let start = std::time::Instant::now();
+        let linear_hits = rays
+            .iter()
+            .filter(|r| collide_linear(r, &scene).is_some())
+            .count();
+        let linear = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let bvh_hits = rays.iter().filter(|r| collide(r, &scene).is_some()).count();
+        let bvh = start.elapsed();
+
+        assert_eq!(linear_hits, bvh_hits);
+        println!(
+            "{} rays: linear {:?}, bvh {:?} ({:.1}x faster)",
+            rays.len(),
+            linear,
+            bvh,
+            linear.as_secs_f64() / bvh.as_secs_f64()
+        );
+    }
 }
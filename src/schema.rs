@@ -0,0 +1,481 @@
+use crate::material;
+
+use glam::Vec2;
+use glam::Vec3;
+use serde::Deserialize;
+
+/*
+    Scene file schema. The file is a JSON array of entries, each with a "type" telling what it
+    is. Entries are deserialized in two steps: first just the type, then the whole entry as the
+    struct for that type, that way errors point at the exact field instead of at the entry as
+    a whole which is what an internally tagged enum would give us.
+    Unknown fields are errors, a misspelled optional field would otherwise quietly leave it at
+    its default. The fields read in the first step, the type and the transform of shapes, are
+    left out when reading the rest.
+*/
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryType {
+    Camera,
+    Sphere,
+    PointLight,
+    Triangle,
+    Mesh,
+    Output,
+    Plane,
+    Disk,
+    Quad,
+    Box,
+    Cylinder,
+    Cone,
+    Torus,
+    Prototype,
+    Instance,
+    Group,
+    Csg,
+    Sdf,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Entry {
+    #[serde(rename = "type")]
+    pub kind: EntryType,
+}
+
+// read from every shape entry besides its own fields, where it goes in the scene
+#[derive(Deserialize, Debug)]
+pub struct Placement {
+    pub transform: Option<TransformDef>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl From<Point> for Vec3 {
+    fn from(p: Point) -> Vec3 {
+        Vec3::new(p.x, p.y, p.z)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Uv {
+    pub u: f32,
+    pub v: f32,
+}
+
+impl From<Uv> for Vec2 {
+    fn from(uv: Uv) -> Vec2 {
+        Vec2::new(uv.u, uv.v)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "RawColor")]
+pub struct ColorDef(pub material::Color);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawColor {
+    r: f32,
+    g: f32,
+    b: f32,
+}
+
+impl TryFrom<RawColor> for ColorDef {
+    type Error = String;
+
+    fn try_from(c: RawColor) -> Result<ColorDef, String> {
+        for channel in [c.r, c.g, c.b] {
+            if !(0.0..=1.0).contains(&channel) {
+                return Err(format!(
+                    "color channels must be inside [0;1], got {}",
+                    channel
+                ));
+            }
+        }
+
+        Ok(ColorDef(material::Color::new(c.r, c.g, c.b)))
+    }
+}
+
+impl Default for ColorDef {
+    fn default() -> ColorDef {
+        ColorDef(material::Color::white())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaterialType {
+    Lambertian,
+    Metal,
+    Dielectric,
+}
+
+// one struct for all materials, which fields are required depends on the type
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDef {
+    #[serde(rename = "type")]
+    pub kind: MaterialType,
+    #[serde(default)]
+    pub color: ColorDef,
+    #[serde(default = "one")]
+    pub albedo: f32,
+    #[serde(default)]
+    pub fuzz: f32,
+    pub refraction: Option<f32>,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FovAxis {
+    Horizontal,
+    #[default]
+    Vertical,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Projection {
+    #[default]
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDef {
+    pub pos: Point,
+    pub lookat: Point,
+    #[serde(default)]
+    pub projection: Projection,
+    // in degrees, needed by the perspective and fisheye projections
+    pub fov: Option<f32>,
+    // width or height (see fov_axis) of the orthographic view in scene units
+    pub ortho_size: Option<f32>,
+    // which side of the image "fov" is for
+    #[serde(default)]
+    pub fov_axis: FovAxis,
+    // up is +y unless given
+    pub vup: Option<Point>,
+    // depth of field, at most one of the two. Without either it's a pinhole camera
+    pub f_stop: Option<f32>,
+    pub aperture_radius: Option<f32>,
+    // focuses on lookat when not given
+    pub focus_distance: Option<f32>,
+    // polygonal bokeh, round when less than 3
+    #[serde(default)]
+    pub blades: u32,
+    // in degrees
+    #[serde(default)]
+    pub blade_rotation: f32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapOperator {
+    #[default]
+    Linear,
+    Reinhard,
+    ReinhardExtended,
+    Aces,
+    Hable,
+    Agx,
+}
+
+// how the image is turned into 8 bits, the command line overrides any of these
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OutputDef {
+    #[serde(default)]
+    pub tonemap: ToneMapOperator,
+    // in stops
+    #[serde(default)]
+    pub exposure: f32,
+    // extended Reinhard only, radiance that becomes white
+    pub white_point: Option<f32>,
+}
+
+// spheres and point lights
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SphereDef {
+    pub pos: Point,
+    pub radius: f32,
+    pub material: MaterialDef,
+    // lights only, emitted radiance is the material color times this
+    #[serde(default = "one")]
+    pub intensity: f32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TriangleDef {
+    pub v0: Point,
+    pub v1: Point,
+    pub v2: Point,
+    pub normals: Option<[Point; 3]>,
+    pub uvs: Option<[Uv; 3]>,
+    pub material: MaterialDef,
+}
+
+/**
+ * Either loaded from an OBJ file given by "path" (relative to the scene file) or given inline,
+ * where positions/normals are [x, y, z], uvs are [u, v] and indices are [a, b, c]
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MeshDef {
+    pub path: Option<String>,
+    #[serde(default)]
+    pub ignore_mtl: bool,
+    #[serde(default)]
+    pub positions: Vec<[f32; 3]>,
+    #[serde(default)]
+    pub normals: Vec<[f32; 3]>,
+    #[serde(default)]
+    pub uvs: Vec<[f32; 2]>,
+    #[serde(default)]
+    pub indices: Vec<[usize; 3]>,
+    pub material: Option<MaterialDef>,
+}
+
+// infinite, the front is the side the normal points to
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PlaneDef {
+    pub point: Point,
+    pub normal: Point,
+    pub material: MaterialDef,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DiskDef {
+    pub center: Point,
+    pub normal: Point,
+    pub radius: f32,
+    pub material: MaterialDef,
+}
+
+// parallelogram from the corner along the edges u and v, facing the side u x v points to
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct QuadDef {
+    pub corner: Point,
+    pub u: Point,
+    pub v: Point,
+    pub material: MaterialDef,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BoxDef {
+    pub min: Point,
+    pub max: Point,
+    // in degrees around x, then y, then z, about the center of the box. Axis aligned without
+    pub rotation: Option<Point>,
+    pub material: MaterialDef,
+}
+
+// cylinders and cones, a cone's tip is at the top
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CylinderDef {
+    pub base: Point,
+    pub top: Point,
+    pub radius: f32,
+    // closed at the ends, only the base for cones
+    #[serde(default = "yes")]
+    pub capped: bool,
+    pub material: MaterialDef,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TorusDef {
+    pub center: Point,
+    // the ring lies flat around +y unless given
+    pub axis: Option<Point>,
+    // from the center to the middle of the tube
+    pub major_radius: f32,
+    // of the tube
+    pub minor_radius: f32,
+    pub material: MaterialDef,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum ScaleDef {
+    Uniform(f32),
+    PerAxis(Point),
+}
+
+/**
+ * Either scale, rotate and translate, applied in that order with any of them left out, or a
+ * whole matrix. The matrix is given row by row with the translation in the last column, the
+ * last row has to be 0, 0, 0, 1
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct TransformDef {
+    pub translate: Option<Point>,
+    // in degrees around x, then y, then z, like the rotation of boxes
+    pub rotate: Option<Point>,
+    pub scale: Option<ScaleDef>,
+    pub matrix: Option<[[f32; 4]; 4]>,
+}
+
+// shapes that aren't in the scene by themselves, only where instances of them are placed
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PrototypeDef {
+    pub name: String,
+    pub objects: Vec<serde_json::Value>,
+}
+
+// the "transform" of the entry places it
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceDef {
+    pub prototype: String,
+}
+
+/**
+ * Shapes, instances and other groups placed together by the "transform" of the group, which
+ * comes on top of their own. Those without a material get the one of the group
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GroupDef {
+    pub objects: Vec<serde_json::Value>,
+    pub material: Option<MaterialDef>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/**
+ * Two solids combined, each a shape entry or another csg. They don't need materials of their
+ * own, the whole takes the one of the csg entry
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CsgDef {
+    pub op: CsgOperation,
+    pub a: serde_json::Value,
+    pub b: serde_json::Value,
+    pub material: MaterialDef,
+}
+
+// the shape is a tree of the nodes below, read one at a time like the entries
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfDef {
+    pub shape: serde_json::Value,
+    pub material: MaterialDef,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SdfType {
+    Sphere,
+    Box,
+    RoundedBox,
+    Torus,
+    Capsule,
+    SmoothUnion,
+    Subtraction,
+    Repeat,
+    Twist,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SdfNode {
+    #[serde(rename = "type")]
+    pub kind: SdfType,
+}
+
+// the shapes are around the origin unless given a center
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfSphereDef {
+    pub center: Option<Point>,
+    pub radius: f32,
+}
+
+// boxes and rounded boxes, the rounding is within the size
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfBoxDef {
+    pub center: Option<Point>,
+    pub size: Point,
+    pub radius: Option<f32>,
+}
+
+// lying around y
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfTorusDef {
+    pub center: Option<Point>,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfCapsuleDef {
+    pub base: Point,
+    pub top: Point,
+    pub radius: f32,
+}
+
+// smooth unions and subtractions, b is taken out of a. Sharp with no smoothness
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfPairDef {
+    pub a: serde_json::Value,
+    pub b: serde_json::Value,
+    #[serde(default)]
+    pub smoothness: f32,
+}
+
+// count copies on either side of the shape along x, y and z, spacing apart
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfRepeatDef {
+    pub shape: serde_json::Value,
+    pub spacing: Point,
+    pub count: [u32; 3],
+}
+
+// turned around y, more the higher up
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SdfTwistDef {
+    pub shape: serde_json::Value,
+    pub degrees_per_unit: f32,
+}
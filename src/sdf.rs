@@ -0,0 +1,486 @@
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::scene::CollisionData;
+use crate::scene::Hitable;
+
+use glam::Vec2;
+use glam::Vec3;
+use std::f32::consts::PI;
+
+/*
+    Shapes given by signed distance functions: how far the point is from the surface, negative
+    inside. They're intersected by sphere tracing, nothing is closer to a point than its distance
+    so the ray can go that far and ask again, until it's close enough to call it a hit. Normals
+    are the gradient of the distance, from central differences.
+    Twisting bends space so distances aren't quite distances anymore, they can be off by up to
+    the lipschitz factor of the tree and the steps are made that much shorter. Repetition relies
+    on the copies not reaching into the cells of their neighbours.
+*/
+
+const THRESHOLD: f32 = 0.001;
+
+// closer than this to the surface is a hit
+const HIT_EPSILON: f32 = 1e-4;
+
+const NORMAL_EPSILON: f32 = 1e-4;
+
+// rays going along a surface barely closer take tiny steps, they give up after this many
+const MAX_STEPS: usize = 512;
+
+// leaving the surface it's on the ray first has to get further from it than HIT_EPSILON, with
+// steps starting at that and doubling
+const ESCAPE_STEPS: usize = 16;
+
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    Box {
+        center: Vec3,
+        half: Vec3,
+    },
+    // the corners rounded with the radius, within the same size
+    RoundedBox {
+        center: Vec3,
+        half: Vec3,
+        radius: f32,
+    },
+    // lying around the y axis
+    Torus {
+        center: Vec3,
+        major: f32,
+        minor: f32,
+    },
+    Capsule {
+        base: Vec3,
+        top: Vec3,
+        radius: f32,
+    },
+    // blended over about the smoothness where they meet, 0 is a plain union
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        smoothness: f32,
+    },
+    // a with b cut out of it
+    Subtraction {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        smoothness: f32,
+    },
+    // copies spacing apart, count of them on either side of the original along each axis
+    Repeat {
+        shape: Box<Sdf>,
+        spacing: Vec3,
+        count: Vec3,
+    },
+    // turned around y by the rate in radians for every unit up
+    Twist {
+        shape: Box<Sdf>,
+        rate: f32,
+    },
+}
+
+// how far from the y axis anything in the box can be
+fn radius_around_y(bounds: &Aabb) -> f32 {
+    Vec2::new(
+        bounds.min.x.abs().max(bounds.max.x.abs()),
+        bounds.min.z.abs().max(bounds.max.z.abs()),
+    )
+    .length()
+}
+
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+fn twisted(p: Vec3, rate: f32) -> Vec3 {
+    let (sin, cos) = (rate * p.y).sin_cos();
+    Vec3::new(cos * p.x - sin * p.z, p.y, sin * p.x + cos * p.z)
+}
+
+impl Sdf {
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match self {
+            Sdf::Sphere { center, radius } => (p - *center).length() - radius,
+            Sdf::Box { center, half } => {
+                let q = (p - *center).abs() - *half;
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+            }
+            Sdf::RoundedBox {
+                center,
+                half,
+                radius,
+            } => {
+                let q = (p - *center).abs() - *half + Vec3::splat(*radius);
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0) - radius
+            }
+            Sdf::Torus {
+                center,
+                major,
+                minor,
+            } => {
+                let p = p - *center;
+                Vec2::new(Vec2::new(p.x, p.z).length() - major, p.y).length() - minor
+            }
+            Sdf::Capsule { base, top, radius } => {
+                let (pa, ba) = (p - *base, *top - *base);
+                let h = (pa.dot(ba) / ba.dot(ba)).clamp(0.0, 1.0);
+                (pa - ba * h).length() - radius
+            }
+            Sdf::SmoothUnion { a, b, smoothness } => {
+                smooth_min(a.distance(p), b.distance(p), *smoothness)
+            }
+            // the largest of a and outside of b is the smallest of their negatives
+            Sdf::Subtraction { a, b, smoothness } => {
+                -smooth_min(-a.distance(p), b.distance(p), *smoothness)
+            }
+            Sdf::Repeat {
+                shape,
+                spacing,
+                count,
+            } => {
+                // which copy is nearest, axes without copies don't have a spacing to divide by
+                let cell = Vec3::select(
+                    count.cmpgt(Vec3::ZERO),
+                    (p / *spacing).round().clamp(-*count, *count),
+                    Vec3::ZERO,
+                );
+                shape.distance(p - *spacing * cell)
+            }
+            Sdf::Twist { shape, rate } => shape.distance(twisted(p, -rate)),
+        }
+    }
+
+    // the box the surface is inside of
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            Sdf::Sphere { center, radius } => Aabb::new(
+                *center - Vec3::splat(*radius),
+                *center + Vec3::splat(*radius),
+            ),
+            Sdf::Box { center, half } | Sdf::RoundedBox { center, half, .. } => {
+                Aabb::new(*center - *half, *center + *half)
+            }
+            Sdf::Torus {
+                center,
+                major,
+                minor,
+            } => {
+                let extent = Vec3::new(major + minor, *minor, major + minor);
+                Aabb::new(*center - extent, *center + extent)
+            }
+            Sdf::Capsule { base, top, radius } => {
+                let r = Vec3::splat(*radius);
+                Aabb::new(base.min(*top) - r, base.max(*top) + r)
+            }
+            // the blend fills in the gap between them by up to a quarter of the smoothness
+            Sdf::SmoothUnion { a, b, smoothness } => {
+                let bounds = a.bounds().union(&b.bounds());
+                let grow = Vec3::splat(smoothness * 0.25);
+                Aabb::new(bounds.min - grow, bounds.max + grow)
+            }
+            Sdf::Subtraction { a, .. } => a.bounds(),
+            Sdf::Repeat {
+                shape,
+                spacing,
+                count,
+            } => {
+                let bounds = shape.bounds();
+                let reach = *spacing * *count;
+                Aabb::new(bounds.min - reach, bounds.max + reach)
+            }
+            // turning it around y it can reach as far out as its furthest corner
+            Sdf::Twist { shape, .. } => {
+                let bounds = shape.bounds();
+                let radius = radius_around_y(&bounds);
+                Aabb::new(
+                    Vec3::new(-radius, bounds.min.y, -radius),
+                    Vec3::new(radius, bounds.max.y, radius),
+                )
+            }
+        }
+    }
+
+    /**
+     * How much faster than the distance along the ray the distance function can change, the
+     * steps are divided by it. Twisting at the rate r stretches space at the distance d from the
+     * axis by up to sqrt(1 + (r d)^2)
+     */
+    pub fn lipschitz(&self) -> f32 {
+        match self {
+            Sdf::SmoothUnion { a, b, .. } | Sdf::Subtraction { a, b, .. } => {
+                a.lipschitz().max(b.lipschitz())
+            }
+            Sdf::Repeat { shape, .. } => shape.lipschitz(),
+            Sdf::Twist { shape, rate } => {
+                let furthest = radius_around_y(&shape.bounds());
+                shape.lipschitz() * (1.0 + (rate * furthest).powi(2)).sqrt()
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+pub struct SdfShape {
+    sdf: Sdf,
+    bbox: Aabb,
+    lipschitz: f32,
+}
+
+impl SdfShape {
+    pub fn new(sdf: Sdf) -> SdfShape {
+        let bounds = sdf.bounds();
+        // a little room so that the surface isn't right on the edge of the box
+        let margin = Vec3::splat(HIT_EPSILON * 10.0);
+
+        SdfShape {
+            bbox: Aabb::new(bounds.min - margin, bounds.max + margin),
+            lipschitz: sdf.lipschitz(),
+            sdf,
+        }
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let e = |axis: Vec3| {
+            self.sdf.distance(p + axis * NORMAL_EPSILON)
+                - self.sdf.distance(p - axis * NORMAL_EPSILON)
+        };
+        let gradient = Vec3::new(e(Vec3::X), e(Vec3::Y), e(Vec3::Z));
+        if gradient.length_squared() > 0.0 {
+            gradient.normalize()
+        } else {
+            Vec3::Y
+        }
+    }
+
+    // where the ray is inside the box
+    fn span(&self, r: &Ray) -> Option<(f32, f32)> {
+        let inv_dir = r.dir.recip();
+        let t0 = (self.bbox.min - r.origin) * inv_dir;
+        let t1 = (self.bbox.max - r.origin) * inv_dir;
+
+        let t_near = t0.min(t1).max_element().max(THRESHOLD);
+        let t_far = t0.max(t1).min_element();
+        if t_near <= t_far {
+            Some((t_near, t_far))
+        } else {
+            None
+        }
+    }
+}
+
+impl Hitable for SdfShape {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        (u - Vec3::splat(0.5)) * self.bbox.extent()
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.bbox.centroid()
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bbox
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let (mut t, t_far) = self.span(r)?;
+
+        // inside it's the way out that's looked for, the distance is negative all the way there
+        let mut distance = self.sdf.distance(r.at(t));
+        let side = if distance.abs() < HIT_EPSILON {
+            // still on the surface the ray bounced off, it's going to the side the normal says
+            let side = self.normal(r.at(t)).dot(r.dir).signum();
+            let mut step = HIT_EPSILON;
+            for _ in 0..ESCAPE_STEPS {
+                if distance.abs() >= HIT_EPSILON {
+                    break;
+                }
+                t += step;
+                step *= 2.0;
+                distance = self.sdf.distance(r.at(t));
+            }
+            side
+        } else {
+            distance.signum()
+        };
+
+        for _ in 0..MAX_STEPS {
+            if t > t_far {
+                return None;
+            }
+
+            let towards = distance * side;
+            if towards < HIT_EPSILON {
+                let point = r.at(t);
+                let normal = self.normal(point);
+                let around = point - self.pos();
+                let uv = Vec2::new(
+                    (around.z.atan2(around.x) + PI) / (2.0 * PI),
+                    around.normalize_or_zero().y.clamp(-1.0, 1.0).acos() / PI,
+                );
+                return Some(CollisionData::new(r, t, normal, uv));
+            }
+
+            t += towards / self.lipschitz;
+            distance = self.sdf.distance(r.at(t));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Face;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn test_distances() {
+        let cases = [
+            (
+                Sdf::Sphere {
+                    center: Vec3::ZERO,
+                    radius: 1.0,
+                },
+                Vec3::new(3.0, 0.0, 0.0),
+                2.0,
+            ),
+            (
+                Sdf::Box {
+                    center: Vec3::ZERO,
+                    half: Vec3::ONE,
+                },
+                Vec3::new(0.5, 0.0, 0.0),
+                -0.5,
+            ),
+            (
+                Sdf::RoundedBox {
+                    center: Vec3::ZERO,
+                    half: Vec3::ONE,
+                    radius: 0.5,
+                },
+                Vec3::splat(2.0),
+                3.0f32.sqrt() * 1.5 - 0.5,
+            ),
+            (
+                Sdf::Torus {
+                    center: Vec3::ZERO,
+                    major: 2.0,
+                    minor: 0.5,
+                },
+                Vec3::new(0.0, 0.0, 2.0),
+                -0.5,
+            ),
+            (
+                Sdf::Capsule {
+                    base: Vec3::ZERO,
+                    top: Vec3::Y,
+                    radius: 0.5,
+                },
+                Vec3::new(1.0, 0.5, 0.0),
+                0.5,
+            ),
+        ];
+        for (sdf, p, expected) in cases {
+            let d = sdf.distance(p);
+            assert!((d - expected).abs() < 1.0e-5, "{:?} {}", sdf, d);
+        }
+    }
+
+    #[test]
+    fn test_combinators() {
+        let ball = |x: f32| {
+            Box::new(Sdf::Sphere {
+                center: Vec3::new(x, 0.0, 0.0),
+                radius: 1.0,
+            })
+        };
+
+        // smoothing fills in the gap between the balls, without it's a plain union
+        let between = Vec3::new(0.0, 1.1, 0.0);
+        let union = |smoothness| Sdf::SmoothUnion {
+            a: ball(-0.5),
+            b: ball(0.5),
+            smoothness,
+        };
+        assert!((union(0.0).distance(Vec3::new(3.0, 0.0, 0.0)) - 1.5).abs() < 1.0e-5);
+        assert!(union(0.0).distance(between) > 0.0);
+        assert!(union(1.0).distance(between) < 0.0);
+
+        let bitten = Sdf::Subtraction {
+            a: ball(0.0),
+            b: ball(1.0),
+            smoothness: 0.0,
+        };
+        assert!(bitten.distance(Vec3::new(0.75, 0.0, 0.0)) > 0.0);
+        assert!(bitten.distance(Vec3::new(-0.75, 0.0, 0.0)) < 0.0);
+
+        // balls at -4, -2, 0, 2 and 4 along x
+        let row = Sdf::Repeat {
+            shape: ball(0.0),
+            spacing: Vec3::new(2.0, 0.0, 0.0),
+            count: Vec3::new(2.0, 0.0, 0.0),
+        };
+        assert!(row.distance(Vec3::new(4.0, 0.0, 0.0)) < 0.0);
+        assert!((row.distance(Vec3::new(7.0, 0.0, 0.0)) - 2.0).abs() < 1.0e-5);
+        assert!((row.bounds().max.x - 5.0).abs() < 1.0e-5);
+        // a quarter turn for every unit up, one unit above the middle the box lies along z
+        let twisted = Sdf::Twist {
+            shape: Box::new(Sdf::Box {
+                center: Vec3::ZERO,
+                half: Vec3::new(1.0, 2.0, 0.1),
+            }),
+            rate: PI / 2.0,
+        };
+        assert!(twisted.distance(Vec3::new(0.0, 1.0, 0.9)) < 0.0);
+        assert!(twisted.distance(Vec3::new(0.9, 1.0, 0.0)) > 0.0);
+        assert!(twisted.distance(Vec3::new(0.9, 0.0, 0.0)) < 0.0);
+        assert!(twisted.lipschitz() > 1.0);
+    }
+
+    #[test]
+    fn test_sphere_tracing() {
+        // has to agree with the analytic sphere
+        let shape = SdfShape::new(Sdf::Sphere {
+            center: Vec3::new(0.0, 1.0, 0.0),
+            radius: 1.0,
+        });
+        let sphere = Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0);
+
+        for i in 0..20 {
+            let target = Vec3::new(i as f32 / 20.0 - 0.5, 1.0 + (i % 7) as f32 / 10.0, 0.0);
+            let r = Ray::new(Vec3::new(0.3, 0.5, 5.0), target - Vec3::new(0.3, 0.5, 5.0));
+            let (traced, exact) = (shape.hit(&r).unwrap(), sphere.hit(&r).unwrap());
+            assert!(traced.point.distance(exact.point) < 1.0e-3);
+            assert!(traced.normal.distance(exact.normal) < 1.0e-2);
+            assert!(matches!(traced.face, Face::Front));
+        }
+
+        assert!(shape
+            .hit(&Ray::new(Vec3::new(0.0, 3.0, 5.0), -Vec3::Z))
+            .is_none());
+
+        // from inside, and from the point where it went in, it's the way out
+        let inside = shape
+            .hit(&Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::X))
+            .unwrap();
+        assert!(inside.point.distance(Vec3::new(1.0, 1.0, 0.0)) < 1.0e-3);
+        assert!(matches!(inside.face, Face::Back));
+
+        let entry = shape
+            .hit(&Ray::new(Vec3::new(-5.0, 1.0, 0.0), Vec3::X))
+            .unwrap();
+        let through = shape.hit(&Ray::new(entry.point, Vec3::X)).unwrap();
+        assert!(through.point.distance(Vec3::new(1.0, 1.0, 0.0)) < 1.0e-3);
+        assert!(matches!(through.face, Face::Back));
+    }
+}
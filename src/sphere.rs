@@ -1,10 +1,14 @@
+use crate::aabb::Aabb;
 use crate::ray::Ray;
-use crate::scene::random_point_in_circle;
+use crate::rng::Pcg32;
+use crate::sampling;
 use crate::scene::CollisionData;
 use crate::scene::Face;
 use crate::scene::Hitable;
 
+use glam::Vec2;
 use glam::Vec3;
+use std::f32::consts::PI;
 
 #[derive(Copy, Clone)]
 pub struct Sphere {
@@ -21,16 +25,35 @@ impl Sphere {
 const THRESHOLD: f32 = 0.001;
 
 impl Hitable for Sphere {
-    fn get_random_point(&self) -> Vec3 {
-        random_point_in_circle() * self.radius
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        sampling::uniform_ball(u) * self.radius
     }
 
     fn pos(&self) -> Vec3 {
         self.pos
     }
 
-    fn pos_mut(&mut self) -> &mut Vec3 {
-        &mut self.pos
+    fn bounding_box(&self) -> Aabb {
+        // radius can be negative for hollow spheres
+        let r = Vec3::splat(self.radius.abs());
+        Aabb::new(self.pos - r, self.pos + r)
+    }
+
+    // picks directions inside the cone the sphere covers as seen from the point
+    fn sample_direction(&self, from: Vec3, rng: &mut Pcg32) -> Option<(Vec3, f32)> {
+        let to_center = self.pos - from;
+        let distance_squared = to_center.length_squared();
+        let radius_squared = self.radius * self.radius;
+
+        // from inside every direction hits the sphere
+        if distance_squared <= radius_squared {
+            return Some((sampling::uniform_sphere(rng), 1.0 / (4.0 * PI)));
+        }
+
+        let cos_max = (1.0 - radius_squared / distance_squared).sqrt();
+        let dir = sampling::uniform_cone(to_center.normalize(), cos_max, rng);
+
+        Some((dir, 1.0 / (2.0 * PI * (1.0 - cos_max))))
     }
 
     fn hit(&self, r: &Ray) -> Option<CollisionData> {
@@ -42,14 +65,22 @@ impl Hitable for Sphere {
         if discriminant < 0.0 {
             None
         } else {
-            let solution = (-b - discriminant.sqrt()) / (2.0 * a);
+            // the nearest root is behind the origin when the ray starts inside the sphere
+            // (refracted rays do), in that case the far root is the exit point
+            let sqrtd = discriminant.sqrt();
+            let mut solution = (-b - sqrtd) / (2.0 * a);
 
             if solution <= THRESHOLD {
-                return None;
+                solution = (-b + sqrtd) / (2.0 * a);
+
+                if solution <= THRESHOLD {
+                    return None;
+                }
             }
 
-            let point = r.at(solution as f32);
+            let point = r.at(solution);
             let mut normal = (point - self.pos) / self.radius;
+            let uv = sphere_uv(normal);
             let face: Face;
 
             if normal.dot(r.dir) > 0.0 {
@@ -63,7 +94,16 @@ impl Hitable for Sphere {
                 face,
                 normal,
                 point,
+                uv,
             })
         }
     }
 }
+
+// u goes around the y axis starting from -x, v goes from the bottom pole to the top one
+pub fn sphere_uv(n: Vec3) -> Vec2 {
+    let theta = (-n.y).clamp(-1.0, 1.0).acos();
+    let phi = (-n.z).atan2(n.x) + PI;
+
+    Vec2::new(phi / (2.0 * PI), theta / PI)
+}
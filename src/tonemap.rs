@@ -0,0 +1,237 @@
+use crate::material::Color;
+
+use glam::Mat3;
+use glam::Vec3;
+
+/*
+    Tone mapping squashes the unbounded radiance on the film into what a display can show. It's
+    only done when saving to 8 bits, the float formats get the film as is. The steps are:
+    - exposure, a multiplier given in stops (EV), +1 doubles the light
+    - the operator, maps [0;inf) onto [0;1] in linear display values
+    - sRGB transfer function, encodes the linear values the way monitors expect them
+*/
+
+#[derive(clap::ArgEnum, PartialEq, Eq, Debug, Copy, Clone)]
+#[clap(rename_all = "snake_case")]
+pub enum Operator {
+    // clips everything above 1
+    Linear,
+    // x / (1 + x), never quite reaches white
+    Reinhard,
+    // Reinhard that reaches white at the white point
+    ReinhardExtended,
+    // filmic curve fitted to the ACES reference rendering transform (Narkowicz)
+    Aces,
+    // filmic curve from Uncharted 2 (John Hable)
+    Hable,
+    // AgX by Troy Sobotka, desaturates very bright colors towards white the way film does
+    Agx,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ToneMapping {
+    pub operator: Operator,
+    // in stops
+    pub exposure: f32,
+    // for the extended Reinhard, the radiance that ends up white
+    pub white_point: f32,
+}
+
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping {
+            operator: Operator::Linear,
+            exposure: 0.0,
+            white_point: 4.0,
+        }
+    }
+}
+
+impl ToneMapping {
+    // linear display color in [0;1]
+    pub fn apply(&self, c: Color) -> Color {
+        let x = Vec3::from(c).max(Vec3::ZERO) * 2.0_f32.powf(self.exposure);
+
+        let mapped = match self.operator {
+            Operator::Linear => x,
+            Operator::Reinhard => x / (Vec3::ONE + x),
+            Operator::ReinhardExtended => {
+                let w2 = self.white_point * self.white_point;
+                x * (Vec3::ONE + x / w2) / (Vec3::ONE + x)
+            }
+            Operator::Aces => aces(x),
+            Operator::Hable => hable(x),
+            Operator::Agx => agx(x),
+        };
+
+        mapped.clamp(Vec3::ZERO, Vec3::ONE).into()
+    }
+
+    // what ends up in an 8 bit image
+    pub fn to_srgb8(self, c: Color) -> [u8; 3] {
+        let c = self.apply(c);
+        let quantize = |v: f32| (srgb_oetf(v) * 255.0).round() as u8;
+
+        [quantize(c.r), quantize(c.g), quantize(c.b)]
+    }
+}
+
+// sRGB transfer function, linear below a small threshold and roughly a 2.4 power above it
+pub fn srgb_oetf(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn aces(x: Vec3) -> Vec3 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    (x * (a * x + b)) / (x * (c * x + d) + e)
+}
+
+fn hable(x: Vec3) -> Vec3 {
+    fn curve(x: Vec3) -> Vec3 {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - Vec3::splat(e / f)
+    }
+
+    // the curve is normalized to be white at W, the exposure bias is the one from the talk
+    let white = 11.2;
+    let exposure_bias = 2.0;
+    curve(x * exposure_bias) / curve(Vec3::splat(white))
+}
+
+/**
+ * Minimal AgX: the color is moved into a slightly desaturated working space, squashed
+ * logarithmically between min and max EV and shaped by a sigmoid, after which it's moved back
+ * and turned into linear display values. Matrices and the polynomial fit of the sigmoid are the
+ * usual ones from the reference shader
+ */
+fn agx(x: Vec3) -> Vec3 {
+    let inset = Mat3::from_cols_array(&[
+        0.8424791, 0.04232824, 0.04237565, 0.0784336, 0.8784686, 0.0784336, 0.07922374, 0.07916613,
+        0.879143,
+    ]);
+    let outset = Mat3::from_cols_array(&[
+        1.196879,
+        -0.05289685,
+        -0.05297164,
+        -0.09802088,
+        1.151903,
+        -0.09804345,
+        -0.09902974,
+        -0.09896118,
+        1.151074,
+    ]);
+    let (min_ev, max_ev) = (-12.47393_f32, 4.026069_f32);
+
+    let v = inset * x;
+    let log = |c: f32| (c.max(1e-10).log2().clamp(min_ev, max_ev) - min_ev) / (max_ev - min_ev);
+    let v = Vec3::new(log(v.x), log(v.y), log(v.z));
+
+    let sigmoid = |x: f32| {
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    };
+    let v = outset * Vec3::new(sigmoid(v.x), sigmoid(v.y), sigmoid(v.z));
+
+    // the sigmoid gives display encoded values, undo the 2.2 display gamma
+    v.max(Vec3::ZERO).powf(2.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(v: f32) -> Color {
+        Color::new(v, v, v)
+    }
+
+    fn tone_mapping(operator: Operator) -> ToneMapping {
+        ToneMapping {
+            operator,
+            ..ToneMapping::default()
+        }
+    }
+
+    #[test]
+    fn test_srgb_oetf() {
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb_oetf(0.18) - 0.4613).abs() < 1e-3);
+
+        // the two pieces meet
+        let t = 0.0031308;
+        assert!((srgb_oetf(t - 1e-7) - srgb_oetf(t + 1e-7)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_operators() {
+        let linear = tone_mapping(Operator::Linear);
+        assert_eq!(linear.apply(grey(0.5)).r, 0.5);
+        assert_eq!(linear.apply(grey(7.0)).r, 1.0);
+
+        assert_eq!(tone_mapping(Operator::Reinhard).apply(grey(1.0)).r, 0.5);
+
+        let extended = ToneMapping {
+            white_point: 3.0,
+            ..tone_mapping(Operator::ReinhardExtended)
+        };
+        assert!((extended.apply(grey(3.0)).r - 1.0).abs() < 1e-6);
+
+        assert!((tone_mapping(Operator::Hable).apply(grey(5.6)).r - 1.0).abs() < 1e-5);
+
+        for operator in [Operator::Aces, Operator::Hable, Operator::Agx] {
+            let tm = tone_mapping(operator);
+            assert!(tm.apply(grey(0.0)).r < 0.01, "{:?}", operator);
+            assert!(tm.apply(grey(1000.0)).r > 0.95, "{:?}", operator);
+
+            // mid grey stays somewhere in the middle
+            let mid = tm.apply(grey(0.18)).r;
+            assert!(mid > 0.05 && mid < 0.5, "{:?}: {}", operator, mid);
+        }
+    }
+
+    #[test]
+    fn test_operators_keep_order() {
+        let operators = [
+            Operator::Linear,
+            Operator::Reinhard,
+            Operator::ReinhardExtended,
+            Operator::Aces,
+            Operator::Hable,
+            Operator::Agx,
+        ];
+
+        for operator in operators {
+            let tm = tone_mapping(operator);
+            let mut previous = -1.0;
+
+            for i in 0..200 {
+                let v = tm.apply(grey(i as f32 * 0.05)).g;
+                assert!(v >= previous - 1e-6, "{:?} at {}", operator, i);
+                assert!((0.0..=1.0).contains(&v));
+                previous = v;
+            }
+        }
+    }
+
+    #[test]
+    fn test_exposure() {
+        let tm = ToneMapping {
+            exposure: 1.0,
+            ..ToneMapping::default()
+        };
+        assert_eq!(tm.apply(grey(0.25)).r, 0.5);
+
+        let tm = ToneMapping {
+            exposure: -2.0,
+            ..ToneMapping::default()
+        };
+        assert_eq!(tm.to_srgb8(grey(4.0)), [255, 255, 255]);
+        assert_eq!(tm.to_srgb8(grey(0.0)), [0, 0, 0]);
+    }
+}
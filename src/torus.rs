@@ -0,0 +1,273 @@
+use crate::aabb::Aabb;
+use crate::frame::Frame;
+use crate::ray::Ray;
+use crate::scene::CollisionData;
+use crate::scene::Hitable;
+
+use glam::DVec3;
+use glam::Vec2;
+use glam::Vec3;
+use std::f64::consts::PI;
+
+const THRESHOLD: f32 = 0.001;
+
+// Newton steps every root of the quartic is polished with
+const POLISH_STEPS: usize = 3;
+
+/**
+ * Ring around the axis through the center. The major radius goes from the center to the middle
+ * of the tube, the minor one is the radius of the tube. u goes around the axis and v around the
+ * tube, it's 0.5 on the outer equator
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Torus {
+    frame: Frame,
+    major: f32,
+    minor: f32,
+}
+
+impl Torus {
+    pub fn new(center: Vec3, axis: Vec3, major: f32, minor: f32) -> Torus {
+        Torus {
+            frame: Frame::around(center, axis.normalize()),
+            major,
+            minor,
+        }
+    }
+}
+
+impl Hitable for Torus {
+    // uniform in the angles rather than in the volume, the inner side gets a few more
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        let phi = 2.0 * std::f32::consts::PI * u.x;
+        let theta = 2.0 * std::f32::consts::PI * u.y;
+        let distance = self.major + self.minor * u.z.sqrt() * theta.cos();
+        let y = self.minor * u.z.sqrt() * theta.sin();
+
+        self.frame
+            .world_direction(Vec3::new(distance * phi.cos(), y, distance * phi.sin()))
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.frame.origin
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let outer = self.major + self.minor;
+        self.frame.bounding_box(
+            Vec3::new(-outer, -self.minor, -outer),
+            Vec3::new(outer, self.minor, outer),
+        )
+    }
+
+    /**
+     * (x^2 + y^2 + z^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2) with the axis along y. Putting the ray
+     * in gives a quartic in the distance, solved in double precision as the coefficients grow
+     * with the fourth power of the distance to the torus
+     */
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let local = self.frame.local_ray(r);
+        let o = local.origin.as_dvec3();
+        let d = local.dir.as_dvec3();
+        let (major2, minor2) = ((self.major as f64).powi(2), (self.minor as f64).powi(2));
+
+        let e = o.dot(o) - major2 - minor2;
+        let f = o.dot(d);
+        let dd = d.dot(d);
+        let four_major2 = 4.0 * major2;
+
+        let roots = solve_quartic([
+            dd * dd,
+            4.0 * dd * f,
+            2.0 * dd * e + 4.0 * f * f + four_major2 * d.y * d.y,
+            4.0 * f * e + 2.0 * four_major2 * o.y * d.y,
+            e * e - four_major2 * (minor2 - o.y * o.y),
+        ]);
+
+        let t = roots
+            .into_iter()
+            .filter(|&t| t > THRESHOLD as f64)
+            .min_by(|a, b| a.total_cmp(b))?;
+
+        let p = o + d * t;
+        let outward = normal(p, major2, minor2);
+        let around_axis = (p.z.atan2(p.x) + PI) / (2.0 * PI);
+        let ring = (p.x * p.x + p.z * p.z).sqrt() - self.major as f64;
+        let around_tube = (p.y.atan2(ring) + PI) / (2.0 * PI);
+
+        Some(CollisionData::new(
+            r,
+            t as f32,
+            self.frame.world_direction(outward.as_vec3()).normalize(),
+            Vec2::new(around_axis as f32, around_tube as f32),
+        ))
+    }
+}
+
+// gradient of the implicit function
+fn normal(p: DVec3, major2: f64, minor2: f64) -> DVec3 {
+    let s = p.dot(p) - major2 - minor2;
+    DVec3::new(p.x * s, p.y * (s + 2.0 * major2), p.z * s).normalize()
+}
+
+/**
+ * Real roots of c[0] x^4 + c[1] x^3 + c[2] x^2 + c[3] x + c[4] = 0, by Ferrari's method: the
+ * depressed quartic is split into two quadratics using a root of its resolvent cubic. The
+ * closed form loses digits, the roots are polished with a few Newton steps on the original
+ */
+fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    if c[0].abs() < 1e-12 {
+        return Vec::new();
+    }
+    let (a, b, cc, d) = (c[1] / c[0], c[2] / c[0], c[3] / c[0], c[4] / c[0]);
+
+    // x = y - a / 4 gives y^4 + p y^2 + q y + r = 0
+    let a2 = a * a;
+    let p = b - 3.0 * a2 / 8.0;
+    let q = cc - a * b / 2.0 + a2 * a / 8.0;
+    let r = d - a * cc / 4.0 + a2 * b / 16.0 - 3.0 * a2 * a2 / 256.0;
+
+    let mut roots = Vec::with_capacity(4);
+    if q.abs() < 1e-12 {
+        // biquadratic, a quadratic in y^2
+        for z in solve_quadratic(1.0, p, r) {
+            if z >= 0.0 {
+                roots.push(z.sqrt());
+                roots.push(-z.sqrt());
+            }
+        }
+    } else {
+        // the resolvent m^3 + p m^2 + (p^2 / 4 - r) m - q^2 / 8 has a positive root
+        let m = largest_cubic_root(p, p * p / 4.0 - r, -q * q / 8.0);
+        if m <= 0.0 {
+            return Vec::new();
+        }
+        let s = (2.0 * m).sqrt();
+
+        roots.extend(solve_quadratic(1.0, s, p / 2.0 + m - q / (2.0 * s)));
+        roots.extend(solve_quadratic(1.0, -s, p / 2.0 + m + q / (2.0 * s)));
+    }
+
+    roots
+        .into_iter()
+        .map(|y| {
+            let mut x = y - a / 4.0;
+            for _ in 0..POLISH_STEPS {
+                let value = (((x + a) * x + b) * x + cc) * x + d;
+                let slope = ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + cc;
+                if slope.abs() < 1e-12 {
+                    break;
+                }
+                x -= value / slope;
+            }
+            x
+        })
+        .collect()
+}
+
+// real roots of a x^2 + b x + c = 0
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return Vec::new();
+    }
+
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    if q == 0.0 {
+        return vec![0.0];
+    }
+    vec![q / a, c / q]
+}
+
+// of x^3 + a x^2 + b x + c = 0, which always has at least one
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    // x = w - a / 3 gives w^3 + p w + q = 0
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+
+    let w = if discriminant > 0.0 {
+        let sqrt = discriminant.sqrt();
+        (-q / 2.0 + sqrt).cbrt() + (-q / 2.0 - sqrt).cbrt()
+    } else {
+        // three real roots, the first of the trigonometric ones is the largest
+        let radius = (-p / 3.0).sqrt();
+        let cos = if radius > 0.0 {
+            (-q / (2.0 * radius * radius * radius)).clamp(-1.0, 1.0)
+        } else {
+            1.0
+        };
+        2.0 * radius * (cos.acos() / 3.0).cos()
+    };
+
+    w - a / 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Face;
+
+    #[test]
+    fn test_solve_quartic() {
+        // (x - 1)(x - 2)(x + 3)(x - 0.5)
+        let mut roots = solve_quartic([1.0, -0.5, -7.0, 9.5, -3.0]);
+        roots.sort_by(|a, b| a.total_cmp(b));
+        let expected = [-3.0, 0.5, 1.0, 2.0];
+        assert_eq!(roots.len(), 4);
+        for (root, e) in roots.iter().zip(expected) {
+            assert!((root - e).abs() < 1e-9, "{:?}", roots);
+        }
+
+        // x^4 + 1 has none
+        assert!(solve_quartic([1.0, 0.0, 0.0, 0.0, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn test_torus() {
+        let torus = Torus::new(Vec3::ZERO, Vec3::Y, 2.0, 0.5);
+
+        // through the tube on both sides of the hole
+        let hit = torus
+            .hit(&Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::X))
+            .unwrap();
+        assert!(hit.point.distance(Vec3::new(-2.5, 0.0, 0.0)) < 1.0e-4);
+        assert!(hit.normal.distance(-Vec3::X) < 1.0e-4);
+        assert!(matches!(hit.face, Face::Front));
+
+        // down the hole there's nothing
+        assert!(torus
+            .hit(&Ray::new(Vec3::new(0.0, 5.0, 0.0), -Vec3::Y))
+            .is_none());
+
+        // onto the top of the tube
+        let top = torus
+            .hit(&Ray::new(Vec3::new(0.0, 5.0, 2.0), -Vec3::Y))
+            .unwrap();
+        assert!(top.point.distance(Vec3::new(0.0, 0.5, 2.0)) < 1.0e-4);
+        assert!(top.normal.distance(Vec3::Y) < 1.0e-4);
+
+        // from inside the tube
+        let inside = torus
+            .hit(&Ray::new(Vec3::new(2.0, 0.0, 0.0), Vec3::X))
+            .unwrap();
+        assert!(matches!(inside.face, Face::Back));
+        assert!(inside.point.distance(Vec3::new(2.5, 0.0, 0.0)) < 1.0e-4);
+
+        assert!(hit.uv.cmpge(Vec2::ZERO).all() && hit.uv.cmple(Vec2::ONE).all());
+    }
+
+    #[test]
+    fn test_standing_torus() {
+        // around x, so it stands upright in the yz plane
+        let torus = Torus::new(Vec3::new(0.0, 1.0, 0.0), Vec3::X, 1.0, 0.25);
+        let bbox = torus.bounding_box();
+        assert!((bbox.max.y - 2.25).abs() < 1.0e-5 && (bbox.max.x - 0.25).abs() < 1.0e-5);
+
+        let hit = torus
+            .hit(&Ray::new(Vec3::new(5.0, 2.0, 0.0), -Vec3::X))
+            .unwrap();
+        assert!(hit.point.distance(Vec3::new(0.25, 2.0, 0.0)) < 1.0e-4);
+        assert!(hit.normal.distance(Vec3::X) < 1.0e-4);
+    }
+}
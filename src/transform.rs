@@ -0,0 +1,229 @@
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::rng::Pcg32;
+use crate::scene::CollisionData;
+use crate::scene::Hitable;
+
+use glam::Mat3;
+use glam::Mat4;
+use glam::Vec3;
+use std::sync::Arc;
+
+/*
+    Shapes placed with an affine matrix: moved, turned, scaled even unevenly or mirrored. Rays
+    are brought into the space of the shape and the hits taken back out, normals with the
+    inverse transpose so they stay perpendicular to the surface. The shape is behind an Arc so
+    any number of copies can be placed without copying it, which is what instancing is.
+    Distances along the ray aren't kept by scaling, which is why the ray in object space gets
+    normalized again and hits are only compared by their world space points.
+*/
+
+// the directions are normalized so it's only a scale that goes wrong with this
+const SINGULAR_EPSILON: f32 = 1e-12;
+
+pub struct Transformed<H: Hitable + ?Sized> {
+    inner: Arc<H>,
+    // object space to world space
+    to_world: Mat4,
+    to_object: Mat4,
+    // takes normals from object to world space
+    normals: Mat3,
+    bbox: Aabb,
+}
+
+impl<H: Hitable + ?Sized> Transformed<H> {
+    // the matrix has to be invertible, the loader makes sure of that
+    pub fn new(inner: Arc<H>, to_world: Mat4) -> Transformed<H> {
+        let inner_box = inner.bounding_box();
+        let bbox = (0..8).fold(Aabb::empty(), |acc, corner| {
+            let pick = |bit: u32, lo: f32, hi: f32| if corner & bit == 0 { lo } else { hi };
+            let p = Vec3::new(
+                pick(1, inner_box.min.x, inner_box.max.x),
+                pick(2, inner_box.min.y, inner_box.max.y),
+                pick(4, inner_box.min.z, inner_box.max.z),
+            );
+            acc.grow(to_world.transform_point3(p))
+        });
+
+        Transformed {
+            inner,
+            to_world,
+            to_object: to_world.inverse(),
+            normals: Mat3::from_mat4(to_world).inverse().transpose(),
+            bbox,
+        }
+    }
+
+    fn world_normal(&self, n: Vec3) -> Vec3 {
+        (self.normals * n).normalize()
+    }
+
+    /**
+     * How much the matrix stretches the surface with the normal in object space, areas in world
+     * space are this much bigger
+     */
+    fn area_scale(&self, n: Vec3) -> f32 {
+        Mat3::from_mat4(self.to_world).determinant().abs() * (self.normals * n).length()
+    }
+}
+
+impl<H: Hitable + ?Sized> Hitable for Transformed<H> {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        let local = self.inner.pos() + self.inner.get_random_point(u);
+        self.to_world.transform_point3(local) - self.pos()
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.to_world.transform_point3(self.inner.pos())
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bbox
+    }
+
+    /**
+     * The shape picks a direction in object space, which is followed to the point on it. That's
+     * then taken to world space, the density with it: per solid angle to per area, stretched by
+     * the matrix, and back to per solid angle as seen from the world space point
+     */
+    fn sample_direction(&self, from: Vec3, rng: &mut Pcg32) -> Option<(Vec3, f32)> {
+        let local_from = self.to_object.transform_point3(from);
+        let (local_dir, local_pdf) = self.inner.sample_direction(local_from, rng)?;
+        let hit = self.inner.hit(&Ray::new(local_from, local_dir))?;
+
+        let local_distance_squared = hit.point.distance_squared(local_from);
+        let local_cos = hit.normal.dot(local_dir).abs();
+        let area_pdf = local_pdf * local_cos / local_distance_squared / self.area_scale(hit.normal);
+
+        let to_point = self.to_world.transform_point3(hit.point) - from;
+        let dir = to_point.normalize();
+        let cos = self.world_normal(hit.normal).dot(dir).abs();
+        if cos < SINGULAR_EPSILON {
+            return None;
+        }
+
+        Some((dir, area_pdf * to_point.length_squared() / cos))
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let local = Ray::new(
+            self.to_object.transform_point3(r.origin),
+            self.to_object.transform_vector3(r.dir),
+        );
+        let hit = self.inner.hit(&local)?;
+
+        // n . d doesn't change sign with the inverse transpose, so the face stays what it was
+        Some(CollisionData {
+            face: hit.face,
+            normal: self.world_normal(hit.normal),
+            point: self.to_world.transform_point3(hit.point),
+            uv: hit.uv,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plane::Quad;
+    use crate::scene::Face;
+    use crate::sphere::Sphere;
+    use glam::Quat;
+
+    #[test]
+    fn test_scaled_sphere() {
+        // squashed into an ellipsoid 4 wide, 1 high and 2 deep, then moved up
+        let sphere: Arc<dyn Hitable> = Arc::new(Sphere::new(Vec3::ZERO, 1.0));
+        let matrix = Mat4::from_scale_rotation_translation(
+            Vec3::new(2.0, 0.5, 1.0),
+            Quat::IDENTITY,
+            Vec3::new(0.0, 3.0, 0.0),
+        );
+        let ellipsoid = Transformed::new(sphere.clone(), matrix);
+
+        let side = ellipsoid
+            .hit(&Ray::new(Vec3::new(10.0, 3.0, 0.0), -Vec3::X))
+            .unwrap();
+        assert!(side.point.distance(Vec3::new(2.0, 3.0, 0.0)) < 1.0e-5);
+        assert!(side.normal.distance(Vec3::X) < 1.0e-5);
+        assert!(matches!(side.face, Face::Front));
+
+        let top = ellipsoid
+            .hit(&Ray::new(Vec3::new(0.0, 10.0, 0.0), -Vec3::Y))
+            .unwrap();
+        assert!(top.point.distance(Vec3::new(0.0, 3.5, 0.0)) < 1.0e-5);
+
+        // off the axes the normal isn't where the point is, it's the gradient of the ellipsoid
+        let p = Vec3::new(2.0 * 0.6, 0.5 * 0.8, 0.0);
+        let oblique = ellipsoid
+            .hit(&Ray::new(Vec3::new(p.x, 10.0, 0.0), -Vec3::Y))
+            .unwrap();
+        assert!(oblique.point.distance(p + Vec3::new(0.0, 3.0, 0.0)) < 1.0e-4);
+        let expected = Vec3::new(p.x / 4.0, p.y / 0.25, 0.0).normalize();
+        assert!(oblique.normal.distance(expected) < 1.0e-4);
+
+        let inside = ellipsoid
+            .hit(&Ray::new(Vec3::new(0.0, 3.0, 0.0), Vec3::Z))
+            .unwrap();
+        assert!(matches!(inside.face, Face::Back));
+        assert!(inside.point.distance(Vec3::new(0.0, 3.0, 1.0)) < 1.0e-5);
+
+        let bbox = ellipsoid.bounding_box();
+        assert!(bbox.min.distance(Vec3::new(-2.0, 2.5, -1.0)) < 1.0e-5);
+        assert!(bbox.max.distance(Vec3::new(2.0, 3.5, 1.0)) < 1.0e-5);
+        assert!(ellipsoid.pos().distance(Vec3::new(0.0, 3.0, 0.0)) < 1.0e-6);
+
+        // the shape itself is shared, not copied
+        let other = Transformed::new(sphere.clone(), Mat4::from_translation(Vec3::X));
+        assert!(Arc::ptr_eq(&ellipsoid.inner, &other.inner));
+    }
+
+    #[test]
+    fn test_mirrored() {
+        let quad: Arc<dyn Hitable> = Arc::new(Quad::new(Vec3::ZERO, Vec3::X, Vec3::Z));
+        // a unit square in the xz plane facing down, flipped upside down
+        let flipped = Transformed::new(quad, Mat4::from_scale(Vec3::new(1.0, -1.0, 1.0)));
+
+        let hit = flipped
+            .hit(&Ray::new(Vec3::new(0.5, 5.0, 0.5), -Vec3::Y))
+            .unwrap();
+        assert!(matches!(hit.face, Face::Front));
+        assert!(hit.normal.distance(Vec3::Y) < 1.0e-6);
+    }
+
+    #[test]
+    fn test_sample_direction() {
+        // a quad stretched and turned has to be sampled like the same quad made in world space
+        let matrix = Mat4::from_scale_rotation_translation(
+            Vec3::new(3.0, 1.0, 0.5),
+            Quat::from_rotation_z(0.3),
+            Vec3::new(0.0, 4.0, 0.0),
+        );
+        let local = Quad::new(Vec3::ZERO, Vec3::X, Vec3::Z);
+        let transformed = Transformed::new(Arc::new(local), matrix);
+        let world = Quad::new(
+            matrix.transform_point3(Vec3::ZERO),
+            matrix.transform_vector3(Vec3::X),
+            matrix.transform_vector3(Vec3::Z),
+        );
+
+        // the same numbers pick the same point on both
+        let from = Vec3::new(0.5, 0.0, 0.2);
+        for seed in 0..20 {
+            let (dir, pdf) = transformed
+                .sample_direction(from, &mut Pcg32::new(seed, 0))
+                .unwrap();
+            let (expected_dir, expected_pdf) = world
+                .sample_direction(from, &mut Pcg32::new(seed, 0))
+                .unwrap();
+
+            assert!(dir.distance(expected_dir) < 1.0e-4);
+            assert!(
+                (pdf / expected_pdf - 1.0).abs() < 1.0e-3,
+                "{} {}",
+                pdf,
+                expected_pdf
+            );
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::rng::Pcg32;
+use crate::scene::CollisionData;
+use crate::scene::Face;
+use crate::scene::Hitable;
+
+use glam::Vec2;
+use glam::Vec3;
+use rand::prelude::*;
+
+const THRESHOLD: f32 = 0.001;
+
+// rays this close to parallel with the triangle plane are considered to miss it
+const PARALLEL_EPSILON: f32 = 1e-8;
+
+/**
+ * Single triangle. Vertices go counter clockwise when looking at the front face. Normals and
+ * UVs are optional, without normals the triangle is flat shaded and without UVs the barycentric
+ * coordinates are used instead
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: Option<[Vec2; 3]>,
+}
+
+impl Triangle {
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3) -> Triangle {
+        Triangle {
+            vertices: [v0, v1, v2],
+            normals: None,
+            uvs: None,
+        }
+    }
+
+    pub fn with_normals(mut self, normals: [Vec3; 3]) -> Triangle {
+        self.normals = Some(normals);
+        self
+    }
+
+    pub fn with_uvs(mut self, uvs: [Vec2; 3]) -> Triangle {
+        self.uvs = Some(uvs);
+        self
+    }
+}
+
+/**
+ * Möller–Trumbore: a point on the triangle is v0 + b1 * (v1 - v0) + b2 * (v2 - v0) and a point
+ * on the ray is origin + t * dir, setting them equal gives a 3x3 linear system in (t, b1, b2)
+ * which is solved with Cramer's rule, the determinants being expressed as triple products.
+ * Returns the distance along the ray and the barycentric coordinates of v1 and v2
+ */
+pub fn intersect(v: &[Vec3; 3], r: &Ray) -> Option<(f32, f32, f32)> {
+    let edge1 = v[1] - v[0];
+    let edge2 = v[2] - v[0];
+
+    let pvec = r.dir.cross(edge2);
+    let det = edge1.dot(pvec);
+
+    if det.abs() < PARALLEL_EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let tvec = r.origin - v[0];
+    let b1 = tvec.dot(pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+
+    let qvec = tvec.cross(edge1);
+    let b2 = r.dir.dot(qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(qvec) * inv_det;
+    if t <= THRESHOLD {
+        return None;
+    }
+
+    Some((t, b1, b2))
+}
+
+/**
+ * Builds the collision data from the barycentric coordinates of a hit. The face is decided by
+ * the winding of the vertices while the returned normal is the interpolated one (when there are
+ * vertex normals) turned towards the incoming ray, same as for spheres
+ */
+pub fn collision(
+    r: &Ray,
+    hit: (f32, f32, f32),
+    v: &[Vec3; 3],
+    normals: Option<&[Vec3; 3]>,
+    uvs: Option<&[Vec2; 3]>,
+) -> CollisionData {
+    let (t, b1, b2) = hit;
+    let b0 = 1.0 - b1 - b2;
+
+    let geometric = (v[1] - v[0]).cross(v[2] - v[0]).normalize();
+    let face = if geometric.dot(r.dir) > 0.0 {
+        Face::Back
+    } else {
+        Face::Front
+    };
+
+    let mut normal = match normals {
+        Some(n) => (n[0] * b0 + n[1] * b1 + n[2] * b2).normalize(),
+        None => geometric,
+    };
+
+    if let Face::Back = face {
+        normal = -normal;
+    }
+
+    let uv = match uvs {
+        Some(uv) => uv[0] * b0 + uv[1] * b1 + uv[2] * b2,
+        None => Vec2::new(b1, b2),
+    };
+
+    CollisionData {
+        face,
+        normal,
+        point: r.at(t),
+        uv,
+    }
+}
+
+// uniformly distributed point on the triangle
+pub fn random_point(v: &[Vec3; 3], u: Vec2) -> Vec3 {
+    let (mut b1, mut b2) = (u.x, u.y);
+
+    // fold the other half of the parallelogram back onto the triangle
+    if b1 + b2 > 1.0 {
+        b1 = 1.0 - b1;
+        b2 = 1.0 - b2;
+    }
+
+    v[0] + (v[1] - v[0]) * b1 + (v[2] - v[0]) * b2
+}
+
+/**
+ * Direction towards a uniformly distributed point on the triangle. The density per area is
+ * 1 / area, per solid angle that becomes distance^2 / (cos * area) where cos is between the
+ * direction and the triangle normal
+ */
+pub fn sample_direction(
+    v: &[Vec3; 3],
+    from: Vec3,
+    area: f32,
+    rng: &mut Pcg32,
+) -> Option<(Vec3, f32)> {
+    let to_point = random_point(v, Vec2::new(rng.gen(), rng.gen())) - from;
+    let distance_squared = to_point.length_squared();
+    let dir = to_point.normalize();
+
+    let normal = (v[1] - v[0]).cross(v[2] - v[0]).normalize();
+    let cos = normal.dot(dir).abs();
+    if cos < PARALLEL_EPSILON || area <= 0.0 {
+        return None;
+    }
+
+    Some((dir, distance_squared / (cos * area)))
+}
+
+pub fn area(v: &[Vec3; 3]) -> f32 {
+    (v[1] - v[0]).cross(v[2] - v[0]).length() * 0.5
+}
+
+pub fn bounding_box(v: &[Vec3; 3]) -> Aabb {
+    Aabb::empty().grow(v[0]).grow(v[1]).grow(v[2])
+}
+
+impl Hitable for Triangle {
+    fn get_random_point(&self, u: Vec3) -> Vec3 {
+        random_point(&self.vertices, u.truncate()) - self.pos()
+    }
+
+    fn pos(&self) -> Vec3 {
+        (self.vertices[0] + self.vertices[1] + self.vertices[2]) / 3.0
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        bounding_box(&self.vertices)
+    }
+
+    fn sample_direction(&self, from: Vec3, rng: &mut Pcg32) -> Option<(Vec3, f32)> {
+        sample_direction(&self.vertices, from, area(&self.vertices), rng)
+    }
+
+    fn hit(&self, r: &Ray) -> Option<CollisionData> {
+        let hit = intersect(&self.vertices, r)?;
+
+        Some(collision(
+            r,
+            hit,
+            &self.vertices,
+            self.normals.as_ref(),
+            self.uvs.as_ref(),
+        ))
+    }
+}